@@ -0,0 +1,43 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use tokio::runtime::Runtime;
+
+use pledger::input::{InputTransaction, TransactionType};
+use pledger::transactions::Transactions;
+use pledger::transactions_store::DEFAULT_CURRENCY;
+
+const TRANSACTIONS: u32 = 10_000;
+
+// Set up a store with a single client, with `TRANSACTIONS` deposits and withdrawals,
+// disputing every `dispute_every`th one.
+async fn setup(dispute_every: u32) -> Transactions {
+    let transactions = Transactions::new("sqlite::memory:").await.unwrap();
+    for tx in 1..=TRANSACTIONS {
+        let transaction_type = if tx % 4 == 0 { TransactionType::Withdrawal } else { TransactionType::Deposit };
+        transactions.add_input(InputTransaction::new(transaction_type, 1, tx, Some("1.2345")).unwrap()).await.unwrap();
+        if tx % dispute_every == 0 {
+            transactions.add_input(InputTransaction::new(TransactionType::Dispute, 1, tx, None).unwrap()).await.unwrap();
+        }
+    }
+    transactions
+}
+
+fn bench_replay(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("replay");
+    group.sample_size(20);
+
+    let deposits_heavy = runtime.block_on(setup(TRANSACTIONS));
+    group.bench_function("deposits_heavy", |b| {
+        b.to_async(&runtime).iter(|| deposits_heavy.replay_account(1, DEFAULT_CURRENCY))
+    });
+
+    let dispute_heavy = runtime.block_on(setup(2));
+    group.bench_function("dispute_heavy", |b| {
+        b.to_async(&runtime).iter(|| dispute_heavy.replay_account(1, DEFAULT_CURRENCY))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_replay);
+criterion_main!(benches);
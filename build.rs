@@ -0,0 +1,22 @@
+use std::path::Path;
+use std::process::Command;
+
+// Embed the git commit the binary is built from as `PLEDGER_GIT_HASH`, when it is built from a git checkout.
+// Also rebuild when a migration changes, as `sqlx::migrate!` embeds them.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=PLEDGER_GIT_HASH={}", hash.trim());
+    }
+    for path in [".git/HEAD", ".git/refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
@@ -0,0 +1,20 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use pledger::input::{parse_input_transaction, parse_input_transaction_with, InputOptions, TrimPolicy};
+
+// Every record of any input must be read as either a transaction or an error, without panicking.
+fuzz_target!(|data: &[u8]| {
+    for result in parse_input_transaction(data) {
+        let _ = result;
+    }
+    let options = InputOptions {
+        comment: Some(b'#'),
+        trim: TrimPolicy::None,
+        lenient_amounts: true,
+    };
+    for result in parse_input_transaction_with(data, &options) {
+        let _ = result;
+    }
+});
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS transactions
+(
+    ordinal         INTEGER PRIMARY KEY,
+    client_id       INTEGER,
+    transaction_id  INTEGER UNIQUE,
+    amount          INTEGER,
+    disputed        BOOLEAN,
+    charged_back    BOOLEAN
+);
+
+CREATE INDEX IF NOT EXISTS idx_client_transaction ON transactions (client_id, transaction_id);
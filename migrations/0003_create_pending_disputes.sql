@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS pending_disputes
+(
+    client_id       INTEGER,
+    transaction_id  INTEGER,
+    PRIMARY KEY (client_id, transaction_id)
+);
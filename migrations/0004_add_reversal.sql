@@ -0,0 +1,2 @@
+ALTER TABLE transactions ADD COLUMN reversal_reason TEXT;
+ALTER TABLE transactions ADD COLUMN reversed_at TEXT;
@@ -0,0 +1 @@
+ALTER TABLE transactions ADD COLUMN run_id TEXT;
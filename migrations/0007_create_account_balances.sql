@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS account_balances
+(
+    client_id       INTEGER,
+    currency        TEXT,
+    available       TEXT NOT NULL,
+    held            TEXT NOT NULL,
+    total           TEXT NOT NULL,
+    locked          BOOLEAN NOT NULL,
+    halted          BOOLEAN NOT NULL,
+    last_timestamp  INTEGER,
+    last_ordinal    INTEGER NOT NULL,
+    replay_policy   TEXT NOT NULL,
+    PRIMARY KEY (client_id, currency)
+);
@@ -0,0 +1,136 @@
+use std::error::Error;
+use std::str::FromStr;
+
+use futures_core::Stream;
+use futures_util::pin_mut;
+use futures_util::stream::TryStreamExt;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+
+use crate::output::OutputAccount;
+use crate::transactions_store::DEFAULT_CURRENCY;
+
+// Writes accounts to a database table, replacing any previous row for the same account.
+// Amounts are stored as text, so they are kept exactly as they are written to CSV.
+pub struct AccountTableWriter {
+    db_pool: SqlitePool,
+    table: String,
+}
+
+impl AccountTableWriter {
+    pub async fn connect(url: &str, table: &str) -> Result<Self, Box<dyn Error>> {
+        let db_pool = SqlitePoolOptions::new().connect_with(
+            SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+        ).await?;
+        Self::new(db_pool, table).await
+    }
+
+    // Use an existing pool, creating the table if it doesn't exist.
+    pub async fn new(db_pool: SqlitePool, table: &str) -> Result<Self, Box<dyn Error>> {
+        if table.is_empty() || !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("Invalid table name: {}", table).into());
+        }
+        sqlx::query(&format!("
+            CREATE TABLE IF NOT EXISTS {}
+            (
+                client      INTEGER NOT NULL,
+                currency    TEXT NOT NULL DEFAULT '',
+                available   TEXT NOT NULL,
+                held        TEXT NOT NULL,
+                total       TEXT NOT NULL,
+                locked      BOOLEAN NOT NULL,
+                PRIMARY KEY (client, currency)
+            );
+        ", table)).execute(&db_pool).await?;
+
+        Ok(Self{ db_pool, table: table.into() })
+    }
+
+    pub async fn write_account(&self, account: &OutputAccount) -> Result<(), Box<dyn Error>> {
+        sqlx::query(&format!("
+            INSERT INTO {} (client, currency, available, held, total, locked)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (client, currency) DO UPDATE SET
+                available = excluded.available,
+                held = excluded.held,
+                total = excluded.total,
+                locked = excluded.locked;
+        ", self.table))
+        .bind(account.client)
+        .bind(account.currency.as_deref().unwrap_or(DEFAULT_CURRENCY))
+        .bind(account.available.to_string())
+        .bind(account.held.to_string())
+        .bind(account.total.to_string())
+        .bind(account.locked)
+        .execute(&self.db_pool).await?;
+        Ok(())
+    }
+
+    pub async fn close(&self) {
+        self.db_pool.close().await;
+    }
+}
+
+// Upsert every account from `accounts` into `table`, returning the number of accounts written.
+pub async fn write_accounts_to_table<S>(db_pool: SqlitePool, table: &str, accounts: S) -> Result<usize, Box<dyn Error>>
+where S: Stream<Item = Result<OutputAccount, Box<dyn Error>>>
+{
+    let writer = AccountTableWriter::new(db_pool, table).await?;
+    let mut written = 0;
+    pin_mut!(accounts);
+    while let Some(account) = accounts.try_next().await? {
+        writer.write_account(&account).await?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_util::stream;
+
+    #[derive(sqlx::FromRow, Debug, PartialEq)]
+    struct AccountRow {
+        client: u32,
+        currency: String,
+        available: String,
+        held: String,
+        total: String,
+        locked: bool,
+    }
+
+    #[tokio::test]
+    async fn test_write_accounts_to_table() {
+        let db_pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+        let accounts = vec![
+            Ok(OutputAccount::new(1, "1.5", "2", "3.5", false).unwrap()),
+            Ok(OutputAccount::new(2, "0", "0", "0", true).unwrap().with_currency("EUR")),
+            Ok(OutputAccount::new(1, "2.5", "0", "2.5", false).unwrap()),
+        ];
+        let written = write_accounts_to_table(db_pool.clone(), "accounts", stream::iter(accounts)).await.unwrap();
+        assert_eq!(written, 3);
+
+        let rows: Vec<AccountRow> = sqlx::query_as("SELECT * FROM accounts ORDER BY client")
+            .fetch_all(&db_pool).await.unwrap();
+        let row = |client, currency: &str, available: &str, held: &str, total: &str, locked| AccountRow {
+            client,
+            currency: currency.into(),
+            available: available.into(),
+            held: held.into(),
+            total: total.into(),
+            locked,
+        };
+        assert_eq!(rows, vec![
+            row(1, "", "2.5", "0", "2.5", false),
+            row(2, "EUR", "0", "0", "0", true),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_table_name() {
+        let db_pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+        assert!(AccountTableWriter::new(db_pool, "accounts; DROP TABLE transactions").await.is_err());
+    }
+}
@@ -0,0 +1,718 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use bigdecimal::{BigDecimal, Signed};
+use log::LevelFilter;
+
+use crate::input::TrimPolicy;
+use crate::output::{BoolFormat, ColumnScales, OutputFormat, RoundMode};
+use crate::transactions::{NegativeAvailablePolicy, WithdrawalPolicy};
+use crate::transactions_store::ConflictPolicy;
+
+// The size of the buffer the output is written through, unless set with `--write-buffer-bytes`.
+pub const DEFAULT_WRITE_BUFFER_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    // Process the input and write out the accounts.
+    Process,
+    // Process the input and check the accounts are consistent.
+    Verify,
+    // Process the input and show what a single transaction did to its account.
+    Explain { client: u32, tx: u32 },
+    // Write out the account of a single client from the transactions already in the database, without reading any input.
+    Query { client: u32 },
+    // Process the input and write out the clients with locked accounts, without computing any accounts.
+    LockedClients,
+    // Process the input and write out the number of transactions of each client, without computing any accounts.
+    TransactionCounts,
+    // Print the version and exit, without reading any input.
+    Version,
+    // Print the JSON Schema of the accounts in the JSON output and exit, without reading any input.
+    EmitSchema,
+    // Process the input and write out only the accounts that changed since the accounts written by a previous run.
+    Diff { previous: PathBuf },
+}
+
+// What to do with an input that has more transactions than allowed.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LimitPolicy {
+    // Fail the run before writing any output.
+    Abort,
+    // Ignore the transactions after the limit.
+    Truncate,
+}
+
+impl FromStr for LimitPolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(LimitPolicy::Abort),
+            "truncate" => Ok(LimitPolicy::Truncate),
+            _ => Err(format!("Unknown limit policy: {}", s).into()),
+        }
+    }
+}
+
+// Options for a single run, as given on the command line.
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub command: Command,
+    pub input: Option<String>,
+    pub dir: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub output_db: Option<String>,
+    pub dump_transactions: Option<PathBuf>,
+    pub held_report: Option<PathBuf>,
+    pub held_alert_threshold: Option<BigDecimal>,
+    // The most that the held funds across all accounts can be of their total before it's reported.
+    pub max_held_ratio: Option<BigDecimal>,
+    // Fail the run, rather than only logging a warning, if the held funds are above `max_held_ratio`.
+    pub fail_on_held_ratio: bool,
+    pub rejects: Option<PathBuf>,
+    pub db_url: String,
+    pub format: OutputFormat,
+    pub skip_zero: bool,
+    pub integer_amounts: bool,
+    pub timestamp_output: bool,
+    pub bool_format: BoolFormat,
+    pub write_buffer_bytes: usize,
+    pub append: bool,
+    pub recompute: bool,
+    pub skip: usize,
+    pub lossy: bool,
+    pub comment_char: Option<u8>,
+    pub trim: TrimPolicy,
+    pub lenient_amounts: bool,
+    pub merge_by_timestamp: bool,
+    pub group_by_client: bool,
+    // Write the accounts that aren't locked first, and then those that are.
+    pub group_by_locked: bool,
+    pub max_transactions: Option<usize>,
+    // The number of invalid input records to skip before failing, or unlimited if not given.
+    pub max_errors: Option<usize>,
+    pub max_clients: Option<u32>,
+    pub limit_policy: LimitPolicy,
+    pub split_output: Option<PathBuf>,
+    pub reject_client_zero: bool,
+    // Files of client IDs, one per line, to process only or to exclude.
+    pub allow_clients: Option<PathBuf>,
+    pub deny_clients: Option<PathBuf>,
+    pub pending_disputes: bool,
+    pub lock_halts_replay: bool,
+    pub ignore_disputes: bool,
+    pub negative_available: NegativeAvailablePolicy,
+    pub withdrawal_policy: WithdrawalPolicy,
+    pub strict_dispute_client: bool,
+    pub freeze_on_dispute: bool,
+    pub on_conflict: ConflictPolicy,
+    pub round_digits: u32,
+    // Write amounts with exactly `round_digits` decimal places, as set by `--precision`.
+    // Beyond the 4 decimal places amounts are stored with, that only pads them with zeros.
+    pub fixed_scale: bool,
+    pub max_input_scale: Option<u32>,
+    pub strict_input_scale: bool,
+    pub as_of: Option<i64>,
+    // Stored with each transaction of this run. A timestamp of when the run started if not given.
+    pub run_id: Option<String>,
+    pub round_mode: RoundMode,
+    // Overrides `round_digits` for the available, held or total amounts.
+    pub column_scales: ColumnScales,
+    pub pool_size: Option<u32>,
+    pub log_level: LevelFilter,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            command: Command::Process,
+            input: None,
+            dir: None,
+            output: None,
+            output_db: None,
+            dump_transactions: None,
+            held_report: None,
+            held_alert_threshold: None,
+            max_held_ratio: None,
+            fail_on_held_ratio: false,
+            rejects: None,
+            db_url: "sqlite://transactions.db".into(),
+            format: OutputFormat::Csv,
+            skip_zero: false,
+            integer_amounts: false,
+            bool_format: BoolFormat::TrueFalse,
+            write_buffer_bytes: DEFAULT_WRITE_BUFFER_BYTES,
+            append: false,
+            recompute: false,
+            skip: 0,
+            lossy: false,
+            comment_char: None,
+            trim: TrimPolicy::All,
+            lenient_amounts: false,
+            merge_by_timestamp: false,
+            group_by_client: false,
+            group_by_locked: false,
+            timestamp_output: false,
+            max_transactions: None,
+            max_errors: None,
+            max_clients: None,
+            limit_policy: LimitPolicy::Abort,
+            split_output: None,
+            reject_client_zero: false,
+            allow_clients: None,
+            deny_clients: None,
+            pending_disputes: false,
+            lock_halts_replay: true,
+            ignore_disputes: false,
+            negative_available: NegativeAvailablePolicy::Skip,
+            withdrawal_policy: WithdrawalPolicy::RejectWhole,
+            strict_dispute_client: false,
+            freeze_on_dispute: false,
+            on_conflict: ConflictPolicy::Ignore,
+            round_digits: 4,
+            fixed_scale: false,
+            max_input_scale: None,
+            strict_input_scale: false,
+            as_of: None,
+            run_id: None,
+            round_mode: RoundMode::HalfUp,
+            column_scales: ColumnScales::default(),
+            pool_size: None,
+            log_level: LevelFilter::Warn,
+        }
+    }
+}
+
+impl Config {
+    // Parse the command line arguments, not including the program name.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, Box<dyn Error>> {
+        Self::from_args_and_env(args, |_| None)
+    }
+
+    // Parse the command line arguments, falling back to the environment variables
+    // returned by `env` for options that are not given, and then to the defaults.
+    pub fn from_args_and_env<I, E>(args: I, env: E) -> Result<Self, Box<dyn Error>>
+    where
+        I: IntoIterator<Item = String>,
+        E: Fn(&str) -> Option<String>,
+    {
+        let mut config = Config::default();
+        if let Some(db_url) = env("PLEDGER_DB_URL") {
+            config.db_url = db_url;
+        }
+        if let Some(round_digits) = env("PLEDGER_ROUND_DIGITS") {
+            config.round_digits = round_digits.parse()
+                .map_err(|_| format!("Invalid PLEDGER_ROUND_DIGITS: {}", round_digits))?;
+        }
+        if let Some(pool_size) = env("PLEDGER_POOL_SIZE") {
+            config.pool_size = Some(pool_size.parse()
+                .map_err(|_| format!("Invalid PLEDGER_POOL_SIZE: {}", pool_size))?);
+        }
+
+        let mut args = args.into_iter().peekable();
+        match args.peek().map(String::as_str) {
+            Some("verify") => {
+                config.command = Command::Verify;
+                args.next();
+            },
+            Some("explain") => {
+                args.next();
+                let client = next_value(&mut args, "explain")?;
+                let tx = next_value(&mut args, "explain")?;
+                config.command = Command::Explain {
+                    client: client.parse().map_err(|_| format!("Invalid client: {}", client))?,
+                    tx: tx.parse().map_err(|_| format!("Invalid transaction: {}", tx))?,
+                };
+            },
+            Some("query") => {
+                args.next();
+                let client = next_value(&mut args, "query")?;
+                config.command = Command::Query {
+                    client: client.parse().map_err(|_| format!("Invalid client: {}", client))?,
+                };
+            },
+            _ => {},
+        }
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--db-url" => config.db_url = next_value(&mut args, &arg)?,
+                "--round-digits" => {
+                    config.round_digits = next_value(&mut args, &arg)?.parse()?;
+                },
+                "--precision" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.round_digits = value.parse().map_err(|_| format!("Invalid precision: {}", value))?;
+                    config.fixed_scale = true;
+                },
+                "--max-input-scale" => {
+                    config.max_input_scale = Some(next_value(&mut args, &arg)?.parse()?);
+                },
+                "--strict-input-scale" => config.strict_input_scale = true,
+                "--run-id" => config.run_id = Some(next_value(&mut args, &arg)?),
+                "--as-of" => {
+                    config.as_of = Some(next_value(&mut args, &arg)?.parse()?);
+                },
+                "--scale" => {
+                    config.column_scales = next_value(&mut args, &arg)?.parse()?;
+                },
+                "--round-mode" => {
+                    config.round_mode = next_value(&mut args, &arg)?.parse()?;
+                },
+                "--pool-size" => {
+                    config.pool_size = Some(next_value(&mut args, &arg)?.parse()?);
+                },
+                "--format" => {
+                    config.format = next_value(&mut args, &arg)?.parse()?;
+                },
+                "--skip-zero" => config.skip_zero = true,
+                "--integer-amounts" => config.integer_amounts = true,
+                "--timestamp-output" => config.timestamp_output = true,
+                "--append" => config.append = true,
+                "--recompute" => config.recompute = true,
+                "--lossy" => config.lossy = true,
+                "--lenient-amounts" => config.lenient_amounts = true,
+                "--locked-clients" => config.command = Command::LockedClients,
+                "--transaction-counts" => config.command = Command::TransactionCounts,
+                "--version" | "-V" => config.command = Command::Version,
+                "--emit-schema" => config.command = Command::EmitSchema,
+                "--diff" => {
+                    config.command = Command::Diff { previous: next_value(&mut args, &arg)?.into() };
+                },
+                "--merge-by-timestamp" => config.merge_by_timestamp = true,
+                "--group-by-client" => config.group_by_client = true,
+                "--group-by-locked" => config.group_by_locked = true,
+                "--comment-char" => {
+                    let value = next_value(&mut args, &arg)?;
+                    match value.as_bytes() {
+                        [comment_char] => config.comment_char = Some(*comment_char),
+                        _ => return Err(format!("The comment character must be a single ASCII character: {}", value).into()),
+                    }
+                },
+                "--trim" => {
+                    config.trim = next_value(&mut args, &arg)?.parse()?;
+                },
+                "--reject-client-zero" => config.reject_client_zero = true,
+                "--pending-disputes" => config.pending_disputes = true,
+                "--replay-after-lock" => config.lock_halts_replay = false,
+                "--ignore-disputes" => config.ignore_disputes = true,
+                "--strict-dispute-client" => config.strict_dispute_client = true,
+                "--freeze-on-dispute" => config.freeze_on_dispute = true,
+                "--skip" => {
+                    config.skip = next_value(&mut args, &arg)?.parse()?;
+                },
+                "--max-transactions" => {
+                    config.max_transactions = Some(next_value(&mut args, &arg)?.parse()?);
+                },
+                "--max-errors" => {
+                    config.max_errors = Some(next_value(&mut args, &arg)?.parse()?);
+                },
+                "--negative-available" => {
+                    config.negative_available = next_value(&mut args, &arg)?.parse()?;
+                },
+                "--withdrawal-policy" => {
+                    config.withdrawal_policy = next_value(&mut args, &arg)?.parse()?;
+                },
+                "--on-conflict" => {
+                    config.on_conflict = next_value(&mut args, &arg)?.parse()?;
+                },
+                "--write-buffer-bytes" => {
+                    config.write_buffer_bytes = next_value(&mut args, &arg)?.parse()?;
+                },
+                "--max-clients" => {
+                    config.max_clients = Some(next_value(&mut args, &arg)?.parse()?);
+                },
+                "--limit-policy" => {
+                    config.limit_policy = next_value(&mut args, &arg)?.parse()?;
+                },
+                "--dir" => {
+                    config.dir = Some(next_value(&mut args, &arg)?.into());
+                },
+                "--output" => {
+                    config.output = Some(next_value(&mut args, &arg)?.into());
+                },
+                "--output-db" => {
+                    config.output_db = Some(next_value(&mut args, &arg)?);
+                },
+                "--dump-transactions" => {
+                    config.dump_transactions = Some(next_value(&mut args, &arg)?.into());
+                },
+                "--held-report" => {
+                    config.held_report = Some(next_value(&mut args, &arg)?.into());
+                },
+                "--max-held-ratio" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.max_held_ratio = match BigDecimal::from_str(&value) {
+                        Ok(ratio) if !ratio.is_negative() => Some(ratio),
+                        _ => return Err(format!("Invalid max held ratio: {}", value).into()),
+                    };
+                },
+                "--fail-on-held-ratio" => config.fail_on_held_ratio = true,
+                "--held-alert-threshold" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.held_alert_threshold = Some(BigDecimal::from_str(&value)
+                        .map_err(|_| format!("Invalid held alert threshold: {}", value))?);
+                },
+                "--allow-clients" => {
+                    config.allow_clients = Some(next_value(&mut args, &arg)?.into());
+                },
+                "--deny-clients" => {
+                    config.deny_clients = Some(next_value(&mut args, &arg)?.into());
+                },
+                "--rejects" => {
+                    config.rejects = Some(next_value(&mut args, &arg)?.into());
+                },
+                "--split-output" => {
+                    config.split_output = Some(next_value(&mut args, &arg)?.into());
+                },
+                "--bool-format" => {
+                    config.bool_format = next_value(&mut args, &arg)?.parse()?;
+                },
+                "-q" | "--quiet" => config.log_level = LevelFilter::Error,
+                "--verbose" => config.log_level = more_verbose(config.log_level, 1),
+                _ if arg.starts_with("-v") && arg[1..].chars().all(|c| c == 'v') => {
+                    config.log_level = more_verbose(config.log_level, arg.len() - 1);
+                },
+                _ if arg.starts_with("--") => {
+                    return Err(format!("Unknown option: {}", arg).into());
+                },
+                _ => {
+                    if config.input.is_some() {
+                        return Err(format!("Unexpected argument: {}", arg).into());
+                    }
+                    config.input = Some(arg);
+                },
+            }
+        }
+        Ok(config)
+    }
+}
+
+// Raise the log level by `steps`, from warnings to debug and then to trace.
+fn more_verbose(level: LevelFilter, steps: usize) -> LevelFilter {
+    let mut level = level.max(LevelFilter::Info);
+    for _ in 0..steps {
+        level = if level == LevelFilter::Info { LevelFilter::Debug } else { LevelFilter::Trace };
+    }
+    level
+}
+
+fn next_value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String, Box<dyn Error>> {
+    args.next().ok_or_else(|| format!("{} requires a value", option).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, Box<dyn Error>> {
+        Config::from_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = parse(&["transactions.csv"]).unwrap();
+        let expected = Config {
+            input: Some("transactions.csv".into()),
+            ..Config::default()
+        };
+        assert_eq!(config, expected)
+    }
+
+    #[test]
+    fn test_format() {
+        let config = parse(&["--format", "ndjson", "transactions.csv"]).unwrap();
+        assert_eq!(config.format, OutputFormat::Ndjson);
+        assert_eq!(parse(&["--format", "table"]).unwrap().format, OutputFormat::Table);
+    }
+
+    #[test]
+    fn test_column_scales() {
+        assert_eq!(parse(&[]).unwrap().column_scales, ColumnScales::default());
+        assert_eq!(
+            parse(&["--scale", "available=2,held=4", "transactions.csv"]).unwrap().column_scales,
+            ColumnScales { available: Some(2), held: Some(4), total: None },
+        );
+        assert!(parse(&["--scale", "fee=2"]).is_err());
+    }
+
+    #[test]
+    fn test_precision() {
+        let config = parse(&["--precision", "8", "transactions.csv"]).unwrap();
+        assert_eq!((config.round_digits, config.fixed_scale), (8, true));
+        assert!(!parse(&["--round-digits", "8"]).unwrap().fixed_scale);
+        assert_eq!(parse(&["--precision", "-2"]).unwrap_err().to_string(), "Invalid precision: -2");
+    }
+
+    #[test]
+    fn test_round_mode() {
+        assert_eq!(parse(&[]).unwrap().round_mode, RoundMode::HalfUp);
+        assert_eq!(parse(&["--round-mode", "truncate"]).unwrap().round_mode, RoundMode::Truncate);
+        assert_eq!(parse(&["--round-mode", "half-even"]).unwrap().round_mode, RoundMode::HalfEven);
+        assert!(parse(&["--round-mode", "down"]).is_err());
+    }
+
+    #[test]
+    fn test_max_input_scale() {
+        assert_eq!(parse(&[]).unwrap().max_input_scale, None);
+        assert_eq!(parse(&["--max-input-scale", "4"]).unwrap().max_input_scale, Some(4));
+        assert!(!parse(&[]).unwrap().strict_input_scale);
+        assert!(parse(&["--strict-input-scale"]).unwrap().strict_input_scale);
+        assert!(parse(&["--max-input-scale", "-1"]).is_err());
+    }
+
+    #[test]
+    fn test_run_id() {
+        assert_eq!(parse(&[]).unwrap().run_id, None);
+        assert_eq!(parse(&["--run-id", "nightly-42", "transactions.csv"]).unwrap().run_id, Some("nightly-42".into()));
+    }
+
+    #[test]
+    fn test_as_of() {
+        assert_eq!(parse(&[]).unwrap().as_of, None);
+        assert_eq!(parse(&["--as-of", "42", "transactions.csv"]).unwrap().as_of, Some(42));
+        assert!(parse(&["--as-of", "latest"]).is_err());
+    }
+
+    #[test]
+    fn test_max_held_ratio() {
+        let config = parse(&[]).unwrap();
+        assert_eq!((config.max_held_ratio, config.fail_on_held_ratio), (None, false));
+        let config = parse(&["--max-held-ratio", "0.2", "--fail-on-held-ratio", "transactions.csv"]).unwrap();
+        assert_eq!((config.max_held_ratio, config.fail_on_held_ratio), (Some(BigDecimal::from_str("0.2").unwrap()), true));
+        assert!(parse(&["--max-held-ratio", "-0.1"]).is_err());
+        assert!(parse(&["--max-held-ratio", "a fifth"]).is_err());
+    }
+
+    #[test]
+    fn test_held_alert_threshold() {
+        assert_eq!(parse(&[]).unwrap().held_alert_threshold, None);
+        let config = parse(&["--held-alert-threshold", "1000.50", "transactions.csv"]).unwrap();
+        assert_eq!(config.held_alert_threshold, Some(BigDecimal::from_str("1000.50").unwrap()));
+        assert!(parse(&["--held-alert-threshold", "lots"]).is_err());
+    }
+
+    #[test]
+    fn test_write_buffer_bytes() {
+        assert_eq!(parse(&[]).unwrap().write_buffer_bytes, DEFAULT_WRITE_BUFFER_BYTES);
+        assert_eq!(parse(&["--write-buffer-bytes", "1048576"]).unwrap().write_buffer_bytes, 1048576);
+        assert!(parse(&["--write-buffer-bytes", "1MB"]).is_err());
+    }
+
+    #[test]
+    fn test_bool_format() {
+        let config = parse(&["--bool-format", "y-n", "transactions.csv"]).unwrap();
+        assert_eq!(config.bool_format, BoolFormat::YN)
+    }
+
+    #[test]
+    fn test_flags() {
+        let config = parse(&["transactions.csv", "--skip-zero", "--integer-amounts", "--timestamp-output", "--append", "--skip", "3", "--lossy", "--lenient-amounts", "--merge-by-timestamp", "--group-by-client", "--group-by-locked", "--reject-client-zero", "--pending-disputes", "--replay-after-lock", "--ignore-disputes", "--strict-dispute-client", "--freeze-on-dispute"]).unwrap();
+        assert!(config.strict_dispute_client);
+        assert!(config.freeze_on_dispute);
+        assert!(config.ignore_disputes);
+        assert!(config.skip_zero);
+        assert!(config.integer_amounts);
+        assert!(config.timestamp_output);
+        assert!(!config.lock_halts_replay);
+        assert!(config.pending_disputes);
+        assert!(config.reject_client_zero);
+        assert!(config.lossy);
+        assert!(config.lenient_amounts);
+        assert!(config.merge_by_timestamp);
+        assert!(config.group_by_client);
+        assert!(config.group_by_locked);
+        assert!(config.append);
+        assert_eq!(config.skip, 3);
+    }
+
+    #[test]
+    fn test_recompute() {
+        let config = parse(&["--recompute", "--db-url", "sqlite://existing.db"]).unwrap();
+        assert!(config.recompute);
+        assert_eq!(config.input, None);
+    }
+
+    #[test]
+    fn test_max_transactions() {
+        let config = parse(&["--max-transactions", "10", "--limit-policy", "truncate", "transactions.csv"]).unwrap();
+        assert_eq!(config.max_transactions, Some(10));
+        assert_eq!(config.limit_policy, LimitPolicy::Truncate);
+        assert!(parse(&["--limit-policy", "ignore"]).is_err());
+
+        let config = parse(&["--max-clients", "2", "transactions.csv"]).unwrap();
+        assert_eq!(config.max_clients, Some(2));
+
+        let config = parse(&["--max-errors", "5", "transactions.csv"]).unwrap();
+        assert_eq!(config.max_errors, Some(5));
+        assert!(parse(&["--max-errors", "some", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_dir() {
+        let config = parse(&["--dir", "incoming"]).unwrap();
+        assert_eq!(config.dir, Some(PathBuf::from("incoming")));
+        assert_eq!(config.input, None);
+    }
+
+    #[test]
+    fn test_comment_char() {
+        assert_eq!(parse(&[]).unwrap().comment_char, None);
+        assert_eq!(parse(&["--comment-char", "#"]).unwrap().comment_char, Some(b'#'));
+        assert!(parse(&["--comment-char", "//"]).is_err());
+        assert!(parse(&["--comment-char", "£"]).is_err());
+    }
+
+    #[test]
+    fn test_trim() {
+        assert_eq!(parse(&[]).unwrap().trim, TrimPolicy::All);
+        assert_eq!(parse(&["--trim", "none"]).unwrap().trim, TrimPolicy::None);
+        assert!(parse(&["--trim", "left"]).is_err());
+    }
+
+    #[test]
+    fn test_on_conflict() {
+        assert_eq!(parse(&[]).unwrap().on_conflict, ConflictPolicy::Ignore);
+        assert_eq!(parse(&["--on-conflict", "replace"]).unwrap().on_conflict, ConflictPolicy::Replace);
+        assert_eq!(parse(&["--on-conflict", "error"]).unwrap().on_conflict, ConflictPolicy::Error);
+        assert!(parse(&["--on-conflict", "update"]).is_err());
+    }
+
+    #[test]
+    fn test_withdrawal_policy() {
+        assert_eq!(parse(&[]).unwrap().withdrawal_policy, WithdrawalPolicy::RejectWhole);
+        assert_eq!(parse(&["--withdrawal-policy", "allow-partial"]).unwrap().withdrawal_policy, WithdrawalPolicy::AllowPartial);
+        assert_eq!(parse(&["--withdrawal-policy", "reject-and-flag"]).unwrap().withdrawal_policy, WithdrawalPolicy::RejectAndFlag);
+        assert!(parse(&["--withdrawal-policy", "partial"]).is_err());
+    }
+
+    #[test]
+    fn test_negative_available() {
+        let config = parse(&["--negative-available", "allow-when-held"]).unwrap();
+        assert_eq!(config.negative_available, NegativeAvailablePolicy::AllowWhenHeld);
+        assert!(parse(&["--negative-available", "allow"]).is_err());
+    }
+
+    #[test]
+    fn test_split_output() {
+        let config = parse(&["--split-output", "out", "transactions.csv"]).unwrap();
+        assert_eq!(config.split_output, Some(PathBuf::from("out")));
+        assert_eq!(config.output, None);
+
+        let config = parse(&["--output", "accounts.csv", "transactions.csv"]).unwrap();
+        assert_eq!(config.output, Some(PathBuf::from("accounts.csv")));
+
+        let config = parse(&["--output-db", "sqlite://accounts.db", "transactions.csv"]).unwrap();
+        assert_eq!(config.output_db, Some("sqlite://accounts.db".into()));
+
+        let config = parse(&["--dump-transactions", "dump.csv", "transactions.csv"]).unwrap();
+        assert_eq!(config.dump_transactions, Some(PathBuf::from("dump.csv")));
+
+        let config = parse(&["--held-report", "held.csv", "transactions.csv"]).unwrap();
+        assert_eq!(config.held_report, Some(PathBuf::from("held.csv")));
+
+        let config = parse(&["--rejects", "rejects.csv", "transactions.csv"]).unwrap();
+        assert_eq!(config.rejects, Some(PathBuf::from("rejects.csv")));
+
+        let config = parse(&["--allow-clients", "allow.txt", "--deny-clients", "deny.txt", "transactions.csv"]).unwrap();
+        assert_eq!(config.allow_clients, Some(PathBuf::from("allow.txt")));
+        assert_eq!(config.deny_clients, Some(PathBuf::from("deny.txt")));
+    }
+
+    fn parse_with_env(args: &[&str], env: &[(&str, &str)]) -> Result<Config, Box<dyn Error>> {
+        Config::from_args_and_env(
+            args.iter().map(|a| a.to_string()),
+            |name| env.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string()),
+        )
+    }
+
+    #[test]
+    fn test_env_precedence() {
+        let env = [
+            ("PLEDGER_DB_URL", "sqlite://env.db"),
+            ("PLEDGER_ROUND_DIGITS", "2"),
+            ("PLEDGER_POOL_SIZE", "3"),
+        ];
+
+        let config = parse_with_env(&[], &[]).unwrap();
+        assert_eq!((config.db_url.as_str(), config.round_digits, config.pool_size), ("sqlite://transactions.db", 4, None));
+
+        let config = parse_with_env(&[], &env).unwrap();
+        assert_eq!((config.db_url.as_str(), config.round_digits, config.pool_size), ("sqlite://env.db", 2, Some(3)));
+
+        let args = ["--db-url", "sqlite://cli.db", "--round-digits", "6", "--pool-size", "1"];
+        let config = parse_with_env(&args, &env).unwrap();
+        assert_eq!((config.db_url.as_str(), config.round_digits, config.pool_size), ("sqlite://cli.db", 6, Some(1)));
+
+        assert!(parse_with_env(&[], &[("PLEDGER_POOL_SIZE", "many")]).is_err());
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(parse(&[]).unwrap().log_level, LevelFilter::Warn);
+        assert_eq!(parse(&["-q"]).unwrap().log_level, LevelFilter::Error);
+        assert_eq!(parse(&["--quiet"]).unwrap().log_level, LevelFilter::Error);
+        assert_eq!(parse(&["-v"]).unwrap().log_level, LevelFilter::Debug);
+        assert_eq!(parse(&["--verbose"]).unwrap().log_level, LevelFilter::Debug);
+        assert_eq!(parse(&["-vv"]).unwrap().log_level, LevelFilter::Trace);
+        assert_eq!(parse(&["-v", "-v"]).unwrap().log_level, LevelFilter::Trace);
+        assert_eq!(parse(&["-vvvv"]).unwrap().log_level, LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_verify_command() {
+        let config = parse(&["verify", "transactions.csv"]).unwrap();
+        assert_eq!(config.command, Command::Verify);
+        assert_eq!(config.input, Some("transactions.csv".into()));
+    }
+
+    #[test]
+    fn test_locked_clients() {
+        let config = parse(&["--locked-clients", "transactions.csv"]).unwrap();
+        assert_eq!(config.command, Command::LockedClients);
+        assert_eq!(config.input, Some("transactions.csv".into()));
+
+        let config = parse(&["--transaction-counts", "transactions.csv"]).unwrap();
+        assert_eq!(config.command, Command::TransactionCounts);
+    }
+
+    #[test]
+    fn test_version() {
+        assert_eq!(parse(&["--version"]).unwrap().command, Command::Version);
+        assert_eq!(parse(&["-V"]).unwrap().command, Command::Version);
+        assert_eq!(parse(&["--emit-schema"]).unwrap().command, Command::EmitSchema);
+        assert_eq!(
+            parse(&["--diff", "yesterday.csv", "transactions.csv"]).unwrap().command,
+            Command::Diff { previous: "yesterday.csv".into() },
+        );
+    }
+
+    #[test]
+    fn test_query_command() {
+        let config = parse(&["query", "42", "--db-url", "sqlite://ledger.db"]).unwrap();
+        assert_eq!(config.command, Command::Query { client: 42 });
+        assert_eq!((config.db_url.as_str(), config.input), ("sqlite://ledger.db", None));
+        assert!(parse(&["query"]).is_err());
+        assert!(parse(&["query", "-1"]).is_err());
+    }
+
+    #[test]
+    fn test_explain_command() {
+        let config = parse(&["explain", "7", "42", "transactions.csv"]).unwrap();
+        assert_eq!(config.command, Command::Explain { client: 7, tx: 42 });
+        assert_eq!(config.input, Some("transactions.csv".into()));
+        assert!(parse(&["explain", "7"]).is_err());
+        assert!(parse(&["explain", "seven", "42", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(parse(&["--format", "xml"]).is_err());
+        assert!(parse(&["--format"]).is_err());
+        assert!(parse(&["--bool-format", "on-off"]).is_err());
+        assert!(parse(&["--skip", "-1"]).is_err());
+        assert!(parse(&["--unknown"]).is_err());
+        assert!(parse(&["a.csv", "b.csv"]).is_err());
+    }
+}
@@ -1,12 +1,20 @@
+use std::collections::HashSet;
 use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use bigdecimal::BigDecimal;
-use serde::Deserialize;
+use serde::de;
+use serde::{Deserialize, Deserializer};
 
+use crate::config::Config;
+use crate::output::OutputAccount;
 
-#[derive(Debug, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")] 
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
@@ -15,17 +23,62 @@ pub enum TransactionType {
     Chargeback
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+// Transaction types are parsed ignoring case and surrounding whitespace,
+// and with the synonyms that partner exports commonly use, such as `cb` for a chargeback.
+impl FromStr for TransactionType {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "deposit" | "dep" => Ok(TransactionType::Deposit),
+            "withdrawal" | "withdraw" | "wd" => Ok(TransactionType::Withdrawal),
+            "dispute" => Ok(TransactionType::Dispute),
+            "resolve" | "resolution" => Ok(TransactionType::Resolve),
+            "chargeback" | "charge-back" | "charge_back" | "cb" => Ok(TransactionType::Chargeback),
+            _ => Err(format!("Unknown transaction type: {}", s).into()),
+        }
+    }
+}
+
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+        };
+        f.write_str(name)
+    }
+}
+
+// Transaction types are parsed with `FromStr`, as not every input writes them the same way.
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
 pub struct InputTransaction {
     #[serde(alias = "type")]
     pub transaction_type: TransactionType,
-    pub client: u16,
+    pub client: u32,
     pub tx: u32,
+    // Missing for disputes, resolves and chargebacks, and so may be left out of the input altogether.
+    #[serde(default, deserialize_with = "deserialize_amount")]
     pub amount: Option<BigDecimal>,
+    #[serde(default)]
+    pub currency: Option<String>,
+    // When the transaction was made, as a Unix timestamp.
+    #[serde(default)]
+    pub timestamp: Option<i64>,
 }
 
 impl InputTransaction {
-    pub fn new(transaction_type: TransactionType, client: u16, tx: u32, amount: Option<&str>) -> Result<Self, Box<dyn Error>> {
+    pub fn new(transaction_type: TransactionType, client: u32, tx: u32, amount: Option<&str>) -> Result<Self, Box<dyn Error>> {
         let converted_amount = match amount { 
             None => None,
             Some(amount) => Some(BigDecimal::from_str(amount)?)
@@ -35,17 +88,445 @@ impl InputTransaction {
             client,
             tx,
             amount: converted_amount,
+            currency: None,
+            timestamp: None,
         })
     }
+
+    pub fn with_currency(mut self, currency: &str) -> Self {
+        self.currency = Some(currency.into());
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: i64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
 }
 
-pub fn parse_input_transaction<R>(input_stream: R) -> csv::DeserializeRecordsIntoIter<R, InputTransaction>
-where R: std::io::Read
-{
-    let reader = csv::ReaderBuilder::new()
+// Amounts are parsed from their text with `parse_amount`, rather than going through a float,
+// so that they are exact.
+fn deserialize_amount<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<BigDecimal>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(amount) if !amount.is_empty() => parse_amount(&amount).map(Some).map_err(de::Error::custom),
+        _ => Ok(None),
+    }
+}
+
+// The largest exponent accepted in scientific notation, far beyond any amount that can be stored,
+// so that an amount like `1e999999999` fails rather than expanding to a billion digits.
+const MAX_AMOUNT_EXPONENT: i64 = 100;
+
+// Parse a decimal amount, which may be in scientific notation, as some numeric systems export them.
+// An amount in scientific notation is normalized, e.g. `1.5e2` is parsed as `150`, and `1E-3` as `0.001`.
+pub fn parse_amount(amount: &str) -> Result<BigDecimal, Box<dyn Error>> {
+    let invalid = || format!("Invalid amount: {}", amount);
+    let (mantissa, exponent) = match amount.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (amount, None),
+    };
+    if !mantissa.chars().any(|c| c.is_ascii_digit()) {
+        return Err(invalid().into());
+    }
+    let mantissa = BigDecimal::from_str(mantissa).map_err(|_| invalid())?;
+    let exponent = match exponent {
+        None => return Ok(mantissa),
+        Some(exponent) => exponent.parse::<i64>().map_err(|_| invalid())?,
+    };
+    if exponent.abs() > MAX_AMOUNT_EXPONENT {
+        return Err(format!("Amount {} has an exponent out of range", amount).into());
+    }
+    let (digits, scale) = mantissa.as_bigint_and_exponent();
+    let value = BigDecimal::new(digits, scale - exponent).normalized();
+    let (_, scale) = value.as_bigint_and_exponent();
+    Ok(if scale < 0 { value.with_scale(0) } else { value })
+}
+
+// Which parts of the input CSV have whitespace trimmed around them.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum TrimPolicy {
+    #[default]
+    All,
+    Fields,
+    Headers,
+    None,
+}
+
+impl FromStr for TrimPolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(TrimPolicy::All),
+            "fields" => Ok(TrimPolicy::Fields),
+            "headers" => Ok(TrimPolicy::Headers),
+            "none" => Ok(TrimPolicy::None),
+            _ => Err(format!("Unknown trim policy: {}", s).into()),
+        }
+    }
+}
+
+impl From<TrimPolicy> for csv::Trim {
+    fn from(trim: TrimPolicy) -> Self {
+        match trim {
+            TrimPolicy::All => csv::Trim::All,
+            TrimPolicy::Fields => csv::Trim::Fields,
+            TrimPolicy::Headers => csv::Trim::Headers,
+            TrimPolicy::None => csv::Trim::None,
+        }
+    }
+}
+
+// How an input file is compressed, going by its extension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    None,
+    Zstd,
+    Bzip2,
+}
+
+impl Compression {
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("zst") => Compression::Zstd,
+            Some("bz2") => Compression::Bzip2,
+            _ => Compression::None,
+        }
+    }
+
+    // The cargo feature that enables reading this compression.
+    fn feature(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Zstd => "zstd",
+            Compression::Bzip2 => "bzip2",
+        }
+    }
+}
+
+// Whether a file is a CSV, either plain or compressed, e.g. `transactions.csv.zst`.
+fn is_csv(path: &Path) -> bool {
+    let path = match Compression::from_path(path) {
+        Compression::None => path.to_path_buf(),
+        _ => path.with_extension(""),
+    };
+    path.extension() == Some("csv".as_ref())
+}
+
+// Open an input file, decompressing it if it has the extension of a codec that's been enabled.
+fn open_input(path: &Path) -> Result<Box<dyn Read + Send>, Box<dyn Error>> {
+    let file = BufReader::new(File::open(path)?);
+    match Compression::from_path(path) {
+        Compression::None => Ok(Box::new(file)),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok(Box::new(zstd::Decoder::with_buffer(file)?)),
+        #[cfg(feature = "bzip2")]
+        Compression::Bzip2 => Ok(Box::new(bzip2::bufread::BzDecoder::new(file))),
+        #[allow(unreachable_patterns)]
+        compression => Err(format!(
+            "Reading {} requires pledger to be built with the `{}` feature.", path.display(), compression.feature(),
+        ).into()),
+    }
+}
+
+// Get the input CSVs as Readers: either the single file given as an argument,
+// or every `*.csv` file in `config.dir`, sorted by file name.
+// Files ending in `.zst` or `.bz2` are decompressed, if the `zstd` or `bzip2` feature is enabled.
+// With `config.recompute` there are no inputs, and the accounts are computed from the existing store.
+pub fn open_inputs(config: &Config) -> Result<Vec<Box<dyn Read + Send>>, Box<dyn Error>> {
+    if config.recompute {
+        if config.input.is_some() || config.dir.is_some() {
+            return Err("No input can be given with --recompute.".into());
+        }
+        return Ok(Vec::new());
+    }
+    let filenames = match (&config.input, &config.dir) {
+        (Some(_), Some(_)) => return Err("Either a file name or --dir can be given, not both.".into()),
+        (Some(filename), None) => vec![PathBuf::from(filename)],
+        (None, Some(dir)) => {
+            let mut filenames = Vec::new();
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_file() && is_csv(&path) {
+                    filenames.push(path);
+                }
+            }
+            filenames.sort();
+            filenames
+        },
+        (None, None) => return Err("A valid file name is required as an argument.".into()),
+    };
+    let mut inputs = Vec::with_capacity(filenames.len());
+    for filename in filenames {
+        inputs.push(open_input(&filename)?);
+    }
+    Ok(inputs)
+}
+
+// Read a list of client IDs, one per line, e.g. for `--allow-clients`.
+// Blank lines and lines starting with `#` are ignored.
+pub fn read_client_list(path: &Path) -> Result<HashSet<u32>, Box<dyn Error>> {
+    parse_client_list(BufReader::new(File::open(path)?))
+        .map_err(|e| format!("Invalid client list {}: {}", path.display(), e).into())
+}
+
+pub fn parse_client_list<R: BufRead>(input: R) -> Result<HashSet<u32>, Box<dyn Error>> {
+    let mut clients = HashSet::new();
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let client = line.parse().map_err(|_| format!("invalid client ID {:?} on line {}", line, index + 1))?;
+        clients.insert(client);
+    }
+    Ok(clients)
+}
+
+// Read the accounts written by a previous run as CSV, e.g. for `--diff`.
+pub fn read_accounts(path: &Path) -> Result<Vec<OutputAccount>, Box<dyn Error>> {
+    parse_accounts(File::open(path)?)
+        .map_err(|e| format!("Invalid accounts file {}: {}", path.display(), e).into())
+}
+
+pub fn parse_accounts<R: Read>(input: R) -> Result<Vec<OutputAccount>, Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new()
         .trim(csv::Trim::All)
+        .from_reader(input);
+    let accounts = reader.deserialize().collect::<Result<_, _>>()?;
+    Ok(accounts)
+}
+
+// Options for reading the input CSV.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct InputOptions {
+    // Lines starting with this character are skipped.
+    pub comment: Option<u8>,
+    pub trim: TrimPolicy,
+    // Accept amounts written the way accounting exports do, see `lenient_amount`.
+    pub lenient_amounts: bool,
+}
+
+// Rewrite an amount written as `+50.00` or as `(50.00)` (a negative amount),
+// to a form that `BigDecimal` can parse.
+pub fn lenient_amount(amount: &str) -> String {
+    let amount = amount.trim();
+    if let Some(negated) = amount.strip_prefix('(').and_then(|a| a.strip_suffix(')')) {
+        return format!("-{}", negated.trim());
+    }
+    amount.strip_prefix('+').unwrap_or(amount).into()
+}
+
+// A record with a different number of fields than the header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowShapeError {
+    pub line: u64,
+    pub expected: u64,
+    pub found: u64,
+}
+
+impl fmt::Display for RowShapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {} has {} fields, but the header has {}", self.line, self.found, self.expected)
+    }
+}
+
+impl Error for RowShapeError {}
+
+// Replace the error csv gives for a record with the wrong number of fields with a `RowShapeError`,
+// which is returned inside a `csv::Error`, like the other errors of reading an input.
+fn with_row_shape(error: csv::Error) -> csv::Error {
+    match error.kind() {
+        csv::ErrorKind::UnequalLengths { pos, expected_len, len } => {
+            let row_shape_error = RowShapeError {
+                line: pos.as_ref().map_or(0, |pos| pos.line()),
+                expected: *expected_len,
+                found: *len,
+            };
+            io::Error::new(io::ErrorKind::InvalidData, row_shape_error).into()
+        },
+        _ => error,
+    }
+}
+
+// The `RowShapeError` of a record with the wrong number of fields, if that's what the error is.
+pub fn row_shape_error(error: &csv::Error) -> Option<&RowShapeError> {
+    match error.kind() {
+        csv::ErrorKind::Io(error) => error.get_ref()?.downcast_ref(),
+        _ => None,
+    }
+}
+
+// The transactions read from an input CSV.
+pub struct InputTransactions<R> {
+    records: csv::StringRecordsIntoIter<R>,
+    headers: Result<csv::StringRecord, Option<csv::Error>>,
+    amount_column: Option<usize>,
+}
+
+impl<R: Read> Iterator for InputTransactions<R> {
+    type Item = Result<InputTransaction, csv::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let headers = match &mut self.headers {
+            Ok(headers) => headers,
+            Err(error) => return error.take().map(Err),
+        };
+        let mut record = match self.records.next()? {
+            Ok(record) => record,
+            Err(error) => return Some(Err(with_row_shape(error))),
+        };
+        if let Some(column) = self.amount_column {
+            if let Some(amount) = record.get(column) {
+                let amount = lenient_amount(amount);
+                record = record.iter()
+                    .enumerate()
+                    .map(|(i, field)| if i == column { amount.as_str() } else { field })
+                    .collect();
+            }
+        }
+        Some(record.deserialize(Some(headers)))
+    }
+}
+
+pub fn parse_input_transaction<R>(input_stream: R) -> InputTransactions<R>
+where R: Read
+{
+    parse_input_transaction_with(input_stream, &InputOptions::default())
+}
+
+pub fn parse_input_transaction_with<R>(input_stream: R, options: &InputOptions) -> InputTransactions<R>
+where R: Read
+{
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(options.trim.into())
+        .comment(options.comment)
         .from_reader(input_stream);
-    reader.into_deserialize()
+    let headers = reader.headers().cloned().map_err(Some)
+        .and_then(|headers| check_headers(&headers).map(|_| headers).map_err(Some));
+    let amount_column = match &headers {
+        Ok(headers) if options.lenient_amounts => headers.iter().position(|header| header == "amount"),
+        _ => None,
+    };
+    InputTransactions {
+        records: reader.into_records(),
+        headers,
+        amount_column,
+    }
+}
+
+const REQUIRED_COLUMNS: [&str; 3] = ["type", "client", "tx"];
+const OPTIONAL_COLUMNS: [&str; 3] = ["amount", "currency", "timestamp"];
+
+// Check that a header has the columns of an input transaction, so that a malformed header fails once,
+// naming the missing and unexpected columns, rather than failing every record.
+// An empty input has no columns at all, and is accepted.
+fn check_headers(headers: &csv::StringRecord) -> Result<(), csv::Error> {
+    if headers.iter().all(|header| header.is_empty()) {
+        return Ok(());
+    }
+    fn column(header: &str) -> &str {
+        if header == "transaction_type" { "type" } else { header }
+    }
+    let missing: Vec<&str> = REQUIRED_COLUMNS.iter()
+        .filter(|required| !headers.iter().any(|header| column(header) == **required))
+        .copied()
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let unexpected: Vec<&str> = headers.iter()
+        .filter(|header| !REQUIRED_COLUMNS.contains(&column(header)) && !OPTIONAL_COLUMNS.contains(header))
+        .collect();
+    let message = format!(
+        "Unexpected header: missing columns {}, unexpected columns {}",
+        missing.join(", "),
+        if unexpected.is_empty() { "none".into() } else { unexpected.join(", ") },
+    );
+    Err(io::Error::new(io::ErrorKind::InvalidData, message).into())
+}
+
+// Merges inputs that are each in timestamp order into a single stream in timestamp order.
+// Transactions without a timestamp come first, and ties are taken from the earlier input.
+// An error from any input is returned as soon as it is read.
+pub struct MergedTransactions<I> {
+    inputs: Vec<I>,
+    // The next transaction of each input, if it has been read.
+    heads: Vec<Option<InputTransaction>>,
+    exhausted: Vec<bool>,
+}
+
+pub fn merge_by_timestamp<I, E>(inputs: Vec<I>) -> MergedTransactions<I>
+where I: Iterator<Item = Result<InputTransaction, E>>
+{
+    let count = inputs.len();
+    MergedTransactions {
+        inputs,
+        heads: (0..count).map(|_| None).collect(),
+        exhausted: vec![false; count],
+    }
+}
+
+impl<I, E> Iterator for MergedTransactions<I>
+where I: Iterator<Item = Result<InputTransaction, E>>
+{
+    type Item = Result<InputTransaction, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (i, input) in self.inputs.iter_mut().enumerate() {
+            if self.heads[i].is_some() || self.exhausted[i] {
+                continue;
+            }
+            match input.next() {
+                Some(Ok(transaction)) => self.heads[i] = Some(transaction),
+                Some(Err(error)) => return Some(Err(error)),
+                None => self.exhausted[i] = true,
+            }
+        }
+        let earliest = self.heads.iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.as_ref().map(|transaction| (transaction.timestamp, i)))
+            .min()?;
+        self.heads[earliest.1].take().map(Ok)
+    }
+}
+
+// Decodes the input a line at a time, replacing invalid UTF-8 sequences
+// with the replacement character instead of failing the whole record.
+pub struct LossyUtf8Reader<R: BufRead> {
+    inner: R,
+    line: Vec<u8>,
+    decoded: Vec<u8>,
+    position: usize,
+}
+
+impl<R: BufRead> LossyUtf8Reader<R> {
+    pub fn new(inner: R) -> Self {
+        LossyUtf8Reader {
+            inner,
+            line: Vec::new(),
+            decoded: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Read for LossyUtf8Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.decoded.len() {
+            self.line.clear();
+            if self.inner.read_until(b'\n', &mut self.line)? == 0 {
+                return Ok(0);
+            }
+            self.decoded = String::from_utf8_lossy(&self.line).into_owned().into_bytes();
+            self.position = 0;
+        }
+        let count = buf.len().min(self.decoded.len() - self.position);
+        buf[..count].copy_from_slice(&self.decoded[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
 }
 
 #[cfg(test)]
@@ -69,18 +550,302 @@ mod tests {
                 transaction_type: TransactionType::Deposit, 
                 client: 7,
                 tx: 11,
-                amount: Some(BigDecimal::from_str("42.0").unwrap())
+                amount: Some(BigDecimal::from_str("42.0").unwrap()),
+                currency: None,
+                timestamp: None,
             }, 
             InputTransaction {
                 transaction_type: TransactionType::Withdrawal, 
                 client: 9,
                 tx: 18,
-                amount: Some(BigDecimal::from_str("6.5").unwrap())
+                amount: Some(BigDecimal::from_str("6.5").unwrap()),
+                currency: None,
+                timestamp: None,
             }, 
         ];
         assert_eq!(input_transactions, expected)
     }
 
+    #[test]
+    fn test_parse_client_list() {
+        let input = "# tenant A\n1\n 2 \n\n1\n";
+        assert_eq!(parse_client_list(input.as_bytes()).unwrap(), HashSet::from([1, 2]));
+
+        let error = parse_client_list("1\nclient 2\n".as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "invalid client ID \"client 2\" on line 2");
+    }
+
+    #[test]
+    fn test_parse_accounts() {
+        let input = "client,available,held,total,locked\n1,7.5000,2.5,10,false\n2, 0, 0, 0, true\n";
+        assert_eq!(parse_accounts(input.as_bytes()).unwrap(), vec![
+            OutputAccount::new(1, "7.5", "2.5", "10", false).unwrap(),
+            OutputAccount::new(2, "0", "0", "0", true).unwrap(),
+        ]);
+
+        let input = "client,currency,available,held,total,locked\n1,EUR,1,0,1,false\n";
+        assert_eq!(parse_accounts(input.as_bytes()).unwrap(), vec![
+            OutputAccount::new(1, "1", "0", "1", false).unwrap().with_currency("EUR"),
+        ]);
+
+        assert!(parse_accounts("client,available,held,total,locked\n1,x,0,0,false\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_row_shape() {
+        let input = "type,client,tx,amount\ndeposit,7,1\ndeposit,7,2,1.0,extra\ndeposit,7,3,1.0\n";
+        let results: Vec<Result<InputTransaction, csv::Error>> = parse_input_transaction(input.as_bytes()).collect();
+        assert_eq!(results.len(), 3);
+
+        let short = results[0].as_ref().unwrap_err();
+        assert_eq!(row_shape_error(short), Some(&RowShapeError { line: 2, expected: 4, found: 3 }));
+        assert_eq!(short.to_string(), "Line 2 has 3 fields, but the header has 4");
+
+        let long = results[1].as_ref().unwrap_err();
+        assert_eq!(row_shape_error(long), Some(&RowShapeError { line: 3, expected: 4, found: 5 }));
+        assert_eq!(long.to_string(), "Line 3 has 5 fields, but the header has 4");
+
+        assert_eq!(results[2].as_ref().unwrap().tx, 3);
+    }
+
+    #[test]
+    fn test_misspelled_header() {
+        let input = "typ,client,tx,amt\ndeposit,7,1,1.0\nwithdrawal,7,2,1.0\n";
+        let results: Vec<Result<InputTransaction, csv::Error>> = parse_input_transaction(input.as_bytes()).collect();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].as_ref().unwrap_err().to_string(),
+            "Unexpected header: missing columns type, unexpected columns typ, amt",
+        );
+
+        let input = "transaction_type,client,tx,amount,note\ndeposit,7,1,1.0,first\n";
+        assert_eq!(parse_input_transaction(input.as_bytes()).next().unwrap().unwrap().tx, 1);
+        assert_eq!(parse_input_transaction("".as_bytes()).count(), 0);
+    }
+
+    #[test]
+    fn test_transaction_type_from_str() {
+        let cases = [
+            ("deposit", TransactionType::Deposit),
+            ("Withdrawal", TransactionType::Withdrawal),
+            ("DISPUTE", TransactionType::Dispute),
+            ("resolve", TransactionType::Resolve),
+            ("chargeBack", TransactionType::Chargeback),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(s.parse::<TransactionType>().unwrap(), expected);
+        }
+        for (_, transaction_type) in cases {
+            assert_eq!(transaction_type.to_string().parse::<TransactionType>().unwrap(), transaction_type);
+        }
+        let error = "refund".parse::<TransactionType>().unwrap_err();
+        assert_eq!(error.to_string(), "Unknown transaction type: refund");
+    }
+
+    #[test]
+    fn test_transaction_type_synonyms() {
+        let cases = [
+            ("DEP", TransactionType::Deposit),
+            ("Withdraw", TransactionType::Withdrawal),
+            ("wd", TransactionType::Withdrawal),
+            ("Resolution", TransactionType::Resolve),
+            ("CB", TransactionType::Chargeback),
+            ("charge-back", TransactionType::Chargeback),
+            ("Charge_Back", TransactionType::Chargeback),
+            (" deposit ", TransactionType::Deposit),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(s.parse::<TransactionType>().unwrap(), expected, "{}", s);
+        }
+
+        // Whitespace in the type column is ignored even when fields aren't trimmed.
+        let input = "type,client,tx,amount\nDeposit ,7,1,1.0\nCb,7,1,\n";
+        let options = InputOptions { trim: TrimPolicy::None, ..InputOptions::default() };
+        let types: Vec<TransactionType> = parse_input_transaction_with(input.as_bytes(), &options)
+            .map(|r| r.unwrap().transaction_type)
+            .collect();
+        assert_eq!(types, vec![TransactionType::Deposit, TransactionType::Chargeback]);
+
+        let input = "type,client,tx,amount\nDEPOSITT,7,1,1.0\n";
+        let error = parse_input_transaction(input.as_bytes()).next().unwrap().unwrap_err();
+        assert!(error.to_string().contains("Unknown transaction type: DEPOSITT"), "{}", error);
+    }
+
+    #[test]
+    fn test_parse_mixed_case_types() {
+        let input = "
+            type,       client, tx, amount
+            Deposit,    7,      1,  1.0
+            WITHDRAWAL, 7,      2,  1.0
+            dIsPuTe,    7,      1,
+            Resolve,    7,      1,
+            CHARGEBACK, 7,      1,";
+        let types: Vec<TransactionType> = parse_input_transaction(input.as_bytes())
+            .map(|r| r.unwrap().transaction_type)
+            .collect();
+        assert_eq!(types, vec![
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ]);
+
+        let input = "type,client,tx,amount\nrefund,7,1,1.0\n";
+        let error = parse_input_transaction(input.as_bytes()).next().unwrap().unwrap_err();
+        assert!(error.to_string().contains("Unknown transaction type: refund"), "{}", error);
+    }
+
+    #[test]
+    fn test_trim_policy() {
+        let input = "type,client,tx,amount,currency\ndeposit,7,11,42.0,  EUR\n";
+        let currency = |trim| {
+            let options = InputOptions { trim, ..InputOptions::default() };
+            parse_input_transaction_with(input.as_bytes(), &options).next().unwrap().unwrap().currency.unwrap()
+        };
+        assert_eq!(currency(TrimPolicy::All), "EUR");
+        assert_eq!(currency(TrimPolicy::Fields), "EUR");
+        assert_eq!(currency(TrimPolicy::Headers), "  EUR");
+        assert_eq!(currency(TrimPolicy::None), "  EUR");
+        assert!("some".parse::<TrimPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_parse_amount() {
+        let parse = |amount| parse_amount(amount).map(|amount| amount.to_string());
+        assert_eq!(parse("1.5e2").unwrap(), "150");
+        assert_eq!(parse("1E-3").unwrap(), "0.001");
+        assert_eq!(parse("-2.50E+1").unwrap(), "-25");
+        assert_eq!(parse("2.5000").unwrap(), "2.5000");
+        assert_eq!(parse(".5").unwrap(), "0.5");
+        assert_eq!(parse("1.5e").unwrap_err().to_string(), "Invalid amount: 1.5e");
+        assert!(parse("e5").is_err());
+        assert!(parse("1.5e2.5").is_err());
+        assert!(parse("1e5e5").is_err());
+        assert!(parse("").is_err());
+        assert_eq!(parse("1e999999999").unwrap_err().to_string(), "Amount 1e999999999 has an exponent out of range");
+
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      11, 1.5e2
+            withdrawal, 7,      12, 1E-3
+            deposit,    7,      13, 12345678901234.56789
+            dispute,    7,      11,";
+        let input_transactions: Vec<InputTransaction> = parse_input_transaction(input.as_bytes())
+            .map(|r| r.unwrap())
+            .collect();
+        let expected = vec![
+            InputTransaction::new(TransactionType::Deposit, 7, 11, Some("150")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal, 7, 12, Some("0.001")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit, 7, 13, Some("12345678901234.56789")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute, 7, 11, None).unwrap(),
+        ];
+        assert_eq!(input_transactions, expected);
+        // Exactly, rather than as the nearest float.
+        assert_eq!(input_transactions[2].amount.as_ref().unwrap().to_string(), "12345678901234.56789");
+
+        let malformed = "type,client,tx,amount\ndeposit,7,11,1.5e\n";
+        let results: Vec<Result<InputTransaction, csv::Error>> = parse_input_transaction(malformed.as_bytes()).collect();
+        assert!(results[0].as_ref().unwrap_err().to_string().contains("Invalid amount: 1.5e"));
+    }
+
+    #[test]
+    fn test_lenient_amounts() {
+        assert_eq!(lenient_amount("(50.00)"), "-50.00");
+        assert_eq!(lenient_amount("( 50.00 )"), "-50.00");
+        assert_eq!(lenient_amount("+50.00"), "50.00");
+        assert_eq!(lenient_amount("50.00"), "50.00");
+        assert_eq!(lenient_amount(""), "");
+
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      11, +50.00
+            withdrawal, 7,      12, (50.00)
+            dispute,    7,      11,";
+        let options = InputOptions { lenient_amounts: true, ..InputOptions::default() };
+        let input_transactions: Vec<InputTransaction> = parse_input_transaction_with(input.as_bytes(), &options)
+            .map(|r| r.unwrap())
+            .collect();
+        let expected = vec![
+            InputTransaction::new(TransactionType::Deposit, 7, 11, Some("50.00")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal, 7, 12, Some("-50.00")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute, 7, 11, None).unwrap(),
+        ];
+        assert_eq!(input_transactions, expected);
+
+        let strict: Vec<Result<InputTransaction, csv::Error>> = parse_input_transaction(input.as_bytes()).collect();
+        assert!(strict[1].is_err());
+    }
+
+    #[test]
+    fn test_parse_without_amount_column() {
+        let input = "
+            type,       client, tx
+            dispute,    7,      11
+            deposit,    7,      12";
+        let input_transactions: Vec<InputTransaction> = parse_input_transaction(input.as_bytes())
+            .map(|r| r.unwrap())
+            .collect();
+        let expected = vec![
+            InputTransaction::new(TransactionType::Dispute, 7, 11, None).unwrap(),
+            InputTransaction::new(TransactionType::Deposit, 7, 12, None).unwrap(),
+        ];
+        assert_eq!(input_transactions, expected);
+    }
+
+    #[test]
+    fn test_parse_comments() {
+        let input = "\
+# generated at 2024-01-01T00:00:00Z
+type,client,tx,amount
+deposit,7,11,42.0
+# batch 2
+withdrawal,7,12,2.0";
+        let options = InputOptions { comment: Some(b'#'), ..InputOptions::default() };
+        let input_transactions: Vec<InputTransaction> = parse_input_transaction_with(input.as_bytes(), &options)
+            .map(|r| r.unwrap())
+            .collect();
+        let expected = vec![
+            InputTransaction::new(TransactionType::Deposit, 7, 11, Some("42.0")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal, 7, 12, Some("2.0")).unwrap(),
+        ];
+        assert_eq!(input_transactions, expected);
+
+        assert!(parse_input_transaction(input.as_bytes()).any(|r| r.is_err()));
+    }
+
+    #[test]
+    fn test_parse_currency() {
+        let input = "
+            type, client, tx, amount, currency
+            deposit, 7, 11, 42.0, USD
+            deposit, 7, 12, 6.5,";
+        let input_transactions: Vec<InputTransaction> = parse_input_transaction(input.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let expected = vec![
+            InputTransaction::new(TransactionType::Deposit, 7, 11, Some("42.0")).unwrap().with_currency("USD"),
+            InputTransaction::new(TransactionType::Deposit, 7, 12, Some("6.5")).unwrap(),
+        ];
+        assert_eq!(input_transactions, expected)
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let input = "
+            type, client, tx, amount, timestamp
+            deposit, 7, 11, 42.0, 1704067200
+            deposit, 7, 12, 6.5,";
+        let input_transactions: Vec<InputTransaction> = parse_input_transaction(input.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let expected = vec![
+            InputTransaction::new(TransactionType::Deposit, 7, 11, Some("42.0")).unwrap().with_timestamp(1704067200),
+            InputTransaction::new(TransactionType::Deposit, 7, 12, Some("6.5")).unwrap(),
+        ];
+        assert_eq!(input_transactions, expected)
+    }
+
     #[test]
     fn test_empty_input() {
         let input = "";
@@ -90,4 +855,95 @@ mod tests {
         let expected = vec![];
         assert_eq!(input_transactions, expected)
     }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let input = b"type,client,tx,amount,comment\ndeposit,1,1,1.0,bad\xff\ndeposit,1,2,2.0,\xe2\x82\xac\n";
+
+        let strict: Vec<Result<InputTransaction, csv::Error>> = parse_input_transaction(&input[..]).collect();
+        assert!(strict[0].is_err());
+
+        let lossy: Vec<InputTransaction> = parse_input_transaction(LossyUtf8Reader::new(&input[..]))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let expected = vec![
+            InputTransaction::new(TransactionType::Deposit, 1, 1, Some("1.0")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit, 1, 2, Some("2.0")).unwrap(),
+        ];
+        assert_eq!(lossy, expected)
+    }
+
+    #[test]
+    fn test_merge_by_timestamp() {
+        let first = "
+            type, client, tx, amount, timestamp
+            deposit, 1, 1, 10.0, 100
+            deposit, 1, 2, 10.0, 300
+            deposit, 1, 3, 10.0, 500";
+        let second = "
+            type, client, tx, amount, timestamp
+            deposit, 2, 4, 10.0, 200
+            deposit, 2, 5, 10.0, 300
+            deposit, 2, 6, 10.0, 600";
+        let inputs = vec![parse_input_transaction(first.as_bytes()), parse_input_transaction(second.as_bytes())];
+        let merged: Vec<(Option<i64>, u32)> = merge_by_timestamp(inputs)
+            .map(|result| result.map(|transaction| (transaction.timestamp, transaction.tx)))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(merged, vec![
+            (Some(100), 1), (Some(200), 4), (Some(300), 2), (Some(300), 5), (Some(500), 3), (Some(600), 6),
+        ]);
+    }
+
+    #[test]
+    fn test_merge_by_timestamp_error() {
+        let first = "
+            type, client, tx, amount, timestamp
+            deposit, 1, 1, 10.0, 100";
+        let second = "
+            type, client, tx, amount, timestamp
+            deposit, 2, two, 10.0, 200";
+        let inputs = vec![parse_input_transaction(first.as_bytes()), parse_input_transaction(second.as_bytes())];
+        let merged: Vec<Result<InputTransaction, csv::Error>> = merge_by_timestamp(inputs).collect();
+        assert_eq!(merged.len(), 2);
+        assert!(merged[0].is_err());
+        assert_eq!(merged[1].as_ref().unwrap().tx, 1);
+    }
+
+    // Reads mutations of the fuzzing seed inputs, which must each give a transaction or an error for every record.
+    // `cargo fuzz run parse_input` in the `fuzz` directory explores far more inputs than this.
+    #[test]
+    fn test_parse_mutated_inputs() {
+        let seeds: Vec<Vec<u8>> = std::fs::read_dir("fuzz/corpus/parse_input").unwrap()
+            .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+            .collect();
+        assert!(!seeds.is_empty());
+        let lenient = InputOptions { comment: Some(b'#'), trim: TrimPolicy::None, lenient_amounts: true };
+        // A xorshift generator, so that any failure can be reproduced.
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let alphabet = b",\n\"#()+-.0123456789abcdefghijklmnopqrstuvwxyz \xff";
+        for seed in &seeds {
+            for _ in 0..200 {
+                let mut input = seed.clone();
+                for _ in 0..1 + random() % 8 {
+                    let position = (random() % (input.len() as u64 + 1)) as usize;
+                    let byte = alphabet[(random() % alphabet.len() as u64) as usize];
+                    match random() % 3 {
+                        0 if position < input.len() => input[position] = byte,
+                        1 if position < input.len() => { input.remove(position); },
+                        _ => input.insert(position, byte),
+                    }
+                }
+                let records = input.iter().filter(|byte| **byte == b'\n' || **byte == b'\r').count() + 1;
+                assert!(parse_input_transaction(input.as_slice()).count() <= records, "{:?}", input);
+                assert!(parse_input_transaction_with(input.as_slice(), &lenient).count() <= records, "{:?}", input);
+            }
+        }
+    }
 }
@@ -0,0 +1,9 @@
+pub mod account_table;
+pub mod config;
+pub mod input;
+pub mod output;
+pub mod sharded_transactions;
+pub mod shutdown;
+pub mod summary;
+pub mod transactions;
+pub mod transactions_store;
@@ -1,55 +1,695 @@
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::io;
 use std::io::{Read, Write};
+use std::time::{Instant, SystemTime};
 
+use bigdecimal::BigDecimal;
 use futures_util::pin_mut;
-use futures_util::TryStreamExt;
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use log::{info, warn};
 
-pub mod input;
-pub mod output;
-pub mod transactions;
-pub mod transactions_store;
+use pledger::account_table::AccountTableWriter;
+use pledger::config::{Command, Config, LimitPolicy};
+use pledger::input;
+use pledger::input::{InputOptions, InputTransaction, LossyUtf8Reader};
+use pledger::output::{is_broken_pipe, output_account_schema, AccountWriter, AtomicFile, DiffWriter, OutputAccount, RejectsWriter, SplitAccountWriter};
+use pledger::shutdown::{Interrupted, Shutdown, INTERRUPTED_EXIT_CODE};
+use pledger::summary::{BookTotals, RunSummary};
+use pledger::transactions::{ApplyOutcome, Explanation, TransactionError, Transactions, TransactionsBuilder};
 
-// Get the input CSV as a Reader.
-async fn get_input() -> Result<std::io::BufReader<std::fs::File>, Box<dyn Error>> {
-    let filename = std::env::args().skip(1).next()
-        .ok_or("A valid file name is required as an argument.")?;
-    let file = std::fs::File::open(filename)?;
-    let input_reader = std::io::BufReader::new(file);
-    Ok(input_reader)
+// Whether a CSV error is about a single record, which can be skipped, rather than the whole input.
+fn is_invalid_record(error: &csv::Error) -> bool {
+    matches!(error.kind(), csv::ErrorKind::Deserialize { .. } | csv::ErrorKind::Utf8 { .. })
+        || input::row_shape_error(error).is_some()
 }
 
-// Main transaction processor.
-// Converts a CSV of transactions from `input` and writes a CSV of accounts to `output`. 
-async fn process_transactions<R: Read, W: Write>(input: R, output: W, db_url: &str) -> Result<(), Box<dyn Error>> {
-    let input_transactions = input::parse_input_transaction(input);
-
-    let transactions = transactions::Transactions::new(db_url).await?;
-    for result in input_transactions {
-        let input_transaction = result?;
-        transactions.add_input(input_transaction).await?;
+// Count an invalid record that is skipped, or fail with it when it is one more than `max_errors`, if that is set.
+fn skip_invalid_record(error: Box<dyn Error>, errors: &mut usize, max_errors: Option<usize>) -> Result<(), Box<dyn Error>> {
+    *errors += 1;
+    if let Some(max_errors) = max_errors.filter(|max_errors| *errors > *max_errors) {
+        return Err(format!("Stopped after {} invalid input records, more than the maximum of {}. The last was: {}", errors, max_errors, error).into());
+    }
+    warn!("Skipped invalid input record: {}", error);
+    Ok(())
+}
+
+// The builder of the store for the options of `config`, shared by every command that reads or writes it,
+// so that they all compute the accounts the same way.
+fn transactions_builder(config: &Config) -> Result<TransactionsBuilder, Box<dyn Error>> {
+    Ok(Transactions::builder(&config.db_url)
+        .append(config.append || config.recompute)
+        .round_digits(config.round_digits.into())
+        .round_mode(config.round_mode)
+        .column_scales(config.column_scales)
+        .fixed_scale(config.fixed_scale)
+        .pool_size(config.pool_size)
+        .reject_client_zero(config.reject_client_zero)
+        .allowed_clients(config.allow_clients.as_deref().map(input::read_client_list).transpose()?)
+        .denied_clients(config.deny_clients.as_deref().map(input::read_client_list).transpose()?)
+        .max_input_scale(config.max_input_scale)
+        .strict_input_scale(config.strict_input_scale)
+        .pending_disputes(config.pending_disputes)
+        .lock_halts_replay(config.lock_halts_replay)
+        .ignore_disputes(config.ignore_disputes)
+        .negative_available_policy(config.negative_available)
+        .withdrawal_policy(config.withdrawal_policy)
+        .strict_dispute_client(config.strict_dispute_client)
+        .freeze_on_dispute(config.freeze_on_dispute)
+        .conflict_policy(config.on_conflict)
+        .as_of(config.as_of)
+        .run_id(Some(config.run_id.clone().unwrap_or_else(|| humantime::format_rfc3339_millis(SystemTime::now()).to_string()))))
+}
+
+// Load CSVs of transactions from `inputs`, one after the other, into the store,
+// or merged in timestamp order with `config.merge_by_timestamp`,
+// skipping the first `config.skip` records, which were loaded by a previous run.
+// At most `config.max_transactions` records are loaded, after which the rest are either
+// ignored or the run fails, according to `config.limit_policy`.
+// Stops early, closing the store, if a shutdown is triggered.
+// The records read and skipped are counted in `summary`.
+// With `config.group_by_client`, all the records are read first, and then each client's are stored
+// in a database transaction of their own, so that a failure leaves a client either fully updated or not at all.
+// Skipped records are written to `config.rejects` if it is set.
+// Invalid records are skipped, unless there are more than `config.max_errors`, which fails the run.
+// If `config.dump_transactions` is set, all the stored transactions are then written to that file,
+// and if `config.held_report` is set, the clients with held funds are written to that file.
+async fn load_transactions<'a, I, R>(inputs: I, config: &Config, shutdown: &Shutdown, summary: &mut RunSummary) -> Result<Transactions, Box<dyn Error>>
+where
+    I: IntoIterator<Item = R> + 'a,
+    R: Read + 'a,
+{
+    let lossy = config.lossy;
+    let input_options = InputOptions {
+        comment: config.comment_char,
+        trim: config.trim,
+        lenient_amounts: config.lenient_amounts,
+    };
+    let parsed_inputs = inputs.into_iter().map(move |input| {
+        let input: Box<dyn Read + 'a> = if lossy {
+            Box::new(LossyUtf8Reader::new(io::BufReader::new(input)))
+        } else {
+            Box::new(input)
+        };
+        input::parse_input_transaction_with(input, &input_options)
+    });
+    let input_transactions: Box<dyn Iterator<Item = _> + 'a> = if config.merge_by_timestamp {
+        Box::new(input::merge_by_timestamp(parsed_inputs.collect()))
+    } else {
+        Box::new(parsed_inputs.flatten())
+    };
+
+    let transactions = transactions_builder(config)?.build().await?;
+    let mut rejects = match &config.rejects {
+        Some(path) => Some(RejectsWriter::new(io::BufWriter::new(std::fs::File::create(path)?))),
+        None => None,
+    };
+    // With `config.group_by_client`, the inputs for each client, in the order the clients first appear.
+    let mut groups: Option<Vec<Vec<InputTransaction>>> = if config.group_by_client { Some(Vec::new()) } else { None };
+    let mut group_indexes = HashMap::new();
+    let mut records = config.skip;
+    let mut errors = 0;
+    for (loaded, result) in input_transactions.skip(config.skip).enumerate() {
+        if Some(loaded) == config.max_transactions {
+            if config.limit_policy == LimitPolicy::Truncate {
+                warn!("Ignoring input records after the first {}", loaded);
+                break;
+            }
+            transactions.close().await;
+            return Err(format!("The input has more than the maximum of {} transactions", loaded).into());
+        }
+        if shutdown.is_triggered() {
+            transactions.close().await;
+            warn!("Stopped after {} input records", records);
+            return Err(Interrupted.into());
+        }
+        let input_transaction = match result {
+            Ok(input_transaction) => input_transaction,
+            Err(error) if is_invalid_record(&error) => {
+                skip_invalid_record(error.into(), &mut errors, config.max_errors)?;
+                summary.skipped += 1;
+                summary.processed += 1;
+                records += 1;
+                continue;
+            },
+            Err(error) => return Err(error.into()),
+        };
+        if let Some(groups) = &mut groups {
+            let index = *group_indexes.entry(input_transaction.client).or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+            groups[index].push(input_transaction);
+            records += 1;
+            continue;
+        }
+        let (client, tx) = (input_transaction.client, input_transaction.tx);
+        let rejected = rejects.as_ref().map(|_| input_transaction.clone());
+        let outcome = match transactions.add_input(input_transaction).await {
+            Err(error) if error.is::<TransactionError>() => {
+                if let (Some(rejects), Some(rejected)) = (&mut rejects, &rejected) {
+                    rejects.write_reject(rejected, &error.to_string())?;
+                }
+                skip_invalid_record(error, &mut errors, config.max_errors)?;
+                summary.skipped += 1;
+                summary.processed += 1;
+                records += 1;
+                continue;
+            },
+            outcome => outcome?,
+        };
+        if let ApplyOutcome::Skipped(reason) = outcome {
+            warn!("Skipped transaction {} for client {}: {}", tx, client, reason);
+            summary.skipped += 1;
+            if let (Some(rejects), Some(rejected)) = (&mut rejects, &rejected) {
+                rejects.write_reject(rejected, &reason.to_string())?;
+            }
+        }
+        summary.processed += 1;
+        records += 1;
     } 
+    for (committed, group) in groups.unwrap_or_default().into_iter().enumerate() {
+        if shutdown.is_triggered() {
+            transactions.close().await;
+            warn!("Stopped after committing the transactions of {} clients", committed);
+            return Err(Interrupted.into());
+        }
+        // An invalid record rolls back the client's other transactions, and is skipped as in the ungrouped path,
+        // along with the rest of the client's records.
+        let outcomes = match transactions.add_inputs_atomically(&group).await {
+            Err(error) if error.is::<TransactionError>() => {
+                let reason = format!("rolled back with the client's invalid record: {}", error);
+                warn!("Rolled back the {} transactions of client {}", group.len(), group[0].client);
+                skip_invalid_record(error, &mut errors, config.max_errors)?;
+                summary.skipped += group.len();
+                summary.processed += group.len();
+                if let Some(rejects) = &mut rejects {
+                    for input_transaction in &group {
+                        rejects.write_reject(input_transaction, &reason)?;
+                    }
+                }
+                continue;
+            },
+            outcomes => outcomes?,
+        };
+        for (input_transaction, outcome) in group.iter().zip(outcomes) {
+            if let ApplyOutcome::Skipped(reason) = outcome {
+                warn!("Skipped transaction {} for client {}: {}", input_transaction.tx, input_transaction.client, reason);
+                summary.skipped += 1;
+                if let Some(rejects) = &mut rejects {
+                    rejects.write_reject(input_transaction, &reason.to_string())?;
+                }
+            }
+            summary.processed += 1;
+        }
+    }
+    if let Some(rejects) = &mut rejects {
+        rejects.finish()?;
+    }
+    info!(
+        "Loaded {} input records, last committed ordinal: {}",
+        records,
+        transactions.max_ordinal().await?.unwrap_or(0),
+    );
+    if let Some(path) = &config.dump_transactions {
+        let dumped = transactions.dump_transactions(io::BufWriter::new(std::fs::File::create(path)?)).await?;
+        info!("Dumped {} transactions to {}", dumped, path.display());
+    }
+    if let Some(path) = &config.held_report {
+        let report = transactions.held_report().await?;
+        report.write_csv(io::BufWriter::new(std::fs::File::create(path)?))?;
+        info!("Wrote {} clients with held funds to {}", report.entries.len(), path.display());
+    }
+    Ok(transactions)
+}
 
-    let accounts = transactions.get_accounts().await;
+// Log a warning if the amounts of an account about to be written are inconsistent,
+// returning whether they were.
+fn warn_if_inconsistent(account: &OutputAccount) -> bool {
+    match account.check_invariants() {
+        Ok(()) => false,
+        Err(violation) => {
+            warn!("Writing an inconsistent account: {}", violation);
+            true
+        },
+    }
+}
 
-    let mut writer = csv::Writer::from_writer(output);
+// Log a warning if the held amount of an account is above `threshold`, which can indicate a large dispute,
+// returning whether it was.
+fn warn_if_held_above(account: &OutputAccount, threshold: Option<&BigDecimal>) -> bool {
+    match threshold {
+        Some(threshold) if &account.held > threshold => {
+            warn!("Client {} has {} held, which is above the alert threshold of {}", account.client, account.held, threshold);
+            true
+        },
+        _ => false,
+    }
+}
+
+// Log a warning if the held funds across the book are above `config.max_held_ratio` of the total,
+// which can indicate a dispute storm or a bug, or fail if `config.fail_on_held_ratio` is set.
+// Returns whether they were above it.
+fn check_held_ratio(book: &BookTotals, config: &Config) -> Result<bool, Box<dyn Error>> {
+    let max_ratio = match &config.max_held_ratio {
+        Some(max_ratio) => max_ratio,
+        None => return Ok(false),
+    };
+    let messages: Vec<String> = book.held_ratio_above(max_ratio)
+        .map(|(currency, totals)| {
+            let currency = currency.as_ref().map(|currency| format!(" in {}", currency)).unwrap_or_default();
+            format!("The held funds of {} are more than {} of the total funds of {}{}", totals.held, max_ratio, totals.total, currency)
+        })
+        .collect();
+    if messages.is_empty() {
+        return Ok(false);
+    }
+    if config.fail_on_held_ratio {
+        return Err(messages.join("; ").into());
+    }
+    for message in messages {
+        warn!("{}", message);
+    }
+    Ok(true)
+}
+
+// Convert the amounts of an account to integer minor units if `config.integer_amounts` is set.
+fn output_amounts(account: OutputAccount, config: &Config) -> Result<OutputAccount, Box<dyn Error>> {
+    if config.integer_amounts {
+        account.to_minor_units(config.round_digits.into())
+    } else {
+        Ok(account)
+    }
+}
+
+// Whether writing failed because the reader of the output went away, in which case
+// processing stops cleanly, like other Unix tools piped into `head`.
+fn output_closed(result: Result<(), Box<dyn Error>>) -> Result<bool, Box<dyn Error>> {
+    match result {
+        Ok(()) => Ok(false),
+        Err(error) if is_broken_pipe(error.as_ref()) => Ok(true),
+        Err(error) => Err(error),
+    }
+}
+
+// The accounts of at most `max_clients` clients, with those that aren't locked first if `group_by_locked` is set.
+// With `config.recompute`, every account is replayed from the stored transactions, instead of read from its stored balance.
+async fn computed_accounts(transactions: &Transactions, max_clients: Option<u32>, group_by_locked: bool, config: &Config) -> impl Stream<Item = Result<OutputAccount, Box<dyn Error>>> {
+    if config.recompute {
+        transactions.clone().recompute_all(max_clients, group_by_locked).await.left_stream()
+    } else if group_by_locked {
+        transactions.clone().get_accounts_grouped_by_locked(max_clients).await.left_stream().right_stream()
+    } else {
+        transactions.clone().get_accounts_for_clients(max_clients).await.right_stream().right_stream()
+    }
+}
+
+// Main transaction processor.
+// Converts CSVs of transactions from `inputs` and writes the accounts to `output`
+// in the configured format, through a buffer of `config.write_buffer_bytes`.
+// With `config.group_by_locked`, the accounts that aren't locked are written first, and then those that are.
+// If a shutdown is triggered, the accounts written so far are flushed and `Interrupted` is returned.
+// If the reader of `output` goes away, the remaining accounts are not written, which isn't an error.
+// Once every account is written, the held funds across them are checked against `config.max_held_ratio`.
+async fn process_transactions<I, R, W>(inputs: I, output: W, config: &Config, shutdown: &Shutdown) -> Result<RunSummary, Box<dyn Error>>
+where
+    I: IntoIterator<Item = R>,
+    R: Read,
+    W: Write,
+{
+    let start = Instant::now();
+    let mut summary = RunSummary::default();
+    let transactions = load_transactions(inputs, config, shutdown, &mut summary).await?;
+
+    // Every row of a CSV has the same columns, so there is a currency column if any account has a currency.
+    let currency_column = transactions.has_currencies().await?;
+    let output = io::BufWriter::with_capacity(config.write_buffer_bytes, output);
+    let mut writer = AccountWriter::new(output, &config.format, config.bool_format).with_currency_column(currency_column);
+    let mut clients = HashSet::new();
+    let mut book = BookTotals::default();
+    let mut split_writer = match &config.split_output {
+        Some(dir) => Some(SplitAccountWriter::create(dir)?.with_currency_column(currency_column)),
+        None => None,
+    };
+
+    let mut result = Ok(());
+    let mut closed = false;
+    // The accounts are dropped before the store is closed, as they hold a connection until they are.
+    {
+        let accounts = computed_accounts(&transactions, config.max_clients, config.group_by_locked, config).await;
+        pin_mut!(accounts);
+        while let Some(account) = accounts.try_next().await? {
+            if let Err(interrupted) = shutdown.check() {
+                result = Err(interrupted.into());
+                break;
+            }
+            if config.skip_zero && account.is_zero() {
+                continue;
+            }
+            warn_if_inconsistent(&account);
+            warn_if_held_above(&account, config.held_alert_threshold.as_ref());
+            book.add(&account);
+            let mut account = output_amounts(account, config)?;
+            if config.timestamp_output {
+                account.computed_at = Some(humantime::format_rfc3339_millis(SystemTime::now()).to_string());
+            }
+            if output_closed(writer.write_account(&account))? {
+                closed = true;
+                break;
+            }
+            if let Some(split_writer) = &mut split_writer {
+                split_writer.write_account(&account)?;
+            }
+            clients.insert(account.client);
+            summary.locked_accounts += account.locked as usize;
+        }
+    }
+    if !closed {
+        closed = output_closed(writer.finish())?;
+    }
+    if closed {
+        info!("Stopped writing accounts, as the output was closed");
+    }
+    if let Some(split_writer) = &mut split_writer {
+        split_writer.finish()?;
+    }
+    transactions.close().await;
+    if result.is_ok() && !closed {
+        check_held_ratio(&book, config)?;
+    }
+
+    summary.clients = clients.len();
+    summary.duration = start.elapsed();
+    result.map(|_| summary)
+}
+
+// Processes CSVs of transactions from `inputs` and writes the accounts to the `accounts` table
+// of the database at `db_url`, instead of writing them out as CSV.
+async fn process_transactions_to_table<I, R>(inputs: I, db_url: &str, config: &Config, shutdown: &Shutdown) -> Result<RunSummary, Box<dyn Error>>
+where
+    I: IntoIterator<Item = R>,
+    R: Read,
+{
+    let start = Instant::now();
+    let mut summary = RunSummary::default();
+    let transactions = load_transactions(inputs, config, shutdown, &mut summary).await?;
+    let table_writer = AccountTableWriter::connect(db_url, "accounts").await?;
+    let mut clients = HashSet::new();
+    let mut book = BookTotals::default();
+
+    let mut result = Ok(());
+    {
+        let accounts = computed_accounts(&transactions, config.max_clients, false, config).await;
+        pin_mut!(accounts);
+        while let Some(account) = accounts.try_next().await? {
+            if let Err(interrupted) = shutdown.check() {
+                result = Err(interrupted.into());
+                break;
+            }
+            if config.skip_zero && account.is_zero() {
+                continue;
+            }
+            warn_if_inconsistent(&account);
+            warn_if_held_above(&account, config.held_alert_threshold.as_ref());
+            book.add(&account);
+            let account = output_amounts(account, config)?;
+            table_writer.write_account(&account).await?;
+            clients.insert(account.client);
+            summary.locked_accounts += account.locked as usize;
+        }
+    }
+    table_writer.close().await;
+    transactions.close().await;
+    if result.is_ok() {
+        check_held_ratio(&book, config)?;
+    }
+
+    summary.clients = clients.len();
+    summary.duration = start.elapsed();
+    result.map(|_| summary)
+}
+
+// Processes CSVs of transactions from `inputs` and checks that every account is consistent.
+// Returns the number of accounts checked, or an error listing every inconsistent account.
+async fn verify_transactions<I, R>(inputs: I, config: &Config, shutdown: &Shutdown) -> Result<usize, Box<dyn Error>>
+where
+    I: IntoIterator<Item = R>,
+    R: Read,
+{
+    let transactions = load_transactions(inputs, config, shutdown, &mut RunSummary::default()).await?;
+    let accounts = computed_accounts(&transactions, None, false, config).await;
 
+    let mut checked = 0;
+    let mut violations = Vec::new();
     pin_mut!(accounts);
     while let Some(account) = accounts.try_next().await? {
-        writer.serialize(account)?;
+        checked += 1;
+        if let Err(violation) = account.check_invariants() {
+            violations.push(violation);
+        }
+    }
+    transactions.close().await;
+    if !violations.is_empty() {
+        return Err(format!("Verification failed:\n{}", violations.join("\n")).into());
+    }
+    Ok(checked)
+}
+
+// Processes CSVs of transactions from `inputs` and writes the clients with locked accounts to `output` as CSV.
+// Returns the number of clients written.
+async fn write_locked_clients<I, R, W>(inputs: I, output: W, config: &Config, shutdown: &Shutdown) -> Result<usize, Box<dyn Error>>
+where
+    I: IntoIterator<Item = R>,
+    R: Read,
+    W: Write,
+{
+    let transactions = load_transactions(inputs, config, shutdown, &mut RunSummary::default()).await?;
+    let mut writer = csv::Writer::from_writer(output);
+    writer.write_record(["client"])?;
+    let mut written = 0;
+    {
+        let clients = transactions.get_locked_clients().await;
+        pin_mut!(clients);
+        while let Some(client) = clients.try_next().await? {
+            writer.write_record([client.client_id.to_string()])?;
+            written += 1;
+        }
     }
     writer.flush()?;
+    transactions.close().await;
+    Ok(written)
+}
 
-    Ok(())
+// Processes CSVs of transactions from `inputs` and writes the number of transactions of each client to `output` as CSV.
+// Returns the number of clients written.
+async fn write_transaction_counts<I, R, W>(inputs: I, output: W, config: &Config, shutdown: &Shutdown) -> Result<usize, Box<dyn Error>>
+where
+    I: IntoIterator<Item = R>,
+    R: Read,
+    W: Write,
+{
+    let transactions = load_transactions(inputs, config, shutdown, &mut RunSummary::default()).await?;
+    let mut writer = csv::Writer::from_writer(output);
+    writer.write_record(["client", "transactions"])?;
+    let mut written = 0;
+    {
+        let counts = transactions.get_transaction_counts().await;
+        pin_mut!(counts);
+        while let Some(count) = counts.try_next().await? {
+            writer.write_record([count.client_id.to_string(), count.count.to_string()])?;
+            written += 1;
+        }
+    }
+    writer.flush()?;
+    transactions.close().await;
+    Ok(written)
+}
+
+// Processes CSVs of transactions from `inputs` and writes to `output` as CSV the accounts that differ from `previous`,
+// the accounts written by a previous run, with their balances before and after.
+// Accounts that are only in `previous` are written after the others.
+// Returns the number of accounts written.
+async fn write_diff<I, R, W>(inputs: I, previous: &[OutputAccount], output: W, config: &Config, shutdown: &Shutdown) -> Result<usize, Box<dyn Error>>
+where
+    I: IntoIterator<Item = R>,
+    R: Read,
+    W: Write,
+{
+    let transactions = load_transactions(inputs, config, shutdown, &mut RunSummary::default()).await?;
+    let mut remaining: HashMap<(u32, Option<String>), &OutputAccount> = previous.iter()
+        .map(|account| ((account.client, account.currency.clone()), account))
+        .collect();
+    let currency_column = transactions.has_currencies().await? || previous.iter().any(|account| account.currency.is_some());
+    let mut writer = DiffWriter::new(output, config.bool_format).with_currency_column(currency_column);
+    let mut written = 0;
+    {
+        let accounts = computed_accounts(&transactions, None, false, config).await;
+        pin_mut!(accounts);
+        while let Some(account) = accounts.try_next().await? {
+            let account = output_amounts(account, config)?;
+            let before = remaining.remove(&(account.client, account.currency.clone()));
+            if before.is_some_and(|before| !account.changed_from(before)) {
+                continue;
+            }
+            writer.write_change(before, Some(&account))?;
+            written += 1;
+        }
+    }
+    for account in previous {
+        if remaining.remove(&(account.client, account.currency.clone())).is_some() {
+            writer.write_change(Some(account), None)?;
+            written += 1;
+        }
+    }
+    writer.finish()?;
+    transactions.close().await;
+    Ok(written)
+}
+
+// Processes CSVs of transactions from `inputs` and explains what transaction `tx` of `client` did to its account.
+async fn explain_transaction<I, R>(inputs: I, client: u32, tx: u32, config: &Config, shutdown: &Shutdown) -> Result<Explanation, Box<dyn Error>>
+where
+    I: IntoIterator<Item = R>,
+    R: Read,
+{
+    let transactions = load_transactions(inputs, config, shutdown, &mut RunSummary::default()).await?;
+    let explanation = transactions.explain(client, tx).await;
+    transactions.close().await;
+    explanation?.ok_or_else(|| format!("Transaction {} not found for client {}", tx, client).into())
+}
+
+// Writes the account of `client` to `output` in the configured format, from the transactions
+// already stored in `config.db_url`, replaying only that client's transactions with the same options as loading them.
+// A client without any transactions has an empty, unlocked account.
+// Fails if the database or its schema doesn't exist, instead of creating an empty one.
+async fn query_account<W: Write>(client: u32, output: W, config: &Config) -> Result<OutputAccount, Box<dyn Error>> {
+    let transactions = transactions_builder(config)?.existing(true).build().await?;
+    let account = transactions.get_account_for_client(client).await;
+    transactions.close().await;
+    let account = output_amounts(account?, config)?;
+    let mut writer = AccountWriter::new(output, &config.format, config.bool_format);
+    writer.write_account(&account)?;
+    writer.finish()?;
+    Ok(account)
+}
+
+// The crate version, and the git commit it was built from if known.
+fn version() -> String {
+    match option_env!("PLEDGER_GIT_HASH") {
+        Some(hash) => format!("pledger {} ({})", env!("CARGO_PKG_VERSION"), hash),
+        None => format!("pledger {}", env!("CARGO_PKG_VERSION")),
+    }
+}
+
+async fn run(config: &Config, shutdown: &Shutdown) -> Result<(), Box<dyn Error>> {
+    match &config.command {
+        Command::Version => {
+            println!("{}", version());
+            Ok(())
+        },
+        Command::EmitSchema => {
+            println!("{}", serde_json::to_string_pretty(&output_account_schema())?);
+            Ok(())
+        },
+        Command::Process => {
+            let summary = match (&config.output_db, &config.output) {
+                (Some(_), Some(_)) => return Err("Either --output or --output-db can be given, not both.".into()),
+                (Some(db_url), None) => {
+                    process_transactions_to_table(input::open_inputs(config)?, db_url, config, shutdown).await?
+                },
+                (None, Some(path)) => {
+                    let mut output = AtomicFile::create(path)?;
+                    let summary = process_transactions(input::open_inputs(config)?, &mut output, config, shutdown).await?;
+                    output.commit()?;
+                    summary
+                },
+                (None, None) => {
+                    process_transactions(
+                        input::open_inputs(config)?, 
+                        io::stdout(), 
+                        config,
+                        shutdown
+                    ).await?
+                },
+            };
+            info!("{}", summary);
+            Ok(())
+        },
+        Command::Explain { client, tx } => {
+            let explanation = explain_transaction(input::open_inputs(config)?, *client, *tx, config, shutdown).await?;
+            print!("{}", explanation);
+            Ok(())
+        },
+        Command::Query { client } => {
+            query_account(*client, io::stdout(), config).await?;
+            Ok(())
+        },
+        Command::LockedClients => {
+            let written = match &config.output {
+                Some(path) => {
+                    let mut output = AtomicFile::create(path)?;
+                    let written = write_locked_clients(input::open_inputs(config)?, &mut output, config, shutdown).await?;
+                    output.commit()?;
+                    written
+                },
+                None => write_locked_clients(input::open_inputs(config)?, io::stdout(), config, shutdown).await?,
+            };
+            info!("Wrote {} locked clients", written);
+            Ok(())
+        },
+        Command::TransactionCounts => {
+            let written = match &config.output {
+                Some(path) => {
+                    let mut output = AtomicFile::create(path)?;
+                    let written = write_transaction_counts(input::open_inputs(config)?, &mut output, config, shutdown).await?;
+                    output.commit()?;
+                    written
+                },
+                None => write_transaction_counts(input::open_inputs(config)?, io::stdout(), config, shutdown).await?,
+            };
+            info!("Wrote the transaction counts of {} clients", written);
+            Ok(())
+        },
+        Command::Diff { previous } => {
+            let previous = input::read_accounts(previous)?;
+            let written = match &config.output {
+                Some(path) => {
+                    let mut output = AtomicFile::create(path)?;
+                    let written = write_diff(input::open_inputs(config)?, &previous, &mut output, config, shutdown).await?;
+                    output.commit()?;
+                    written
+                },
+                None => write_diff(input::open_inputs(config)?, &previous, io::stdout(), config, shutdown).await?,
+            };
+            info!("Wrote {} changed accounts", written);
+            Ok(())
+        },
+        Command::Verify => {
+            let checked = verify_transactions(input::open_inputs(config)?, config, shutdown).await?;
+            println!("Verified {} accounts", checked);
+            Ok(())
+        },
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    process_transactions(
-        get_input().await?, 
-        io::stdout(), 
-        "sqlite://transactions.db"
-    ).await
+    let config = Config::from_args_and_env(std::env::args().skip(1), |name| std::env::var(name).ok())?;
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .parse_default_env()
+        .init();
+    let shutdown = Shutdown::new();
+    shutdown.listen_for_signals()?;
+
+    let result = run(&config, &shutdown).await;
+    if let Err(error) = &result {
+        if error.is::<Interrupted>() {
+            eprintln!("{}", error);
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    }
+    result
 }
 
 
@@ -57,6 +697,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
 mod tests {
     use super::*;
 
+    use pledger::output::{ColumnScales, OutputFormat, RoundMode};
+
+    fn test_config() -> Config {
+        Config {
+            db_url: "sqlite::memory:".into(),
+            ..Config::default()
+        }
+    }
+
     #[tokio::test]
     async fn test_process_transactions() {
         let input = "
@@ -65,10 +714,1049 @@ mod tests {
             withdrawal, 7,      2,  5.0";
         let expected_output = "client,available,held,total,locked\n7,5.0000,0,5.0000,false\n";
         let mut output = Vec::new();
-        process_transactions(input.as_bytes(), &mut output, "sqlite::memory:").await.unwrap();
+        process_transactions([input.as_bytes()], &mut output, &test_config(), &Shutdown::new()).await.unwrap();
         
         let actual = String::from_utf8(output).unwrap();
         println!("{}", actual);
         assert_eq!(actual, expected_output)
     }
+
+    #[tokio::test]
+    async fn test_process_empty_input() {
+        for input in ["", "type,client,tx,amount\n"] {
+            let mut output = Vec::new();
+            process_transactions([input.as_bytes()], &mut output, &test_config(), &Shutdown::new()).await.unwrap();
+
+            let actual = String::from_utf8(output).unwrap();
+            assert_eq!(actual, "client,available,held,total,locked\n")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_skip_zero() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            withdrawal, 7,      2,  10.0
+            deposit,    8,      3,  5.0
+            deposit,    9,      4,  5.0
+            withdrawal, 9,      5,  5.0
+            dispute,    9,      4,
+            chargeback, 9,      4,";
+        let config = Config {
+            skip_zero: true,
+            ..test_config()
+        };
+        let mut output = Vec::new();
+        process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+
+        let actual = String::from_utf8(output).unwrap();
+        let mut rows: Vec<&str> = actual.lines().skip(1).collect();
+        rows.sort_unstable();
+        assert_eq!(rows, vec!["8,5.0000,0,5.0000,false", "9,0,0,0,true"])
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_skip() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    8,      2,  20.0
+            deposit,    7,      3,  5.0
+            withdrawal, 8,      4,  5.0";
+        let config = Config {
+            skip: 2,
+            ..test_config()
+        };
+        let mut output = Vec::new();
+        process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+
+        let actual = String::from_utf8(output).unwrap();
+        let mut rows: Vec<&str> = actual.lines().skip(1).collect();
+        rows.sort_unstable();
+        assert_eq!(rows, vec!["7,5.0000,0,5.0000,false", "8,0,0,0,false"])
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_lossy() {
+        let input = b"type,client,tx,amount,comment\ndeposit,7,1,10.0,bad\xff\n";
+        let config = Config {
+            lossy: true,
+            ..test_config()
+        };
+        let mut output = Vec::new();
+        process_transactions([&input[..]], &mut output, &config, &Shutdown::new()).await.unwrap();
+
+        let actual = String::from_utf8(output).unwrap();
+        assert_eq!(actual, "client,available,held,total,locked\n7,10.0000,0,10.0000,false\n");
+
+        let config = Config { max_errors: Some(0), ..test_config() };
+        let mut output = Vec::new();
+        assert!(process_transactions([&input[..]], &mut output, &config, &Shutdown::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_max_transactions() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    7,      2,  20.0
+            deposit,    7,      3,  5.0";
+
+        for max_transactions in [3, 4] {
+            let config = Config {
+                max_transactions: Some(max_transactions),
+                ..test_config()
+            };
+            let mut output = Vec::new();
+            process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n7,35.0000,0,35.0000,false\n");
+        }
+
+        let config = Config {
+            max_transactions: Some(2),
+            ..test_config()
+        };
+        let mut output = Vec::new();
+        let error = process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap_err();
+        assert_eq!(error.to_string(), "The input has more than the maximum of 2 transactions");
+        assert!(output.is_empty());
+
+        let config = Config {
+            max_transactions: Some(2),
+            limit_policy: LimitPolicy::Truncate,
+            ..test_config()
+        };
+        let mut output = Vec::new();
+        process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n7,30.0000,0,30.0000,false\n");
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_max_errors() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    7,      two,1.0
+            deposit,    7,      3,
+            deposit,    7,      4
+            deposit,    7,      5,  2.0";
+
+        // Without a maximum, every invalid record is skipped.
+        let mut output = Vec::new();
+        let summary = process_transactions([input.as_bytes()], &mut output, &test_config(), &Shutdown::new()).await.unwrap();
+        assert_eq!((summary.processed, summary.skipped), (5, 3));
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n7,12.0000,0,12.0000,false\n");
+
+        let config = Config { max_errors: Some(0), ..test_config() };
+        let error = process_transactions([input.as_bytes()], Vec::new(), &config, &Shutdown::new()).await.unwrap_err();
+        assert!(error.to_string().contains("CSV deserialize error"), "{}", error);
+
+        let config = Config { max_errors: Some(3), ..test_config() };
+        let mut output = Vec::new();
+        let summary = process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+        assert_eq!((summary.processed, summary.skipped), (5, 3));
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n7,12.0000,0,12.0000,false\n");
+
+        let config = Config { max_errors: Some(2), ..test_config() };
+        let mut output = Vec::new();
+        let error = process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Stopped after 3 invalid input records, more than the maximum of 2. The last was: Line 6 has 3 fields, but the header has 4",
+        );
+        assert!(output.is_empty());
+
+        // A malformed header isn't a record error, so it always fails.
+        let config = Config { max_errors: Some(10), ..test_config() };
+        let input = "typ,client,tx,amount\ndeposit,7,1,1.0\n";
+        assert!(process_transactions([input.as_bytes()], Vec::new(), &config, &Shutdown::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_group_by_locked() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    3,      1,  10.0
+            deposit,    1,      2,  20.0
+            deposit,    4,      3,  5.0
+            deposit,    2,      4,  7.0
+            dispute,    3,      1,
+            chargeback, 3,      1,
+            dispute,    1,      2,
+            chargeback, 1,      2,
+            dispute,    4,      3,";
+        let config = Config { group_by_locked: true, ..test_config() };
+        let mut output = Vec::new();
+        let summary = process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+
+        assert_eq!(summary.locked_accounts, 2);
+        let expected = "\
+client,available,held,total,locked
+2,7.0000,0,7.0000,false
+4,0,5.0000,5.0000,false
+1,0,0,0,true
+3,0,0,0,true
+";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_split_output() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    7,      2,  5.0
+            dispute,    7,      2,";
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            split_output: Some(dir.path().into()),
+            ..test_config()
+        };
+        let mut output = Vec::new();
+        process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n7,10.0000,5.0000,15.0000,false\n");
+        let available = std::fs::read_to_string(dir.path().join("available.csv")).unwrap();
+        let held = std::fs::read_to_string(dir.path().join("held.csv")).unwrap();
+        assert_eq!(available, "client,available\n7,10.0000\n");
+        assert_eq!(held, "client,held\n7,5.0000\n");
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_group_by_client() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    8,      2,  5.0
+            withdrawal, 7,      3,  2.5
+            deposit,    8,      4,
+            deposit,    7,      5,  1.0";
+        let dir = tempfile::tempdir().unwrap();
+        let db_url = format!("sqlite://{}", dir.path().join("transactions.db").display());
+        let config = Config {
+            db_url: db_url.clone(),
+            group_by_client: true,
+            ..test_config()
+        };
+        let input = format!("{}\n            deposit,    9,      6,  2.0", input);
+        let rejects = dir.path().join("rejects.csv");
+        let skipping = Config { rejects: Some(rejects.clone()), db_url: db_url.clone(), group_by_client: true, ..test_config() };
+        let mut output = Vec::new();
+        let summary = process_transactions([input.as_bytes()], &mut output, &skipping, &Shutdown::new()).await.unwrap();
+        assert_eq!((summary.processed, summary.skipped), (6, 2));
+
+        // None of client 8's transactions were stored, but the clients before and after it were committed.
+        let mut lines: Vec<String> = String::from_utf8(output).unwrap().lines().map(String::from).collect();
+        lines[1..].sort();
+        assert_eq!(lines, vec!["client,available,held,total,locked", "7,8.5000,0,8.5000,false", "9,2.0000,0,2.0000,false"]);
+        let transactions = Transactions::open(&db_url).await.unwrap();
+        assert!(transactions.get_account_for_client(8).await.unwrap().is_zero());
+        transactions.close().await;
+        let reason = "rolled back with the client's invalid record: Deposit must have an amount (transaction 4)";
+        assert_eq!(
+            std::fs::read_to_string(&rejects).unwrap(),
+            format!("type,client,tx,amount,reason\ndeposit,8,2,5,{}\ndeposit,8,4,,{}\n", reason, reason),
+        );
+
+        // With a limit on invalid records, the run fails instead, after committing the clients before it.
+        let strict = Config { max_errors: Some(0), ..config };
+        let error = process_transactions([input.as_bytes()], &mut Vec::new(), &strict, &Shutdown::new()).await.unwrap_err();
+        assert!(error.to_string().ends_with("Deposit must have an amount (transaction 4)"));
+        let transactions = Transactions::open(&db_url).await.unwrap();
+        assert_eq!(
+            transactions.get_account_for_client(7).await.unwrap(),
+            OutputAccount::new(7, "8.5", "0", "8.5", false).unwrap(),
+        );
+        assert!(transactions.get_account_for_client(9).await.unwrap().is_zero());
+        transactions.close().await;
+        let config = strict;
+
+        let input = input.replace("deposit,    8,      4,", "deposit,    8,      4,  1.0");
+        let mut output = Vec::new();
+        let summary = process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+        assert_eq!(summary.processed, 6);
+        let mut lines: Vec<String> = String::from_utf8(output).unwrap().lines().map(String::from).collect();
+        lines[1..].sort();
+        assert_eq!(lines, vec![
+            "client,available,held,total,locked",
+            "7,8.5000,0,8.5000,false",
+            "8,6.0000,0,6.0000,false",
+            "9,2.0000,0,2.0000,false",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_rejects() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    7,      1,  20.0
+            dispute,    7,      9,
+            dispute,    9,      8,
+            withdrawal, 7,      2,  2.5
+            resolve,    7,      2,
+            deposit,    7,      3,  1000000000000000";
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rejects.csv");
+        let config = Config {
+            rejects: Some(path.clone()),
+            ..test_config()
+        };
+        let mut output = Vec::new();
+        let summary = process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+
+        assert_eq!(summary.skipped, 5);
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n7,7.5000,0,7.5000,false\n");
+        // An invalid record is written with its error as the reason.
+        let expected = "\
+type,client,tx,amount,reason
+deposit,7,1,20,duplicate transaction
+dispute,7,9,,unknown transaction
+dispute,9,8,,unknown client
+resolve,7,2,,not disputed
+deposit,7,3,1000000000000000,Amount of transaction 3 is larger than the maximum of 922337203685477.5807
+";
+        assert_eq!(std::fs::read_to_string(path).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_allow_clients() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    8,      2,  20.0
+            deposit,    9,      3,  30.0
+            withdrawal, 8,      4,  5.0";
+        let dir = tempfile::tempdir().unwrap();
+        let allow = dir.path().join("allow.txt");
+        std::fs::write(&allow, "7\n8\n").unwrap();
+        let deny = dir.path().join("deny.txt");
+        std::fs::write(&deny, "7\n").unwrap();
+
+        let config = Config { allow_clients: Some(allow.clone()), ..test_config() };
+        let mut output = Vec::new();
+        let summary = process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+        assert_eq!(summary.skipped, 1);
+        let output = String::from_utf8(output).unwrap();
+        let mut lines: Vec<&str> = output.lines().skip(1).collect();
+        lines.sort_unstable();
+        assert_eq!(lines, vec!["7,10.0000,0,10.0000,false", "8,15.0000,0,15.0000,false"]);
+
+        let config = Config { allow_clients: Some(allow), deny_clients: Some(deny), ..test_config() };
+        let mut output = Vec::new();
+        process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n8,15.0000,0,15.0000,false\n");
+
+        let config = Config { allow_clients: Some(dir.path().join("missing.txt")), ..test_config() };
+        assert!(process_transactions([input.as_bytes()], Vec::new(), &config, &Shutdown::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_transactions() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    8,      2,  20.0
+            withdrawal, 7,      3,  5.0
+            dispute,    8,      2,
+            dispute,    7,      3,
+            chargeback, 7,      3,";
+        let checked = verify_transactions([input.as_bytes()], &test_config(), &Shutdown::new()).await.unwrap();
+        assert_eq!(checked, 2)
+    }
+
+    // A writer that triggers the shutdown once something has been written to it.
+    struct InterruptingWriter {
+        output: Vec<u8>,
+        shutdown: Shutdown,
+    }
+
+    impl Write for InterruptingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.shutdown.trigger();
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_interrupted_while_writing() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    8,      2,  3.5
+            deposit,    9,      3,  1.0";
+        let config = Config {
+            format: OutputFormat::Ndjson,
+            ..test_config()
+        };
+        let shutdown = Shutdown::new();
+        let mut output = InterruptingWriter { output: Vec::new(), shutdown: shutdown.clone() };
+        let result = process_transactions([input.as_bytes()], &mut output, &config, &shutdown).await;
+
+        assert!(result.unwrap_err().is::<Interrupted>());
+        let actual = String::from_utf8(output.output).unwrap();
+        assert_eq!(actual.lines().count(), 1);
+        assert!(actual.ends_with('\n'));
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_interrupted_while_loading() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0";
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+        let mut output = Vec::new();
+        let result = process_transactions([input.as_bytes()], &mut output, &test_config(), &shutdown).await;
+
+        assert!(result.unwrap_err().is::<Interrupted>());
+        assert!(output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_ndjson() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    8,      2,  3.5
+            withdrawal, 7,      3,  5.0";
+        let config = Config {
+            format: OutputFormat::Ndjson,
+            ..test_config()
+        };
+        let mut output = Vec::new();
+        process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+
+        let actual = String::from_utf8(output).unwrap();
+        assert!(actual.ends_with('\n'));
+        let mut clients: Vec<u64> = actual.lines()
+            .map(|line| {
+                let value: serde_json::Value = serde_json::from_str(line).unwrap();
+                assert!(value.is_object());
+                value["client"].as_u64().unwrap()
+            })
+            .collect();
+        clients.sort_unstable();
+        assert_eq!(clients, vec![7, 8])
+    }
+
+    #[tokio::test]
+    async fn test_process_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("2024-01-02.csv"), "type,client,tx,amount\nwithdrawal,7,2,4.0\n").unwrap();
+        std::fs::write(dir.path().join("2024-01-01.csv"), "type,client,tx,amount\ndeposit,7,1,10.0\n").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not a csv").unwrap();
+        let config = Config {
+            dir: Some(dir.path().into()),
+            ..test_config()
+        };
+
+        let inputs = input::open_inputs(&config).unwrap();
+        assert_eq!(inputs.len(), 2);
+        let mut output = Vec::new();
+        process_transactions(inputs, &mut output, &config, &Shutdown::new()).await.unwrap();
+
+        // The withdrawal would be ignored if it was processed before the deposit.
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n7,6.0000,0,6.0000,false\n");
+    }
+
+    #[tokio::test]
+    async fn test_run_output_replaced_on_success_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("transactions.csv");
+        let output = dir.path().join("accounts.csv");
+        std::fs::write(&output, "previous\n").unwrap();
+        let config = Config {
+            input: Some(input.to_str().unwrap().into()),
+            output: Some(output.clone()),
+            max_errors: Some(0),
+            ..test_config()
+        };
+
+        std::fs::write(&input, "type,client,tx,amount\ndeposit,7,1,10.0\ndeposit,7,two,1.0\n").unwrap();
+        assert!(run(&config, &Shutdown::new()).await.is_err());
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "previous\n");
+
+        std::fs::write(&input, "type,client,tx,amount\ndeposit,7,1,10.0\n").unwrap();
+        run(&config, &Shutdown::new()).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "client,available,held,total,locked\n7,10.0000,0,10.0000,false\n");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_to_table() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    8,      2,  3.5
+            dispute,    8,      2,";
+        let dir = tempfile::tempdir().unwrap();
+        let db_url = format!("sqlite://{}", dir.path().join("accounts.db").display());
+        process_transactions_to_table([input.as_bytes()], &db_url, &test_config(), &Shutdown::new()).await.unwrap();
+
+        let db_pool = sqlx::SqlitePool::connect(&db_url).await.unwrap();
+        let rows: Vec<(u32, String, String, String, bool)> = sqlx::query_as("SELECT client, available, held, total, locked FROM accounts ORDER BY client")
+            .fetch_all(&db_pool).await.unwrap();
+        assert_eq!(rows, vec![
+            (7, "10.0000".into(), "0".into(), "10.0000".into(), false),
+            (8, "0".into(), "3.5000".into(), "3.5000".into(), false),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_to_table_again() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    8,      2,  3.5
+            dispute,    8,      2,";
+        let dir = tempfile::tempdir().unwrap();
+        let db_url = format!("sqlite://{}", dir.path().join("accounts.db").display());
+        let rows = || async {
+            let db_pool = sqlx::SqlitePool::connect(&db_url).await.unwrap();
+            let rows: Vec<(u32, String, String, String, bool)> = sqlx::query_as("SELECT client, available, held, total, locked FROM accounts ORDER BY client")
+                .fetch_all(&db_pool).await.unwrap();
+            db_pool.close().await;
+            rows
+        };
+        process_transactions_to_table([input.as_bytes()], &db_url, &test_config(), &Shutdown::new()).await.unwrap();
+        let first = rows().await;
+
+        // Reprocessing the same input, e.g. when a scheduled job is retried, leaves the same rows.
+        process_transactions_to_table([input.as_bytes()], &db_url, &test_config(), &Shutdown::new()).await.unwrap();
+        assert_eq!(rows().await, first);
+
+        // Reprocessing a book with more transactions updates each client's row in place.
+        let input = format!("{}
+            chargeback, 8,      2,
+            deposit,    7,      3,  1.0", input);
+        process_transactions_to_table([input.as_bytes()], &db_url, &test_config(), &Shutdown::new()).await.unwrap();
+        assert_eq!(rows().await, vec![
+            (7, "11.0000".into(), "0".into(), "11.0000".into(), false),
+            (8, "0".into(), "0".into(), "0".into(), true),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_max_clients() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    1,      1,  1.0
+            deposit,    2,      2,  2.0
+            deposit,    3,      3,  3.0
+            deposit,    4,      4,  4.0
+            deposit,    5,      5,  5.0";
+        let config = Config {
+            max_clients: Some(2),
+            ..test_config()
+        };
+        let mut output = Vec::new();
+        process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+
+        let actual = String::from_utf8(output).unwrap();
+        assert_eq!(actual.lines().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_summary() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    7,      1,  10.0
+            deposit,    8,      2,  3.5
+            dispute,    8,      2,
+            chargeback, 8,      2,
+            resolve,    9,      3,";
+        let mut output = Vec::new();
+        let summary = process_transactions([input.as_bytes()], &mut output, &test_config(), &Shutdown::new()).await.unwrap();
+
+        assert_eq!(summary.processed, 6);
+        assert_eq!(summary.skipped, 2);
+        assert_eq!(summary.clients, 2);
+        assert_eq!(summary.locked_accounts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_max_held_ratio() {
+        // 30 of the 130 in the book is held.
+        let input = "
+            type,       client, tx, amount
+            deposit,    1,      1,  100.0
+            deposit,    2,      2,  30.0
+            dispute,    2,      2,";
+        for (max_held_ratio, above) in [("0.25", false), ("0.2", true)] {
+            let config = Config {
+                max_held_ratio: Some(max_held_ratio.parse().unwrap()),
+                ..test_config()
+            };
+            let mut output = Vec::new();
+            process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+            assert_eq!(String::from_utf8(output).unwrap().lines().count(), 3);
+
+            let config = Config { fail_on_held_ratio: true, ..config };
+            let result = process_transactions([input.as_bytes()], &mut Vec::new(), &config, &Shutdown::new()).await;
+            assert_eq!(result.is_err(), above, "{}", max_held_ratio);
+            if above {
+                assert_eq!(
+                    result.unwrap_err().to_string(),
+                    "The held funds of 30.0000 are more than 0.2 of the total funds of 130.0000",
+                );
+            }
+        }
+        let mut book = BookTotals::default();
+        book.add(&OutputAccount::new(1, "0", "1", "1", false).unwrap());
+        let config = Config { max_held_ratio: Some("0.5".parse().unwrap()), ..test_config() };
+        assert!(check_held_ratio(&book, &config).unwrap());
+        assert!(!check_held_ratio(&book, &test_config()).unwrap());
+
+        // Each currency is checked on its own, so a small currency isn't hidden by a large one.
+        let input = "
+            type,       client, tx, amount, currency
+            deposit,    1,      1,  1000.0, EUR
+            deposit,    2,      2,  30.0,   USD
+            deposit,    2,      3,  10.0,   USD
+            dispute,    2,      2,  ,";
+        let config = Config { max_held_ratio: Some("0.5".parse().unwrap()), fail_on_held_ratio: true, ..test_config() };
+        let error = process_transactions([input.as_bytes()], &mut Vec::new(), &config, &Shutdown::new()).await.unwrap_err();
+        assert_eq!(error.to_string(), "The held funds of 30.0000 are more than 0.5 of the total funds of 40.0000 in USD");
+    }
+
+    #[test]
+    fn test_warn_if_inconsistent() {
+        assert!(!warn_if_inconsistent(&OutputAccount::new(7, "1.5", "2", "3.5", false).unwrap()));
+        assert!(warn_if_inconsistent(&OutputAccount::new(7, "1.5", "2", "3.4999", false).unwrap()));
+    }
+
+    #[test]
+    fn test_warn_if_held_above() {
+        let threshold = "1000".parse().unwrap();
+        assert!(warn_if_held_above(&OutputAccount::new(7, "0", "1000.0001", "1000.0001", false).unwrap(), Some(&threshold)));
+        assert!(!warn_if_held_above(&OutputAccount::new(7, "5000", "1000", "6000", false).unwrap(), Some(&threshold)));
+        assert!(!warn_if_held_above(&OutputAccount::new(7, "0", "1000000", "1000000", false).unwrap(), None));
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_lenient_amounts() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  +50.00
+            deposit,    7,      2,  (20.00)
+            withdrawal, 7,      3,  (5.00)
+            withdrawal, 7,      4,  +5.00";
+        let config = Config {
+            lenient_amounts: true,
+            ..test_config()
+        };
+        let mut output = Vec::new();
+        let summary = process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n7,45.0000,0,45.0000,false\n");
+        assert_eq!(summary.skipped, 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_version() {
+        assert!(version().starts_with(&format!("pledger {}", env!("CARGO_PKG_VERSION"))));
+        // No input is needed, so the missing input file doesn't fail the run.
+        let config = Config {
+            command: Command::Version,
+            input: None,
+            ..test_config()
+        };
+        run(&config, &Shutdown::new()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_explain_transaction() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    7,      2,  5.0
+            dispute,    7,      2,";
+        let explanation = explain_transaction([input.as_bytes()], 7, 2, &test_config(), &Shutdown::new()).await.unwrap();
+        assert!(explanation.disputed);
+        assert_eq!(explanation.after.held.to_string(), "5.0000");
+
+        let error = explain_transaction([input.as_bytes()], 7, 3, &test_config(), &Shutdown::new()).await.unwrap_err();
+        assert_eq!(error.to_string(), "Transaction 3 not found for client 7");
+    }
+
+    #[tokio::test]
+    async fn test_query_account() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            db_url: format!("sqlite://{}", dir.path().join("transactions.db").display()),
+            ..Config::default()
+        };
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            deposit,    8,      2,  5.0
+            dispute,    8,      2,";
+        process_transactions([input.as_bytes()], &mut Vec::new(), &config, &Shutdown::new()).await.unwrap();
+
+        let mut output = Vec::new();
+        let account = query_account(8, &mut output, &config).await.unwrap();
+        assert_eq!(account, OutputAccount::new(8, "0", "5", "5", false).unwrap());
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n8,0,5.0000,5.0000,false\n");
+
+        // A client without any transactions.
+        let mut output = Vec::new();
+        let account = query_account(42, &mut output, &config).await.unwrap();
+        assert!(account.is_zero());
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n42,0,0,0,false\n");
+
+        // The stored transactions are kept, so they can be queried again.
+        let config = Config { format: OutputFormat::Ndjson, ..config };
+        let mut output = Vec::new();
+        query_account(7, &mut output, &config).await.unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"client\":7,\"available\":\"10.0000\",\"held\":\"0\",\"total\":\"10.0000\",\"locked\":false}\n",
+        );
+
+        // The replay options are the same as when loading.
+        let ignore_disputes = Config { ignore_disputes: true, db_url: config.db_url.clone(), ..Config::default() };
+        let account = query_account(8, &mut Vec::new(), &ignore_disputes).await.unwrap();
+        assert_eq!(account, OutputAccount::new(8, "5", "0", "5", false).unwrap());
+
+        // A mistyped database isn't created.
+        let missing = Config {
+            db_url: format!("sqlite://{}", dir.path().join("missing.db").display()),
+            ..config
+        };
+        assert!(query_account(7, &mut Vec::new(), &missing).await.is_err());
+        assert!(!dir.path().join("missing.db").exists());
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_recompute() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            db_url: format!("sqlite://{}", dir.path().join("transactions.db").display()),
+            ..Config::default()
+        };
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0";
+        process_transactions([input.as_bytes()], &mut Vec::new(), &config, &Shutdown::new()).await.unwrap();
+
+        let config = Config {
+            recompute: true,
+            ..config
+        };
+        let inputs = input::open_inputs(&config).unwrap();
+        assert!(inputs.is_empty());
+        let mut output = Vec::new();
+        process_transactions(inputs, &mut output, &config, &Shutdown::new()).await.unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n7,10.0000,0,10.0000,false\n");
+
+        // A stored balance that doesn't match the stored transactions is corrected by replaying them.
+        let pool = sqlx::SqlitePool::connect(&config.db_url).await.unwrap();
+        sqlx::query("UPDATE account_balances SET available = '99.0000', total = '99.0000' WHERE client_id = 7")
+            .execute(&pool).await.unwrap();
+        pool.close().await;
+        let appended = Config { append: true, db_url: config.db_url.clone(), ..Config::default() };
+        let mut output = Vec::new();
+        process_transactions(Vec::<&[u8]>::new(), &mut output, &appended, &Shutdown::new()).await.unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n7,99.0000,0,99.0000,false\n");
+        let mut output = Vec::new();
+        process_transactions(Vec::<&[u8]>::new(), &mut output, &config, &Shutdown::new()).await.unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n7,10.0000,0,10.0000,false\n");
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_integer_amounts() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0
+            withdrawal, 7,      2,  5.0
+            deposit,    8,      3,  0.0001";
+        let config = Config {
+            integer_amounts: true,
+            ..test_config()
+        };
+        let mut output = Vec::new();
+        process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+
+        let actual = String::from_utf8(output).unwrap();
+        let mut rows: Vec<&str> = actual.lines().skip(1).collect();
+        rows.sort_unstable();
+        assert_eq!(rows, vec!["7,50000,0,50000,false", "8,1,0,1,false"]);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_timestamp_output() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    7,      1,  10.0";
+        let config = Config {
+            timestamp_output: true,
+            ..test_config()
+        };
+        let mut output = Vec::new();
+        process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+
+        let actual = String::from_utf8(output).unwrap();
+        let mut lines = actual.lines();
+        assert_eq!(lines.next(), Some("client,available,held,total,locked,computed_at"));
+        let row = lines.next().unwrap();
+        let (values, computed_at) = row.rsplit_once(',').unwrap();
+        assert_eq!(values, "7,10.0000,0,10.0000,false");
+        assert!(humantime::parse_rfc3339(computed_at).is_ok(), "{}", computed_at);
+    }
+
+    // Accepts writes until a line has been written, then fails as if the reader had exited.
+    struct ClosingWriter {
+        written: Vec<u8>,
+    }
+
+    impl Write for ClosingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written.contains(&b'\n') {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_output_closed() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    1,      1,  1.0
+            deposit,    2,      2,  2.0
+            deposit,    3,      3,  3.0";
+        let config = Config {
+            format: OutputFormat::Ndjson,
+            ..test_config()
+        };
+        let mut output = ClosingWriter { written: Vec::new() };
+        let summary = process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+
+        assert_eq!(summary.processed, 3);
+        assert_eq!(summary.clients, 1);
+        assert_eq!(String::from_utf8(output.written).unwrap().lines().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_large_client_id() {
+        let input = "
+            type,       client,     tx, amount
+            deposit,    4000000000, 1,  10.0
+            withdrawal, 4000000000, 2,  2.5";
+        let mut output = Vec::new();
+        process_transactions([input.as_bytes()], &mut output, &test_config(), &Shutdown::new()).await.unwrap();
+
+        let actual = String::from_utf8(output).unwrap();
+        assert_eq!(actual.lines().nth(1), Some("4000000000,7.5000,0,7.5000,false"));
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_mixed_currencies() {
+        let input = "
+            type,       client, tx, amount, currency
+            deposit,    1,      1,  5,
+            deposit,    2,      2,  3,      USD";
+        let mut output = Vec::new();
+        process_transactions([input.as_bytes()], &mut output, &test_config(), &Shutdown::new()).await.unwrap();
+
+        // Every row has a currency cell, which is empty for the default currency.
+        let actual = String::from_utf8(output).unwrap();
+        let mut lines: Vec<&str> = actual.lines().collect();
+        lines[1..].sort();
+        assert_eq!(lines, vec![
+            "client,currency,available,held,total,locked",
+            "1,,5.0000,0,5.0000,false",
+            "2,USD,3.0000,0,3.0000,false",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_write_buffer_bytes() {
+        let input: String = std::iter::once("type,client,tx,amount\n".to_string())
+            .chain((1..=200).map(|i| format!("deposit,{},{},{}.5\n", i, i, i)))
+            .collect();
+        let mut outputs = Vec::new();
+        for write_buffer_bytes in [1, 1 << 20] {
+            let config = Config {
+                write_buffer_bytes,
+                ..test_config()
+            };
+            let mut output = Vec::new();
+            process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+            let actual = String::from_utf8(output).unwrap();
+            let mut rows: Vec<&str> = actual.lines().skip(1).collect();
+            rows.sort_unstable();
+            assert_eq!(rows.len(), 200);
+            assert!(rows.contains(&"200,200.5000,0,200.5000,false"));
+            outputs.push(actual);
+        }
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_merged_by_timestamp() {
+        // The dispute is only applied if the deposit from the other input is loaded first.
+        let first = "
+            type,       client, tx, amount, timestamp
+            deposit,    1,      1,  10.0,   100
+            dispute,    2,      2,  ,       400";
+        let second = "
+            type,       client, tx, amount, timestamp
+            deposit,    2,      2,  5.0,    200
+            withdrawal, 1,      3,  1.0,    300";
+        let config = Config {
+            merge_by_timestamp: true,
+            ..test_config()
+        };
+        let mut output = Vec::new();
+        let summary = process_transactions([first.as_bytes(), second.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+        assert_eq!(summary.skipped, 0);
+
+        let actual = String::from_utf8(output).unwrap();
+        let mut rows: Vec<&str> = actual.lines().skip(1).collect();
+        rows.sort_unstable();
+        assert_eq!(rows, vec!["1,9.0000,0,9.0000,false", "2,0,5.0000,5.0000,false"]);
+
+        let mut output = Vec::new();
+        let summary = process_transactions([first.as_bytes(), second.as_bytes()], &mut output, &test_config(), &Shutdown::new()).await.unwrap();
+        assert_eq!(summary.skipped, 1);
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_zero_scale() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    1,      1,  5
+            withdrawal, 1,      2,  2
+            deposit,    2,      3,  2.5";
+        for (round_mode, fractional) in [(RoundMode::HalfUp, "3"), (RoundMode::Truncate, "2")] {
+            let config = Config {
+                round_digits: 0,
+                round_mode,
+                ..test_config()
+            };
+            let mut output = Vec::new();
+            process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+
+            let actual = String::from_utf8(output).unwrap();
+            let mut rows: Vec<&str> = actual.lines().skip(1).collect();
+            rows.sort_unstable();
+            let expected = format!("2,{},0,{},false", fractional, fractional);
+            assert_eq!(rows, vec!["1,3,0,3,false", expected.as_str()]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_precision() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    1,      1,  5
+            deposit,    2,      2,  10
+            withdrawal, 2,      3,  2.125
+            deposit,    2,      4,  1
+            dispute,    2,      4,";
+        for (precision, expected) in [
+            (2, "1,5.00,0.00,5.00,false\n2,7.88,1.00,8.88,false\n"),
+            (8, "1,5.00000000,0.00000000,5.00000000,false\n2,7.87500000,1.00000000,8.87500000,false\n"),
+        ] {
+            let config = Config { round_digits: precision, fixed_scale: true, ..test_config() };
+            let mut output = Vec::new();
+            process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+            let actual = String::from_utf8(output).unwrap();
+            assert_eq!(actual, format!("client,available,held,total,locked\n{}", expected), "{}", precision);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_transactions_column_scales() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    1,      1,  10.1234
+            deposit,    1,      2,  2.4567
+            dispute,    1,      2,";
+        let config = Config {
+            column_scales: ColumnScales { available: Some(2), held: Some(4), total: Some(0) },
+            ..test_config()
+        };
+        let mut output = Vec::new();
+        process_transactions([input.as_bytes()], &mut output, &config, &Shutdown::new()).await.unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n1,10.12,2.4567,13,false\n");
+    }
+
+    #[tokio::test]
+    async fn test_write_locked_clients() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    1,      1,  10.0
+            deposit,    2,      2,  5.0
+            dispute,    2,      2,
+            chargeback, 2,      2,
+            dispute,    1,      1,";
+        let mut output = Vec::new();
+        let written = write_locked_clients([input.as_bytes()], &mut output, &test_config(), &Shutdown::new()).await.unwrap();
+        assert_eq!(written, 1);
+        assert_eq!(String::from_utf8(output).unwrap(), "client\n2\n");
+    }
+
+    #[tokio::test]
+    async fn test_write_transaction_counts() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    2,      1,  10.0
+            deposit,    1,      2,  5.0
+            withdrawal, 2,      3,  1.0
+            deposit,    2,      4,  2.0
+            dispute,    2,      4,
+            deposit,    2,      4,  2.0";
+        let mut output = Vec::new();
+        let written = write_transaction_counts([input.as_bytes()], &mut output, &test_config(), &Shutdown::new()).await.unwrap();
+        assert_eq!(written, 2);
+        assert_eq!(String::from_utf8(output).unwrap(), "client,transactions\n1,1\n2,3\n");
+    }
+
+    #[tokio::test]
+    async fn test_write_diff() {
+        let previous = input::parse_accounts("\
+client,available,held,total,locked
+1,10.0000,0,10.0000,false
+2,5.0000,0,5.0000,false
+3,1.0000,0,1.0000,false
+4,2.0000,0,2.0000,false
+".as_bytes()).unwrap();
+        let input = "
+            type,       client, tx, amount
+            deposit,    1,      1,  10.0
+            deposit,    2,      2,  5.0
+            withdrawal, 2,      3,  1.5
+            deposit,    3,      4,  1.0
+            dispute,    3,      4,
+            chargeback, 3,      4,
+            deposit,    5,      5,  3.0
+            deposit,    4,      6,  2.0
+            dispute,    4,      6,";
+        let mut output = Vec::new();
+        let written = write_diff([input.as_bytes()], &previous, &mut output, &test_config(), &Shutdown::new()).await.unwrap();
+        assert_eq!(written, 4);
+        // Client 1 is unchanged, so it isn't written.
+        let expected = "\
+client,available_before,available_after,held_before,held_after,total_before,total_after,locked_before,locked_after
+2,5.0000,3.5000,0,0,5.0000,3.5000,false,false
+3,1.0000,0,0,0,1.0000,0,false,true
+4,2.0000,0,0,2.0000,2.0000,2.0000,false,false
+5,,3.0000,,0,,3.0000,,false
+";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
 }
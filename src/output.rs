@@ -1,34 +1,1316 @@
 use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use bigdecimal::BigDecimal;
-use serde::Serialize;
+use bigdecimal::{BigDecimal, Signed, Zero};
+use serde::{de, Deserialize, Deserializer, Serialize};
 
+use crate::input::InputTransaction;
+use crate::transactions_store::{from_stored_amount, MutableTransaction};
 
-#[derive(Debug, Serialize, Hash, PartialEq, Eq)]
+// How amounts are rounded to the configured number of decimal places.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum RoundMode {
+    // Round halves away from zero.
+    #[default]
+    HalfUp,
+    // Round halves to the nearest even digit (banker's rounding).
+    HalfEven,
+    // Round toward zero, so that an amount is never overstated.
+    Truncate,
+}
+
+impl RoundMode {
+    pub fn round(&self, amount: &BigDecimal, round_digits: i64) -> BigDecimal {
+        let (_, scale) = amount.as_bigint_and_exponent();
+        if scale <= round_digits {
+            return amount.clone();
+        }
+        let truncated = amount.with_scale(round_digits);
+        match self {
+            RoundMode::HalfUp => amount.round(round_digits),
+            RoundMode::Truncate => truncated,
+            RoundMode::HalfEven => {
+                let unit = BigDecimal::new(1.into(), round_digits);
+                let remainder = (amount - &truncated).abs() * BigDecimal::from(2);
+                let units = &truncated / &unit;
+                let is_odd = !(units % BigDecimal::from(2)).is_zero();
+                if remainder > unit || (remainder == unit && is_odd) {
+                    if amount.is_negative() { truncated - unit } else { truncated + unit }
+                } else {
+                    truncated
+                }
+            },
+        }
+    }
+}
+
+impl FromStr for RoundMode {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half-up" => Ok(RoundMode::HalfUp),
+            "half-even" => Ok(RoundMode::HalfEven),
+            "truncate" => Ok(RoundMode::Truncate),
+            _ => Err(format!("Unknown round mode: {}", s).into()),
+        }
+    }
+}
+
+// The number of decimal places of each amount column, for those that differ from `--round-digits`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ColumnScales {
+    pub available: Option<u32>,
+    pub held: Option<u32>,
+    pub total: Option<u32>,
+}
+
+// Parsed from a list like `available=2,held=4`.
+impl FromStr for ColumnScales {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut scales = ColumnScales::default();
+        for item in s.split(',') {
+            let (column, scale) = item.split_once('=')
+                .ok_or_else(|| format!("Invalid column scale: {}, expected a column=scale pair", item))?;
+            let scale = Some(scale.trim().parse().map_err(|_| format!("Invalid scale of column {}: {}", column, scale))?);
+            match column.trim() {
+                "available" => scales.available = scale,
+                "held" => scales.held = scale,
+                "total" => scales.total = scale,
+                _ => return Err(format!("Unknown amount column: {}", column).into()),
+            }
+        }
+        Ok(scales)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct OutputAccount {
-    pub client: u16,
+    pub client: u32,
+    // Only set for accounts in a currency other than the default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    #[serde(deserialize_with = "deserialize_amount")]
     pub available: BigDecimal,
+    #[serde(deserialize_with = "deserialize_amount")]
     pub held: BigDecimal,
+    #[serde(deserialize_with = "deserialize_amount")]
     pub total: BigDecimal,
     pub locked: bool,
+    // When the account was computed, as an RFC 3339 timestamp. Only set with --timestamp-output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub computed_at: Option<String>,
+}
+
+// Amounts are read back from their decimal strings, as going through a float would change them.
+fn deserialize_amount<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BigDecimal, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    BigDecimal::from_str(&s).map_err(de::Error::custom)
+}
+
+// A JSON Schema of an account in the `json` and `ndjson` outputs, which is each element of the `json` array,
+// and each line of `ndjson`. It must be kept in sync with the fields of `OutputAccount` and how they are serialized.
+// Amounts are decimal strings rather than numbers, so that they are exact.
+pub fn output_account_schema() -> serde_json::Value {
+    let amount = |description: &str| serde_json::json!({
+        "type": "string",
+        "pattern": "^-?[0-9]+(\\.[0-9]+)?$",
+        "description": format!("{}, as a decimal string, or in integer minor units with --integer-amounts.", description),
+    });
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "OutputAccount",
+        "description": "The account of a client in one currency, as written by pledger with --format json or ndjson.",
+        "type": "object",
+        "properties": {
+            "client": {
+                "type": "integer",
+                "minimum": 0,
+                "maximum": u32::MAX,
+                "description": "The client ID.",
+            },
+            "currency": {
+                "type": "string",
+                "description": "The currency of the account, only present for accounts in a currency other than the default.",
+            },
+            "available": amount("The funds available for withdrawal"),
+            "held": amount("The funds held by open disputes"),
+            "total": amount("The available and held funds together"),
+            "locked": {
+                "type": "boolean",
+                "description": "Whether the account is locked by a chargeback.",
+            },
+            "computed_at": {
+                "type": "string",
+                "format": "date-time",
+                "description": "When the account was computed, only present with --timestamp-output.",
+            },
+        },
+        "required": ["client", "available", "held", "total", "locked"],
+        "additionalProperties": false,
+    })
 }
 
 impl OutputAccount {
-    pub fn new(client: u16, available: &str, held: &str, total: &str, locked: bool) -> Result<Self, Box<dyn Error>> {
+    pub fn new(client: u32, available: &str, held: &str, total: &str, locked: bool) -> Result<Self, Box<dyn Error>> {
         Ok(OutputAccount {
             client,
+            currency: None,
             available: BigDecimal::from_str(available)?,
             held: BigDecimal::from_str(held)?,
             total: BigDecimal::from_str(total)?,
             locked,
+            computed_at: None,
+        })
+    }
+
+    pub fn with_currency(mut self, currency: &str) -> Self {
+        self.currency = Some(currency.into());
+        self
+    }
+
+    // Check that the amounts are consistent with each other.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        if self.total != &self.available + &self.held {
+            return Err(format!(
+                "Client {}: total {} is not equal to available {} plus held {}",
+                self.client, self.total, self.available, self.held,
+            ));
+        }
+        if self.held.is_negative() {
+            return Err(format!("Client {}: held amount {} is negative", self.client, self.held));
+        }
+        Ok(())
+    }
+
+    // Whether the balances or the locked status differ from `other`, ignoring the trailing zeros of amounts.
+    pub fn changed_from(&self, other: &OutputAccount) -> bool {
+        self.available != other.available || self.held != other.held || self.total != other.total || self.locked != other.locked
+    }
+
+    // An unlocked account with nothing in it.
+    pub fn is_zero(&self) -> bool {
+        self.available.is_zero() && self.held.is_zero() && self.total.is_zero() && !self.locked
+    }
+
+    pub fn round_amounts(&mut self, round_digits: i64, round_mode: RoundMode) {
+        self.round_columns(&ColumnScales::default(), round_digits, round_mode);
+    }
+
+    // Round each amount to its scale in `scales`, or to `round_digits` if it doesn't have one.
+    pub fn round_columns(&mut self, scales: &ColumnScales, round_digits: i64, round_mode: RoundMode) {
+        let digits = |scale: Option<u32>| scale.map_or(round_digits, i64::from);
+        self.available = round_mode.round(&self.available, digits(scales.available));
+        self.held = round_mode.round(&self.held, digits(scales.held));
+        self.total = round_mode.round(&self.total, digits(scales.total));
+    }
+
+    // Pad each amount with trailing zeros to exactly its scale, e.g. `5` to `5.00` at a scale of 2,
+    // after it has been rounded with `round_columns`.
+    pub fn pad_columns(&mut self, scales: &ColumnScales, round_digits: i64) {
+        let digits = |scale: Option<u32>| scale.map_or(round_digits, i64::from);
+        self.available = self.available.with_scale(digits(scales.available));
+        self.held = self.held.with_scale(digits(scales.held));
+        self.total = self.total.with_scale(digits(scales.total));
+    }
+
+    // The account with its amounts in integer minor units, e.g. ten-thousandths at a scale of 4.
+    // Fails if an amount has more decimal places than the scale, as it can't be converted exactly.
+    pub fn to_minor_units(&self, scale: i64) -> Result<OutputAccount, Box<dyn Error>> {
+        let unit = BigDecimal::new(1.into(), -scale);
+        let convert = |amount: &BigDecimal| {
+            let scaled = amount * &unit;
+            let units = scaled.with_scale(0);
+            if units != scaled {
+                return Err(format!("Amount {} of client {} has more than {} decimal places", amount, self.client, scale));
+            }
+            Ok(units)
+        };
+        Ok(OutputAccount {
+            client: self.client,
+            currency: self.currency.clone(),
+            available: convert(&self.available)?,
+            held: convert(&self.held)?,
+            total: convert(&self.total)?,
+            locked: self.locked,
+            computed_at: self.computed_at.clone(),
+        })
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Json,
+    Ndjson,
+    Table,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(format!("Unknown output format: {}", s).into()),
+        }
+    }
+}
+
+// How the `locked` flag is written in CSV output.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BoolFormat {
+    TrueFalse,
+    OneZero,
+    YesNo,
+    YN,
+}
+
+impl BoolFormat {
+    pub fn render(&self, value: bool) -> &'static str {
+        match (self, value) {
+            (BoolFormat::TrueFalse, true) => "true",
+            (BoolFormat::TrueFalse, false) => "false",
+            (BoolFormat::OneZero, true) => "1",
+            (BoolFormat::OneZero, false) => "0",
+            (BoolFormat::YesNo, true) => "yes",
+            (BoolFormat::YesNo, false) => "no",
+            (BoolFormat::YN, true) => "Y",
+            (BoolFormat::YN, false) => "N",
+        }
+    }
+}
+
+impl FromStr for BoolFormat {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "true-false" => Ok(BoolFormat::TrueFalse),
+            "1-0" => Ok(BoolFormat::OneZero),
+            "yes-no" => Ok(BoolFormat::YesNo),
+            "y-n" => Ok(BoolFormat::YN),
+            _ => Err(format!("Unknown bool format: {}", s).into()),
+        }
+    }
+}
+
+const CSV_HEADER: [&str; 5] = ["client", "available", "held", "total", "locked"];
+
+// The currency cell of an account's CSV row, or `None` if the output has no currency column.
+// Every row of an output must have the same columns, so whether it has a currency column is decided once,
+// by `currency_column` if it is set, or else by whether the first account has a currency.
+// An account in the default currency then has an empty cell.
+fn currency_cell<'a>(currency_column: &mut Option<bool>, account: &'a OutputAccount) -> Result<Option<&'a str>, Box<dyn Error>> {
+    let shown = *currency_column.get_or_insert(account.currency.is_some());
+    match (shown, &account.currency) {
+        (true, currency) => Ok(Some(currency.as_deref().unwrap_or_default())),
+        (false, None) => Ok(None),
+        (false, Some(currency)) => Err(format!(
+            "The account of client {} is in {}, but the output has no currency column", account.client, currency,
+        ).into()),
+    }
+}
+
+// The CSV representation of an account, with `locked` already rendered.
+#[derive(Serialize)]
+struct CsvAccount<'a> {
+    client: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<&'a str>,
+    available: &'a BigDecimal,
+    held: &'a BigDecimal,
+    total: &'a BigDecimal,
+    locked: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    computed_at: &'a Option<String>,
+}
+
+impl<'a> CsvAccount<'a> {
+    fn new(account: &'a OutputAccount, currency: Option<&'a str>, bool_format: BoolFormat) -> Self {
+        CsvAccount {
+            client: account.client,
+            currency,
+            available: &account.available,
+            held: &account.held,
+            total: &account.total,
+            locked: bool_format.render(account.locked),
+            computed_at: &account.computed_at,
+        }
+    }
+}
+
+// Writes accounts one at a time in the chosen format.
+// JSON is written as a single array, NDJSON as one object per line.
+// A table is only written when finished, as its column widths depend on every account.
+pub enum AccountWriter<W: Write> {
+    Csv { writer: Box<csv::Writer<W>>, bool_format: BoolFormat, currency_column: Option<bool>, empty: bool },
+    Json { writer: W, empty: bool },
+    Ndjson(W),
+    Table { writer: W, bool_format: BoolFormat, rows: Vec<Vec<String>> },
+}
+
+impl<W: Write> AccountWriter<W> {
+    pub fn new(output: W, format: &OutputFormat, bool_format: BoolFormat) -> Self {
+        match format {
+            OutputFormat::Csv => AccountWriter::Csv {
+                writer: Box::new(csv::Writer::from_writer(output)),
+                bool_format,
+                currency_column: None,
+                empty: true,
+            },
+            OutputFormat::Json => AccountWriter::Json { writer: output, empty: true },
+            OutputFormat::Ndjson => AccountWriter::Ndjson(output),
+            OutputFormat::Table => AccountWriter::Table { writer: output, bool_format, rows: Vec::new() },
+        }
+    }
+
+    // Whether CSV output has a currency column, e.g. because some of the accounts have a currency,
+    // instead of going by the first account. The other formats show a currency wherever an account has one.
+    pub fn with_currency_column(mut self, shown: bool) -> Self {
+        if let AccountWriter::Csv { currency_column, .. } = &mut self {
+            *currency_column = Some(shown);
+        }
+        self
+    }
+
+    pub fn write_account(&mut self, account: &OutputAccount) -> Result<(), Box<dyn Error>> {
+        match self {
+            AccountWriter::Csv { writer, bool_format, currency_column, empty } => {
+                let currency = currency_cell(currency_column, account)?;
+                writer.serialize(CsvAccount::new(account, currency, *bool_format))?;
+                *empty = false;
+            },
+            AccountWriter::Json { writer, empty } => {
+                writer.write_all(if *empty { b"[" } else { b"," })?;
+                serde_json::to_writer(&mut *writer, account)?;
+                *empty = false;
+            },
+            AccountWriter::Ndjson(writer) => {
+                serde_json::to_writer(&mut *writer, account)?;
+                writer.write_all(b"\n")?;
+                writer.flush()?;
+            },
+            AccountWriter::Table { bool_format, rows, .. } => {
+                rows.push(vec![
+                    account.client.to_string(),
+                    account.currency.clone().unwrap_or_default(),
+                    account.available.to_string(),
+                    account.held.to_string(),
+                    account.total.to_string(),
+                    bool_format.render(account.locked).to_string(),
+                    account.computed_at.clone().unwrap_or_default(),
+                ]);
+            },
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        match self {
+            AccountWriter::Csv { writer, currency_column, empty, .. } => {
+                // The header is normally written with the first account.
+                if *empty {
+                    writer.write_record(with_currency_header(&CSV_HEADER, *currency_column))?;
+                }
+                writer.flush()?;
+            },
+            AccountWriter::Json { writer, empty } => {
+                writer.write_all(if *empty { b"[]\n" } else { b"]\n" })?;
+                writer.flush()?;
+            },
+            AccountWriter::Ndjson(writer) => writer.flush()?,
+            AccountWriter::Table { writer, rows, .. } => {
+                write_table(writer, rows)?;
+                writer.flush()?;
+            },
+        }
+        Ok(())
+    }
+}
+
+// Write `rows` as a table with right-aligned columns, each as wide as its widest value.
+// Each row has a currency after the client, but the column is only included if an account has a currency.
+fn write_table<W: Write>(writer: &mut W, rows: &[Vec<String>]) -> io::Result<()> {
+    // The currency and computed_at columns are only shown if any account has them.
+    let header = ["client", "currency", "available", "held", "total", "locked", "computed_at"];
+    let shown: Vec<bool> = (0..header.len())
+        .map(|i| !(i == 1 || i == 6) || rows.iter().any(|row| !row[i].is_empty()))
+        .collect();
+    let header: Vec<&str> = header.iter().zip(&shown).filter(|(_, shown)| **shown).map(|(name, _)| *name).collect();
+    let rows: Vec<Vec<&str>> = rows.iter()
+        .map(|row| row.iter().zip(&shown).filter(|(_, shown)| **shown).map(|(value, _)| value.as_str()).collect())
+        .collect();
+    let mut widths: Vec<usize> = header.iter().map(|name| name.len()).collect();
+    for row in &rows {
+        for (width, value) in widths.iter_mut().zip(row) {
+            *width = (*width).max(value.len());
+        }
+    }
+    let line = |values: &[&str]| {
+        let cells: Vec<String> = widths.iter().zip(values)
+            .map(|(width, value)| format!("{:>width$}", value, width = width))
+            .collect();
+        cells.join(" | ")
+    };
+    writeln!(writer, "{}", line(&header))?;
+    let separator: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    writeln!(writer, "{}", separator.join("-+-"))?;
+    for row in &rows {
+        writeln!(writer, "{}", line(row))?;
+    }
+    Ok(())
+}
+
+// The header of a CSV without any rows, with a currency column after the client if there is one.
+fn with_currency_header<'a>(header: &[&'a str], currency_column: Option<bool>) -> Vec<&'a str> {
+    let mut header = header.to_vec();
+    if currency_column == Some(true) {
+        header.insert(1, "currency");
+    }
+    header
+}
+
+#[derive(Serialize)]
+struct AvailableRow<'a> {
+    client: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<&'a str>,
+    available: &'a BigDecimal,
+}
+
+#[derive(Serialize)]
+struct HeldRow<'a> {
+    client: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<&'a str>,
+    held: &'a BigDecimal,
+}
+
+// Writes the available and held amounts of each account to two separate CSVs.
+pub struct SplitAccountWriter<W: Write> {
+    available: csv::Writer<W>,
+    held: csv::Writer<W>,
+    currency_column: Option<bool>,
+    empty: bool,
+}
+
+impl SplitAccountWriter<File> {
+    // Create `available.csv` and `held.csv` in `dir`.
+    pub fn create(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::new(
+            File::create(dir.join("available.csv"))?,
+            File::create(dir.join("held.csv"))?,
+        ))
+    }
+}
+
+impl<W: Write> SplitAccountWriter<W> {
+    pub fn new(available: W, held: W) -> Self {
+        SplitAccountWriter {
+            available: csv::Writer::from_writer(available),
+            held: csv::Writer::from_writer(held),
+            currency_column: None,
+            empty: true,
+        }
+    }
+
+    // Whether the CSVs have a currency column, instead of going by the first account, as with `AccountWriter`.
+    pub fn with_currency_column(mut self, shown: bool) -> Self {
+        self.currency_column = Some(shown);
+        self
+    }
+
+    pub fn write_account(&mut self, account: &OutputAccount) -> Result<(), Box<dyn Error>> {
+        let currency = currency_cell(&mut self.currency_column, account)?;
+        self.available.serialize(AvailableRow {
+            client: account.client,
+            currency,
+            available: &account.available,
+        })?;
+        self.held.serialize(HeldRow {
+            client: account.client,
+            currency,
+            held: &account.held,
+        })?;
+        self.empty = false;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.empty {
+            self.available.write_record(with_currency_header(&["client", "available"], self.currency_column))?;
+            self.held.write_record(with_currency_header(&["client", "held"], self.currency_column))?;
+        }
+        self.available.flush()?;
+        self.held.flush()?;
+        Ok(())
+    }
+}
+
+const DUMP_HEADER: [&str; 10] = [
+    "ordinal", "client", "tx", "amount", "currency", "disputed", "charged_back", "reversal_reason", "reversed_at", "run_id",
+];
+
+#[derive(Serialize)]
+struct DumpRow<'a> {
+    ordinal: i64,
+    client: u32,
+    tx: u32,
+    amount: BigDecimal,
+    currency: &'a str,
+    disputed: bool,
+    charged_back: bool,
+    reversal_reason: &'a Option<String>,
+    reversed_at: &'a Option<String>,
+    run_id: &'a Option<String>,
+}
+
+// A stored amount as written in the dump, with zero written as `0`, as it is in the rejects export.
+fn dump_amount(amount: i64) -> BigDecimal {
+    if amount == 0 { BigDecimal::zero() } else { from_stored_amount(amount) }
+}
+
+// Writes the stored transactions as CSV, for auditing.
+// The reversal columns are empty for transactions that haven't been charged back,
+// and `run_id` for those stored without one.
+pub struct TransactionDumpWriter<W: Write> {
+    writer: csv::Writer<W>,
+    empty: bool,
+}
+
+impl<W: Write> TransactionDumpWriter<W> {
+    pub fn new(output: W) -> Self {
+        TransactionDumpWriter {
+            writer: csv::Writer::from_writer(output),
+            empty: true,
+        }
+    }
+
+    pub fn write_transaction(&mut self, transaction: &MutableTransaction) -> Result<(), Box<dyn Error>> {
+        self.writer.serialize(DumpRow {
+            ordinal: transaction.ordinal,
+            client: transaction.client_id,
+            tx: transaction.transaction_id,
+            amount: dump_amount(transaction.amount),
+            currency: &transaction.currency,
+            disputed: transaction.disputed,
+            charged_back: transaction.charged_back,
+            reversal_reason: &transaction.reversal_reason,
+            reversed_at: &transaction.reversed_at,
+            run_id: &transaction.run_id,
+        })?;
+        self.empty = false;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.empty {
+            self.writer.write_record(DUMP_HEADER)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+const REJECTS_HEADER: [&str; 5] = ["type", "client", "tx", "amount", "reason"];
+
+#[derive(Serialize)]
+struct RejectRow<'a> {
+    #[serde(rename = "type")]
+    transaction_type: String,
+    client: u32,
+    tx: u32,
+    amount: Option<BigDecimal>,
+    reason: &'a str,
+}
+
+// Writes the input transactions that were skipped, with the reason for each, to return to whoever sent them.
+pub struct RejectsWriter<W: Write> {
+    writer: csv::Writer<W>,
+    empty: bool,
+}
+
+impl<W: Write> RejectsWriter<W> {
+    pub fn new(output: W) -> Self {
+        RejectsWriter {
+            writer: csv::Writer::from_writer(output),
+            empty: true,
+        }
+    }
+
+    pub fn write_reject(&mut self, transaction: &InputTransaction, reason: &str) -> Result<(), Box<dyn Error>> {
+        self.writer.serialize(RejectRow {
+            transaction_type: transaction.transaction_type.to_string(),
+            client: transaction.client,
+            tx: transaction.tx,
+            // Without the trailing zeros that parsing can add.
+            amount: transaction.amount.as_ref().map(BigDecimal::normalized),
+            reason,
+        })?;
+        self.empty = false;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.empty {
+            self.writer.write_record(REJECTS_HEADER)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+const DIFF_HEADER: [&str; 9] = [
+    "client", "available_before", "available_after", "held_before", "held_after",
+    "total_before", "total_after", "locked_before", "locked_after",
+];
+
+#[derive(Serialize)]
+struct DiffRow<'a> {
+    client: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<&'a str>,
+    available_before: Option<&'a BigDecimal>,
+    available_after: Option<&'a BigDecimal>,
+    held_before: Option<&'a BigDecimal>,
+    held_after: Option<&'a BigDecimal>,
+    total_before: Option<&'a BigDecimal>,
+    total_after: Option<&'a BigDecimal>,
+    locked_before: Option<&'static str>,
+    locked_after: Option<&'static str>,
+}
+
+// Writes the accounts that changed between two runs, with their balances before and after, for `--diff`.
+// An account that is missing from one of the runs has empty cells for that run.
+pub struct DiffWriter<W: Write> {
+    writer: csv::Writer<W>,
+    bool_format: BoolFormat,
+    currency_column: Option<bool>,
+    empty: bool,
+}
+
+impl<W: Write> DiffWriter<W> {
+    pub fn new(output: W, bool_format: BoolFormat) -> Self {
+        DiffWriter {
+            writer: csv::Writer::from_writer(output),
+            bool_format,
+            currency_column: None,
+            empty: true,
+        }
+    }
+
+    // Whether the diff has a currency column, instead of going by the first change, as with `AccountWriter`.
+    pub fn with_currency_column(mut self, shown: bool) -> Self {
+        self.currency_column = Some(shown);
+        self
+    }
+
+    pub fn write_change(&mut self, before: Option<&OutputAccount>, after: Option<&OutputAccount>) -> Result<(), Box<dyn Error>> {
+        let account = after.or(before).ok_or("A change needs an account before or after it")?;
+        let currency = currency_cell(&mut self.currency_column, account)?;
+        self.writer.serialize(DiffRow {
+            client: account.client,
+            currency,
+            available_before: before.map(|a| &a.available),
+            available_after: after.map(|a| &a.available),
+            held_before: before.map(|a| &a.held),
+            held_after: after.map(|a| &a.held),
+            total_before: before.map(|a| &a.total),
+            total_after: after.map(|a| &a.total),
+            locked_before: before.map(|a| self.bool_format.render(a.locked)),
+            locked_after: after.map(|a| self.bool_format.render(a.locked)),
+        })?;
+        self.empty = false;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.empty {
+            self.writer.write_record(with_currency_header(&DIFF_HEADER, self.currency_column))?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Whether an error writing output is because the reader of the output went away,
+// e.g. `head` exiting after reading the lines it needs.
+pub fn is_broken_pipe(error: &(dyn Error + 'static)) -> bool {
+    let io_error_kind = if let Some(error) = error.downcast_ref::<io::Error>() {
+        Some(error.kind())
+    } else if let Some(error) = error.downcast_ref::<csv::Error>() {
+        match error.kind() {
+            csv::ErrorKind::Io(error) => Some(error.kind()),
+            _ => None,
+        }
+    } else if let Some(error) = error.downcast_ref::<serde_json::Error>() {
+        error.io_error_kind()
+    } else {
+        None
+    };
+    io_error_kind == Some(io::ErrorKind::BrokenPipe)
+}
+
+// The clients with held funds, and the total held across all of them.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HeldReport {
+    pub entries: Vec<(u32, BigDecimal)>,
+    pub total: BigDecimal,
+}
+
+impl HeldReport {
+    // Write the report as CSV, with a final `total` row.
+    pub fn write_csv<W: Write>(&self, output: W) -> Result<(), Box<dyn Error>> {
+        let mut writer = csv::Writer::from_writer(output);
+        writer.write_record(["client", "held"])?;
+        for (client, held) in &self.entries {
+            writer.write_record([client.to_string(), held.to_string()])?;
+        }
+        writer.write_record(["total".to_string(), self.total.to_string()])?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+// Writes to a temporary file next to `path`, which only replaces `path` when committed,
+// so readers never see a partially written file.
+// The temporary file is removed if it's dropped without being committed.
+pub struct AtomicFile {
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: Option<BufWriter<File>>,
+}
+
+impl AtomicFile {
+    pub fn create(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file_name = path.file_name()
+            .ok_or_else(|| format!("Invalid output path: {}", path.display()))?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_name);
+        let file = File::create(&temp_path)?;
+        Ok(AtomicFile {
+            path: path.into(),
+            temp_path,
+            file: Some(BufWriter::new(file)),
         })
     }
 
-    pub fn round_amounts(&mut self, round_digits: i64) {
-        self.available = self.available.round(round_digits);
-        self.held = self.held.round(round_digits);
-        self.total = self.total.round(round_digits);
+    // Flush everything written so far to disk and move it into place.
+    pub fn commit(mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(file) = self.file.take() {
+            let file = file.into_inner().map_err(|error| error.into_error())?;
+            file.sync_all()?;
+            std::fs::rename(&self.temp_path, &self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.file {
+            Some(file) => file.write(buf),
+            None => Ok(0),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
     }
 }
 
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_is_broken_pipe() {
+        let broken_pipe = || io::Error::from(io::ErrorKind::BrokenPipe);
+        let errors: Vec<Box<dyn Error>> = vec![
+            broken_pipe().into(),
+            csv::Error::from(broken_pipe()).into(),
+            serde_json::Error::io(broken_pipe()).into(),
+        ];
+        for error in errors {
+            assert!(is_broken_pipe(error.as_ref()), "{:?}", error);
+        }
+        let other: Box<dyn Error> = io::Error::from(io::ErrorKind::PermissionDenied).into();
+        assert!(!is_broken_pipe(other.as_ref()));
+        assert!(!is_broken_pipe(Box::<dyn Error>::from("Broken pipe").as_ref()));
+    }
+
+    #[test]
+    fn test_held_report_csv() {
+        let report = HeldReport {
+            entries: vec![(1, BigDecimal::from(5)), (3, BigDecimal::from_str("2.5").unwrap())],
+            total: BigDecimal::from_str("7.5").unwrap(),
+        };
+        let mut output = Vec::new();
+        report.write_csv(&mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "client,held\n1,5\n3,2.5\ntotal,7.5\n");
+    }
+
+    #[test]
+    fn test_is_zero() {
+        assert!(OutputAccount::new(1, "0", "0", "0.0000", false).unwrap().is_zero());
+        assert!(!OutputAccount::new(1, "0", "0", "0", true).unwrap().is_zero());
+        assert!(!OutputAccount::new(1, "0", "1", "1", false).unwrap().is_zero());
+    }
+
+    #[test]
+    fn test_round_amounts_truncate() {
+        let mut account = OutputAccount::new(1, "1.99999", "0", "1.99999", false).unwrap();
+        account.round_amounts(4, RoundMode::Truncate);
+        assert_eq!(account.available.to_string(), "1.9999");
+        account = OutputAccount::new(1, "1.99999", "0", "1.99999", false).unwrap();
+        account.round_amounts(4, RoundMode::HalfUp);
+        assert_eq!(account.available.to_string(), "2.0000");
+    }
+
+    #[test]
+    fn test_round_columns() {
+        let mut account = OutputAccount::new(1, "1.23456", "2.34567", "3.58023", false).unwrap();
+        let scales = ColumnScales { available: Some(2), held: None, total: Some(0) };
+        account.round_columns(&scales, 3, RoundMode::HalfUp);
+        assert_eq!(
+            (account.available.to_string(), account.held.to_string(), account.total.to_string()),
+            ("1.23".into(), "2.346".into(), "4".into()),
+        );
+    }
+
+    #[test]
+    fn test_pad_columns() {
+        let mut account = OutputAccount::new(1, "5", "0", "5.1234", false).unwrap();
+        account.round_columns(&ColumnScales::default(), 2, RoundMode::HalfUp);
+        account.pad_columns(&ColumnScales { held: Some(3), ..Default::default() }, 2);
+        assert_eq!(
+            (account.available.to_string(), account.held.to_string(), account.total.to_string()),
+            ("5.00".into(), "0.000".into(), "5.12".into()),
+        );
+    }
+
+    #[test]
+    fn test_parse_column_scales() {
+        assert_eq!(
+            "available=2, held=4".parse::<ColumnScales>().unwrap(),
+            ColumnScales { available: Some(2), held: Some(4), total: None },
+        );
+        assert_eq!("total=0".parse::<ColumnScales>().unwrap(), ColumnScales { total: Some(0), ..Default::default() });
+        assert!("locked=2".parse::<ColumnScales>().is_err());
+        assert!("available".parse::<ColumnScales>().is_err());
+        assert!("held=-1".parse::<ColumnScales>().is_err());
+    }
+
+    #[test]
+    fn test_round_amounts_zero_scale() {
+        let mut account = OutputAccount::new(1, "5.0000", "0", "5.0000", false).unwrap();
+        account.round_amounts(0, RoundMode::HalfUp);
+        assert_eq!((account.available.to_string(), account.total.to_string()), ("5".into(), "5".into()));
+
+        let round = |mode: RoundMode, amount: &str| mode.round(&BigDecimal::from_str(amount).unwrap(), 0).to_string();
+        assert_eq!(round(RoundMode::HalfUp, "2.5000"), "3");
+        assert_eq!(round(RoundMode::HalfEven, "2.5000"), "2");
+        assert_eq!(round(RoundMode::HalfEven, "3.5000"), "4");
+        assert_eq!(round(RoundMode::Truncate, "2.9999"), "2");
+        assert_eq!(round(RoundMode::HalfUp, "-2.5000"), "-3");
+    }
+
+    #[test]
+    fn test_round_modes() {
+        let round = |mode: RoundMode, amount: &str, round_digits| {
+            mode.round(&BigDecimal::from_str(amount).unwrap(), round_digits).to_string()
+        };
+        assert_eq!(round(RoundMode::HalfUp, "2.125", 2), "2.13");
+        assert_eq!(round(RoundMode::HalfUp, "-2.125", 2), "-2.13");
+        assert_eq!(round(RoundMode::HalfEven, "2.125", 2), "2.12");
+        assert_eq!(round(RoundMode::HalfEven, "2.135", 2), "2.14");
+        assert_eq!(round(RoundMode::HalfEven, "-2.135", 2), "-2.14");
+        assert_eq!(round(RoundMode::HalfEven, "2.1251", 2), "2.13");
+        assert_eq!(round(RoundMode::Truncate, "2.129", 2), "2.12");
+        assert_eq!(round(RoundMode::Truncate, "-2.129", 2), "-2.12");
+        assert_eq!(round(RoundMode::Truncate, "2.1", 2), "2.1");
+        assert!(RoundMode::from_str("up").is_err());
+    }
+
+    #[test]
+    fn test_to_minor_units() {
+        let account = OutputAccount::new(1, "5.0000", "-0.0001", "4.9999", true).unwrap();
+        let units = account.to_minor_units(4).unwrap();
+        assert_eq!(
+            (units.available.to_string(), units.held.to_string(), units.total.to_string()),
+            ("50000".into(), "-1".into(), "49999".into()),
+        );
+        assert!(units.locked);
+        assert_eq!(OutputAccount::new(1, "0", "0", "0", false).unwrap().to_minor_units(4).unwrap().total.to_string(), "0");
+        assert_eq!(account.to_minor_units(2).unwrap_err().to_string(), "Amount -0.0001 of client 1 has more than 2 decimal places");
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        assert!(OutputAccount::new(1, "1.5", "2", "3.5", false).unwrap().check_invariants().is_ok());
+        assert!(OutputAccount::new(1, "1.5", "2", "3", false).unwrap().check_invariants().is_err());
+        assert!(OutputAccount::new(1, "3", "-1", "2", false).unwrap().check_invariants().is_err());
+    }
+
+    fn write_accounts(format: OutputFormat, accounts: &[OutputAccount]) -> String {
+        write_accounts_with(format, BoolFormat::TrueFalse, accounts)
+    }
+
+    fn write_accounts_with(format: OutputFormat, bool_format: BoolFormat, accounts: &[OutputAccount]) -> String {
+        let mut output = Vec::new();
+        let mut writer = AccountWriter::new(&mut output, &format, bool_format);
+        for account in accounts {
+            writer.write_account(account).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_write_table() {
+        let accounts = vec![
+            OutputAccount::new(1, "1.5", "0", "1.5", false).unwrap(),
+            OutputAccount::new(10423, "-250.1234", "300", "49.8766", true).unwrap(),
+        ];
+        let expected = "\
+client | available | held |   total | locked
+-------+-----------+------+---------+-------
+     1 |       1.5 |    0 |     1.5 |  false
+ 10423 | -250.1234 |  300 | 49.8766 |   true
+";
+        assert_eq!(write_accounts(OutputFormat::Table, &accounts), expected);
+    }
+
+    #[test]
+    fn test_write_table_currency() {
+        let accounts = vec![
+            OutputAccount::new(1, "1", "0", "1", false).unwrap().with_currency("USD"),
+            OutputAccount::new(2, "2", "0", "2", false).unwrap(),
+        ];
+        let expected = "\
+client | currency | available | held | total | locked
+-------+----------+-----------+------+-------+-------
+     1 |      USD |         1 |    0 |     1 |  false
+     2 |          |         2 |    0 |     2 |  false
+";
+        assert_eq!(write_accounts(OutputFormat::Table, &accounts), expected);
+    }
+
+    #[test]
+    fn test_write_csv_currency() {
+        let accounts = vec![
+            OutputAccount::new(1, "1", "0", "1", false).unwrap().with_currency("USD"),
+            OutputAccount::new(1, "2", "0", "2", false).unwrap().with_currency("EUR"),
+        ];
+        let expected = "client,currency,available,held,total,locked\n1,USD,1,0,1,false\n1,EUR,2,0,2,false\n";
+        assert_eq!(write_accounts(OutputFormat::Csv, &accounts), expected)
+    }
+
+    #[test]
+    fn test_write_csv_mixed_currencies() {
+        let accounts = vec![
+            OutputAccount::new(1, "5", "0", "5", false).unwrap(),
+            OutputAccount::new(2, "3", "0", "3", false).unwrap().with_currency("USD"),
+        ];
+        let mut output = Vec::new();
+        let mut writer = AccountWriter::new(&mut output, &OutputFormat::Csv, BoolFormat::TrueFalse).with_currency_column(true);
+        for account in &accounts {
+            writer.write_account(account).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+        // The account in the default currency has an empty currency cell.
+        let expected = "client,currency,available,held,total,locked\n1,,5,0,5,false\n2,USD,3,0,3,false\n";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+
+        // Without being told, the first account decides, and a later account in a currency can't be written.
+        let mut output = Vec::new();
+        let mut writer = AccountWriter::new(&mut output, &OutputFormat::Csv, BoolFormat::TrueFalse);
+        writer.write_account(&accounts[0]).unwrap();
+        let error = writer.write_account(&accounts[1]).unwrap_err();
+        assert_eq!(error.to_string(), "The account of client 2 is in USD, but the output has no currency column");
+
+        // A currency column is in the header even without any accounts.
+        let mut output = Vec::new();
+        AccountWriter::new(&mut output, &OutputFormat::Csv, BoolFormat::TrueFalse).with_currency_column(true).finish().unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "client,currency,available,held,total,locked\n");
+    }
+
+    #[test]
+    fn test_write_csv_empty() {
+        assert_eq!(write_accounts(OutputFormat::Csv, &[]), "client,available,held,total,locked\n")
+    }
+
+    #[test]
+    fn test_write_json() {
+        let accounts = vec![
+            OutputAccount::new(1, "1.5000", "0", "1.5000", false).unwrap(),
+            OutputAccount::new(2, "0", "2", "2", true).unwrap(),
+        ];
+        let expected = concat!(
+            r#"[{"client":1,"available":"1.5000","held":"0","total":"1.5000","locked":false},"#,
+            r#"{"client":2,"available":"0","held":"2","total":"2","locked":true}]"#,
+            "\n",
+        );
+        assert_eq!(write_accounts(OutputFormat::Json, &accounts), expected)
+    }
+
+    #[test]
+    fn test_output_account_schema() {
+        let schema = output_account_schema();
+        let properties = schema["properties"].as_object().unwrap();
+        let required: Vec<&str> = schema["required"].as_array().unwrap().iter().map(|field| field.as_str().unwrap()).collect();
+        assert_eq!(required, vec!["client", "available", "held", "total", "locked"]);
+        let types: Vec<(&str, &str)> = required.iter().map(|field| (*field, properties[*field]["type"].as_str().unwrap())).collect();
+        assert_eq!(types, vec![("client", "integer"), ("available", "string"), ("held", "string"), ("total", "string"), ("locked", "boolean")]);
+
+        // Every serialized field is in the schema, with a value of its type.
+        let mut account = OutputAccount::new(1, "-1.5000", "2", "0.5000", true).unwrap();
+        account.currency = Some("EUR".into());
+        account.computed_at = Some("2024-01-01T00:00:00.000Z".into());
+        let serialized = serde_json::to_value(&account).unwrap();
+        let serialized = serialized.as_object().unwrap();
+        assert_eq!(serialized.keys().collect::<HashSet<_>>(), properties.keys().collect::<HashSet<_>>());
+        for (field, value) in serialized {
+            let matches = match properties[field]["type"].as_str().unwrap() {
+                "integer" => value.is_u64(),
+                "string" => value.is_string(),
+                "boolean" => value.is_boolean(),
+                other => panic!("Unexpected type {}", other),
+            };
+            assert!(matches, "{} is {}", field, value);
+        }
+        let pattern = properties["available"]["pattern"].as_str().unwrap();
+        assert_eq!(pattern, "^-?[0-9]+(\\.[0-9]+)?$");
+    }
+
+    #[test]
+    fn test_write_json_empty() {
+        assert_eq!(write_accounts(OutputFormat::Json, &[]), "[]\n")
+    }
+
+    #[test]
+    fn test_write_ndjson() {
+        let accounts = vec![
+            OutputAccount::new(1, "1.5000", "0", "1.5000", false).unwrap(),
+            OutputAccount::new(2, "0", "2", "2", true).unwrap(),
+        ];
+        let expected = concat!(
+            r#"{"client":1,"available":"1.5000","held":"0","total":"1.5000","locked":false}"#, "\n",
+            r#"{"client":2,"available":"0","held":"2","total":"2","locked":true}"#, "\n",
+        );
+        assert_eq!(write_accounts(OutputFormat::Ndjson, &accounts), expected)
+    }
+
+    #[test]
+    fn test_write_csv_bool_formats() {
+        let accounts = vec![
+            OutputAccount::new(1, "1", "0", "1", false).unwrap(),
+            OutputAccount::new(2, "0", "0", "0", true).unwrap(),
+        ];
+        let cases = [
+            (BoolFormat::TrueFalse, "false", "true"),
+            (BoolFormat::OneZero, "0", "1"),
+            (BoolFormat::YesNo, "no", "yes"),
+            (BoolFormat::YN, "N", "Y"),
+        ];
+        for (bool_format, unlocked, locked) in cases {
+            let expected = format!(
+                "client,available,held,total,locked\n1,1,0,1,{}\n2,0,0,0,{}\n",
+                unlocked, locked,
+            );
+            assert_eq!(write_accounts_with(OutputFormat::Csv, bool_format, &accounts), expected);
+        }
+    }
+
+    #[test]
+    fn test_write_split() {
+        let accounts = vec![
+            OutputAccount::new(1, "1.5", "2", "3.5", false).unwrap(),
+            OutputAccount::new(2, "0", "4", "4", true).unwrap(),
+        ];
+        let mut available = Vec::new();
+        let mut held = Vec::new();
+        let mut writer = SplitAccountWriter::new(&mut available, &mut held);
+        for account in &accounts {
+            writer.write_account(account).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+
+        assert_eq!(String::from_utf8(available).unwrap(), "client,available\n1,1.5\n2,0\n");
+        assert_eq!(String::from_utf8(held).unwrap(), "client,held\n1,2\n2,4\n");
+
+        let mut available = Vec::new();
+        let mut held = Vec::new();
+        let mut writer = SplitAccountWriter::new(&mut available, &mut held).with_currency_column(true);
+        writer.write_account(&accounts[0]).unwrap();
+        writer.write_account(&accounts[1].clone().with_currency("USD")).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        assert_eq!(String::from_utf8(available).unwrap(), "client,currency,available\n1,,1.5\n2,USD,0\n");
+        assert_eq!(String::from_utf8(held).unwrap(), "client,currency,held\n1,,2\n2,USD,4\n");
+    }
+
+    #[test]
+    fn test_atomic_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts.csv");
+        std::fs::write(&path, "previous\n").unwrap();
+
+        // A write that fails part of the way through leaves the previous file in place.
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"client,available").unwrap();
+        drop(file);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous\n");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"client,available\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous\n");
+        file.commit().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "client,available\n");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_write_rejects() {
+        use crate::input::TransactionType;
+
+        let mut output = Vec::new();
+        let mut writer = RejectsWriter::new(&mut output);
+        let deposit = InputTransaction::new(TransactionType::Deposit, 7, 1, Some("2.5")).unwrap();
+        writer.write_reject(&deposit, "duplicate transaction").unwrap();
+        let dispute = InputTransaction::new(TransactionType::Dispute, 7, 9, None).unwrap();
+        writer.write_reject(&dispute, "unknown transaction").unwrap();
+        let zero_deposit = InputTransaction::new(TransactionType::Deposit, 7, 2, Some("0.000")).unwrap();
+        writer.write_reject(&zero_deposit, "duplicate transaction").unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        // A missing amount is an empty cell, and a zero amount is written as `0`.
+        let expected = "\
+type,client,tx,amount,reason
+deposit,7,1,2.5,duplicate transaction
+dispute,7,9,,unknown transaction
+deposit,7,2,0,duplicate transaction
+";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+
+        let mut output = Vec::new();
+        RejectsWriter::new(&mut output).finish().unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), format!("{}\n", REJECTS_HEADER.join(",")));
+    }
+
+    #[test]
+    fn test_write_diff() {
+        let before = OutputAccount::new(1, "10", "0", "10", false).unwrap();
+        let after = OutputAccount::new(1, "7.5", "2.5", "10", true).unwrap();
+        let removed = OutputAccount::new(3, "1", "0", "1", false).unwrap();
+        let mut output = Vec::new();
+        let mut writer = DiffWriter::new(&mut output, BoolFormat::TrueFalse);
+        writer.write_change(Some(&before), Some(&after)).unwrap();
+        writer.write_change(None, Some(&OutputAccount::new(2, "10", "0", "10", false).unwrap())).unwrap();
+        writer.write_change(Some(&removed), None).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let expected = "\
+client,available_before,available_after,held_before,held_after,total_before,total_after,locked_before,locked_after
+1,10,7.5,0,2.5,10,10,false,true
+2,,10,,0,,10,,false
+3,1,,0,,1,,false,
+";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+
+        let mut output = Vec::new();
+        DiffWriter::new(&mut output, BoolFormat::TrueFalse).finish().unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), format!("{}\n", DIFF_HEADER.join(",")));
+
+        let mut output = Vec::new();
+        let mut writer = DiffWriter::new(&mut output, BoolFormat::TrueFalse).with_currency_column(true);
+        writer.write_change(Some(&before), Some(&after)).unwrap();
+        writer.write_change(Some(&removed.clone().with_currency("USD")), None).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let expected = "\
+client,currency,available_before,available_after,held_before,held_after,total_before,total_after,locked_before,locked_after
+1,,10,7.5,0,2.5,10,10,false,true
+3,USD,1,,0,,1,,false,
+";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_changed_from() {
+        let account = OutputAccount::new(1, "7.5", "2.5", "10", false).unwrap();
+        assert!(!account.changed_from(&OutputAccount::new(1, "7.5000", "2.50", "10.0000", false).unwrap()));
+        assert!(account.changed_from(&OutputAccount::new(1, "7.5", "2.5", "10", true).unwrap()));
+        assert!(account.changed_from(&OutputAccount::new(1, "5", "5", "10", false).unwrap()));
+    }
+
+    #[test]
+    fn test_write_dump() {
+        let transaction = |ordinal, charged_back: bool| MutableTransaction {
+            ordinal,
+            client_id: 7,
+            transaction_id: ordinal as u32 + 10,
+            amount: 25000,
+            currency: "".into(),
+            timestamp: None,
+            disputed: false,
+            charged_back,
+            reversal_reason: if charged_back { Some("chargeback".into()) } else { None },
+            reversed_at: if charged_back { Some("2024-01-01T00:00:00.000Z".into()) } else { None },
+            run_id: if ordinal > 1 { Some("nightly".into()) } else { None },
+        };
+        let mut output = Vec::new();
+        let mut writer = TransactionDumpWriter::new(&mut output);
+        writer.write_transaction(&transaction(1, false)).unwrap();
+        writer.write_transaction(&transaction(2, true)).unwrap();
+        writer.write_transaction(&MutableTransaction { amount: 0, ..transaction(3, false) }).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        // Every stored transaction has an amount, so a zero amount is written as `0`, never as an empty cell.
+        let expected = "\
+ordinal,client,tx,amount,currency,disputed,charged_back,reversal_reason,reversed_at,run_id
+1,7,11,2.5000,,false,false,,,
+2,7,12,2.5000,,false,true,chargeback,2024-01-01T00:00:00.000Z,nightly
+3,7,13,0,,false,false,,,nightly
+";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+
+        let mut output = Vec::new();
+        TransactionDumpWriter::new(&mut output).finish().unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), format!("{}\n", DUMP_HEADER.join(",")));
+    }
+}
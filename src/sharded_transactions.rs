@@ -0,0 +1,104 @@
+use std::error::Error;
+
+use async_stream::try_stream;
+use futures_core::Stream;
+use futures_util::pin_mut;
+use futures_util::stream::TryStreamExt;
+
+use crate::input::InputTransaction;
+use crate::output::OutputAccount;
+use crate::transactions::{ApplyOutcome, Transactions};
+
+// Splits clients across several databases by `client_id % shards`.
+// Every transaction for a client lives in the same shard, so disputes and replays
+// never need to look at more than one shard.
+// Transaction IDs are only checked for uniqueness within a shard.
+#[derive(Clone)]
+pub struct ShardedTransactions {
+    shards: Vec<Transactions>,
+}
+
+impl ShardedTransactions {
+    pub async fn new(db_urls: &[&str]) -> Result<Self, Box<dyn Error>> {
+        if db_urls.is_empty() {
+            return Err("At least one shard is required".into());
+        }
+        let mut shards = Vec::with_capacity(db_urls.len());
+        for db_url in db_urls {
+            shards.push(Transactions::new(db_url).await?);
+        }
+        Ok(Self{ shards })
+    }
+
+    pub fn shard_for_client(&self, client_id: u32) -> usize {
+        client_id as usize % self.shards.len()
+    }
+
+    pub async fn add_input(&self, input_transaction: InputTransaction) -> Result<ApplyOutcome, Box<dyn Error>> {
+        let shard = self.shard_for_client(input_transaction.client);
+        self.shards[shard].add_input(input_transaction).await
+    }
+
+    pub async fn get_account_for_client(&self, client_id: u32) -> Result<OutputAccount, Box<dyn Error>> {
+        let shard = self.shard_for_client(client_id);
+        self.shards[shard].get_account_for_client(client_id).await
+    }
+
+    pub async fn get_accounts(self) -> impl Stream<Item = Result<OutputAccount, Box<dyn Error>>> {
+        try_stream! {
+            for shard in self.shards {
+                let accounts = shard.get_accounts().await;
+                pin_mut!(accounts);
+                while let Some(account) = accounts.try_next().await? {
+                    yield account;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    use crate::input::TransactionType;
+
+    #[tokio::test]
+    async fn test_clients_are_routed_to_shards() {
+        let sharded = ShardedTransactions::new(&["sqlite::memory:", "sqlite::memory:"]).await.unwrap();
+        let transactions = vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     2,  2, Some("20")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     3,  3, Some("30")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  1, None).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  2,  4, Some("5")).unwrap(),
+        ];
+        for transaction in transactions {
+            sharded.add_input(transaction).await.unwrap();
+        }
+
+        let even_clients: HashSet<u32> = sharded.shards[0].clone().get_accounts().await
+            .map_ok(|account| account.client)
+            .try_collect().await.unwrap();
+        let odd_clients: HashSet<u32> = sharded.shards[1].clone().get_accounts().await
+            .map_ok(|account| account.client)
+            .try_collect().await.unwrap();
+        assert_eq!(even_clients, HashSet::from([2]));
+        assert_eq!(odd_clients, HashSet::from([1, 3]));
+
+        let accounts: HashSet<OutputAccount> = sharded.get_accounts().await.try_collect().await.unwrap();
+        let expected = HashSet::from([
+            OutputAccount::new(1, "0", "100", "100", false).unwrap(),
+            OutputAccount::new(2, "15", "0", "15", false).unwrap(),
+            OutputAccount::new(3, "30", "0", "30", false).unwrap(),
+        ]);
+        assert_eq!(accounts, expected)
+    }
+
+    #[tokio::test]
+    async fn test_no_shards() {
+        assert!(ShardedTransactions::new(&[]).await.is_err())
+    }
+}
@@ -0,0 +1,81 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Exit code used when processing is stopped by a signal (128 + SIGINT).
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+// A flag shared between the signal handlers and the processing loops,
+// which check it between transactions and between accounts.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    triggered: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    // Trigger the shutdown when the process receives SIGINT or SIGTERM.
+    pub fn listen_for_signals(&self) -> Result<(), Box<dyn Error>> {
+        #[cfg(unix)]
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = terminate.recv() => {},
+            }
+            #[cfg(not(unix))]
+            let _ = tokio::signal::ctrl_c().await;
+            shutdown.trigger();
+        });
+        Ok(())
+    }
+
+    // Return an `Interrupted` error if the shutdown has been triggered.
+    pub fn check(&self) -> Result<(), Interrupted> {
+        if self.is_triggered() {
+            Err(Interrupted)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Processing was interrupted")
+    }
+}
+
+impl Error for Interrupted {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trigger_is_shared() {
+        let shutdown = Shutdown::new();
+        let other = shutdown.clone();
+        assert!(shutdown.check().is_ok());
+        other.trigger();
+        assert!(shutdown.is_triggered());
+        assert!(shutdown.check().is_err());
+    }
+}
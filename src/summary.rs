@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use bigdecimal::BigDecimal;
+
+use crate::output::OutputAccount;
+
+// What a run did, for callers that need the outcome without parsing the logs.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RunSummary {
+    // The number of input records read, including skipped ones.
+    pub processed: usize,
+    // The number of input records that were skipped.
+    pub skipped: usize,
+    // The number of distinct clients with an account in the output.
+    pub clients: usize,
+    // The number of locked accounts in the output.
+    pub locked_accounts: usize,
+    pub duration: Duration,
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Processed {} input records ({} skipped), wrote accounts for {} clients ({} locked accounts) in {:.3}s",
+            self.processed,
+            self.skipped,
+            self.clients,
+            self.locked_accounts,
+            self.duration.as_secs_f64(),
+        )
+    }
+}
+
+// The held and total funds of every account written in one currency.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CurrencyTotals {
+    pub held: BigDecimal,
+    pub total: BigDecimal,
+}
+
+impl CurrencyTotals {
+    // Whether the held funds are more than `max_ratio` of the total funds.
+    pub fn held_ratio_above(&self, max_ratio: &BigDecimal) -> bool {
+        self.held > max_ratio * &self.total
+    }
+}
+
+// The held and total funds across every account written, for book-level checks.
+// Amounts in different currencies can't be added together, so they are kept per currency,
+// with `None` for the default currency.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BookTotals {
+    pub currencies: BTreeMap<Option<String>, CurrencyTotals>,
+}
+
+impl BookTotals {
+    pub fn add(&mut self, account: &OutputAccount) {
+        let totals = self.currencies.entry(account.currency.clone()).or_default();
+        totals.held += &account.held;
+        totals.total += &account.total;
+    }
+
+    // The currencies whose held funds are more than `max_ratio` of their total funds, in currency order.
+    pub fn held_ratio_above<'a>(&'a self, max_ratio: &'a BigDecimal) -> impl Iterator<Item = (&'a Option<String>, &'a CurrencyTotals)> + 'a {
+        self.currencies.iter().filter(move |(_, totals)| totals.held_ratio_above(max_ratio))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_held_ratio_above() {
+        let ratio = BigDecimal::from_str("0.25").unwrap();
+        let mut book = BookTotals::default();
+        let above = |book: &BookTotals| book.held_ratio_above(&ratio).count() > 0;
+        assert!(!above(&book));
+        book.add(&OutputAccount::new(1, "80", "20", "100", false).unwrap());
+        book.add(&OutputAccount::new(2, "100", "0", "100", false).unwrap());
+        let totals = &book.currencies[&None];
+        assert_eq!((totals.held.to_string(), totals.total.to_string()), ("20".into(), "200".into()));
+        assert!(!above(&book));
+        book.add(&OutputAccount::new(3, "0", "40", "40", false).unwrap());
+        assert!(!above(&book));
+        book.add(&OutputAccount::new(4, "0", "1", "1", false).unwrap());
+        assert!(above(&book));
+    }
+
+    #[test]
+    fn test_held_ratio_per_currency() {
+        let ratio = BigDecimal::from_str("0.25").unwrap();
+        let mut book = BookTotals::default();
+        // Half of the USD is held, which is above the ratio, even though it is a small part of all the funds.
+        book.add(&OutputAccount::new(1, "1000", "0", "1000", false).unwrap().with_currency("EUR"));
+        book.add(&OutputAccount::new(2, "10", "10", "20", false).unwrap().with_currency("USD"));
+        let above: Vec<&Option<String>> = book.held_ratio_above(&ratio).map(|(currency, _)| currency).collect();
+        assert_eq!(above, vec![&Some("USD".to_string())]);
+    }
+
+    #[test]
+    fn test_display() {
+        let summary = RunSummary {
+            processed: 10,
+            skipped: 2,
+            clients: 3,
+            locked_accounts: 1,
+            duration: Duration::from_millis(1500),
+        };
+        assert_eq!(
+            summary.to_string(),
+            "Processed 10 input records (2 skipped), wrote accounts for 3 clients (1 locked accounts) in 1.500s",
+        );
+    }
+}
@@ -1,130 +1,2212 @@
 use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use async_stream::try_stream;
 use bigdecimal::{BigDecimal, Zero, Signed};
 use futures_core::Stream;
-use futures_util::stream::TryStreamExt;
+use futures_util::pin_mut;
+use futures_util::stream::{StreamExt, TryStreamExt};
+use sqlx::sqlite::SqliteConnection;
+use sqlx::Connection;
 
 use crate::input::{InputTransaction, TransactionType};
-use crate::output::OutputAccount;
-use crate::transactions_store::{MutableTransactionStore};
+use crate::output::{ColumnScales, HeldReport, OutputAccount, RoundMode, TransactionDumpWriter};
+use crate::transactions_store::{
+    from_stored_amount, max_stored_amount, ClientID, ConflictPolicy, MutableTransaction, MutableTransactionStore, NewTransaction, SchemaMode, AccountBalance, TransactionCount,
+    AMOUNT_SCALE, DEFAULT_CURRENCY,
+};
+
+// Round an amount to the scale it is stored at, so that equal amounts are always stored the same way.
+pub fn normalize_amount(amount: &BigDecimal) -> BigDecimal {
+    amount.round(AMOUNT_SCALE).with_scale(AMOUNT_SCALE)
+}
+
+// The number of decimal places of an amount, not counting trailing zeros.
+fn decimal_places(amount: &BigDecimal) -> i64 {
+    let (_, scale) = amount.normalized().as_bigint_and_exponent();
+    scale.max(0)
+}
+
+// What happened to an input transaction.
+// A deposit or withdrawal is applied when it is stored,
+// even if it is later ignored for overdrawing the account.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApplyOutcome {
+    Applied,
+    // A dispute for a transaction that hasn't been received yet,
+    // which will be applied when the transaction is received.
+    Pending,
+    Skipped(SkipReason),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SkipReason {
+    // A deposit or withdrawal with a transaction ID that already exists.
+    DuplicateTransaction,
+    // A dispute, resolve or chargeback for a transaction that doesn't exist for the client.
+    UnknownTransaction,
+    // A dispute, resolve or chargeback for a client without any transactions.
+    UnknownClient,
+    // A dispute for a transaction that is already disputed, or a replacement of one with `ConflictPolicy::Replace`.
+    AlreadyDisputed,
+    // A resolve or chargeback for a transaction that is not disputed.
+    NotDisputed,
+    // A dispute, resolve, chargeback or replacement for a transaction that has already been charged back, which is settled.
+    AlreadyChargedBack,
+    // A transaction for client 0, when that is reserved.
+    ClientZero,
+    // A transaction for a client that isn't in the allow list.
+    ClientNotAllowed,
+    // A transaction for a client that is in the deny list.
+    ClientDenied,
+    // A deposit or withdrawal with a negative amount.
+    NegativeAmount,
+    // A dispute, resolve or chargeback for a transaction that belongs to another client.
+    ClientMismatch,
+    // A withdrawal for a client with an open dispute, when that freezes the account.
+    DisputeOpen,
+    // A deposit or withdrawal with more decimal places than allowed.
+    ExcessScale { max_scale: u32 },
+    // A transaction rejected by a validation rule, with the rule's reason.
+    Rejected(String),
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            SkipReason::DuplicateTransaction => "duplicate transaction",
+            SkipReason::UnknownTransaction => "unknown transaction",
+            SkipReason::UnknownClient => "unknown client",
+            SkipReason::AlreadyDisputed => "already disputed",
+            SkipReason::NotDisputed => "not disputed",
+            SkipReason::AlreadyChargedBack => "already charged back",
+            SkipReason::ClientZero => "client 0 is reserved",
+            SkipReason::ClientNotAllowed => "client is not allowed",
+            SkipReason::ClientDenied => "client is denied",
+            SkipReason::NegativeAmount => "negative amount",
+            SkipReason::ClientMismatch => "transaction belongs to another client",
+            SkipReason::DisputeOpen => "account is frozen by an open dispute",
+            SkipReason::ExcessScale { max_scale } => return write!(f, "amount has more than {} decimal places", max_scale),
+            SkipReason::Rejected(reason) => return write!(f, "rejected: {}", reason),
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+// Input transactions that can't be applied at all, as opposed to those that are skipped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionError {
+    // A deposit or withdrawal without an amount.
+    MissingAmount { tx: u32, transaction_type: TransactionType },
+    // A deposit or withdrawal with more decimal places than allowed, when that isn't skipped.
+    ExcessScale { tx: u32, max_scale: u32 },
+    // A deposit or withdrawal with an amount too large to be stored as an integer number of units.
+    AmountTooLarge { tx: u32 },
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransactionError::MissingAmount { tx, transaction_type } => {
+                write!(f, "{:?} must have an amount (transaction {})", transaction_type, tx)
+            },
+            TransactionError::ExcessScale { tx, max_scale } => {
+                write!(f, "Amount of transaction {} has more than {} decimal places", tx, max_scale)
+            },
+            TransactionError::AmountTooLarge { tx } => {
+                write!(f, "Amount of transaction {} is larger than the maximum of {}", tx, max_stored_amount())
+            },
+        }
+    }
+}
+
+impl Error for TransactionError {}
+
+// Whether a validation rule accepts an input transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationResult {
+    Accept,
+    Reject(String),
+}
+
+// What to do with a transaction that would bring the available amount of an account below zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NegativeAvailablePolicy {
+    // Ignore the transaction.
+    Skip,
+    // Apply the transaction if the account has enough funds including the held amount,
+    // which happens when funds were disputed after they had already been spent.
+    AllowWhenHeld,
+}
+
+impl FromStr for NegativeAvailablePolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(NegativeAvailablePolicy::Skip),
+            "allow-when-held" => Ok(NegativeAvailablePolicy::AllowWhenHeld),
+            _ => Err(format!("Unknown negative available policy: {}", s).into()),
+        }
+    }
+}
+
+// What to do with a withdrawal of more than the available funds.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum WithdrawalPolicy {
+    // Ignore the withdrawal.
+    #[default]
+    RejectWhole,
+    // Withdraw whatever is available.
+    AllowPartial,
+    // Ignore the withdrawal, and lock the account so that it is reviewed.
+    RejectAndFlag,
+}
+
+impl FromStr for WithdrawalPolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject-whole" => Ok(WithdrawalPolicy::RejectWhole),
+            "allow-partial" => Ok(WithdrawalPolicy::AllowPartial),
+            "reject-and-flag" => Ok(WithdrawalPolicy::RejectAndFlag),
+            _ => Err(format!("Unknown withdrawal policy: {}", s).into()),
+        }
+    }
+}
+
+// Why a transaction would bring the available amount of an account below zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Overdraw {
+    // Disputes hold funds that were already spent, but the total stays at or above zero.
+    HeldByDispute,
+    // The account doesn't have enough funds, even counting the held funds.
+    Insufficient,
+}
+
+// What replaying a stored transaction did to the account.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplayStep {
+    Applied,
+    // Ignored, as it would have overdrawn the available amount.
+    Overdrawn,
+    // A withdrawal of only the available amount, as the whole amount would have overdrawn it.
+    PartiallyApplied,
+    // Ignored, as it would have overdrawn the available amount, and the account locked.
+    Flagged,
+    // Reversed, and the account locked.
+    ChargedBack,
+    // Ignored, as it came after a charged back transaction.
+    AfterLock,
+}
+
+impl fmt::Display for ReplayStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let step = match self {
+            ReplayStep::Applied => "applied",
+            ReplayStep::Overdrawn => "ignored, as it would overdraw the account",
+            ReplayStep::PartiallyApplied => "partially applied, withdrawing only the available funds",
+            ReplayStep::Flagged => "ignored, as it would overdraw the account, locking the account for review",
+            ReplayStep::ChargedBack => "charged back, locking the account",
+            ReplayStep::AfterLock => "ignored, as it came after a chargeback",
+        };
+        write!(f, "{}", step)
+    }
+}
+
+// The effect of a single transaction on its account, for support and debugging.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explanation {
+    pub client: u32,
+    pub tx: u32,
+    // Negative for withdrawals.
+    pub amount: BigDecimal,
+    pub disputed: bool,
+    pub charged_back: bool,
+    pub step: ReplayStep,
+    pub before: OutputAccount,
+    pub after: OutputAccount,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Transaction {} for client {}: amount {}, disputed: {}, charged back: {}",
+            self.tx, self.client, self.amount, self.disputed, self.charged_back)?;
+        writeln!(f, "Outcome: {}", self.step)?;
+        writeln!(f, "Available: {} -> {}", self.before.available, self.after.available)?;
+        writeln!(f, "Held: {} -> {}", self.before.held, self.after.held)
+    }
+}
+
+// Whether an input transaction is stored as a new transaction, rather than changing a stored one.
+fn is_insert(input_transaction: &InputTransaction) -> bool {
+    matches!(input_transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal)
+}
+
+fn empty_account(client_id: u32, currency: &str) -> OutputAccount {
+    OutputAccount{
+        client: client_id,
+        currency: if currency == DEFAULT_CURRENCY { None } else { Some(currency.into()) },
+        available: BigDecimal::zero(),
+        held: BigDecimal::zero(),
+        total: BigDecimal::zero(),
+        locked: false,
+        computed_at: None,
+    }
+}
+
+// The account of a stored running balance, before its amounts are rounded.
+fn balance_to_account(balance: &AccountBalance) -> Result<OutputAccount, Box<dyn Error>> {
+    Ok(OutputAccount{
+        available: BigDecimal::from_str(&balance.available)?,
+        held: BigDecimal::from_str(&balance.held)?,
+        total: BigDecimal::from_str(&balance.total)?,
+        locked: balance.locked,
+        ..empty_account(balance.client_id, &balance.currency)
+    })
+}
+
+type OnTransaction = Arc<dyn Fn(&InputTransaction, ApplyOutcome) + Send + Sync>;
+
+type ValidationRule = Arc<dyn Fn(&InputTransaction) -> ValidationResult + Send + Sync>;
+
+type AccountCache = Arc<Mutex<HashMap<(u32, String), OutputAccount>>>;
 
 #[derive(Clone)]
 pub struct Transactions {
     transactions_store: MutableTransactionStore,
+    on_transaction: Option<OnTransaction>,
+    validation_rules: Vec<ValidationRule>,
+    account_cache: Option<AccountCache>,
+    // The next ordinal to store a transaction at, if ordinals are deterministic.
+    next_ordinal: Option<Arc<AtomicI64>>,
+    reject_client_zero: bool,
+    allowed_clients: Option<Arc<HashSet<u32>>>,
+    denied_clients: Option<Arc<HashSet<u32>>>,
+    max_input_scale: Option<u32>,
+    strict_input_scale: bool,
+    pending_disputes: bool,
+    lock_halts_replay: bool,
+    ignore_disputes: bool,
+    negative_available_policy: NegativeAvailablePolicy,
+    withdrawal_policy: WithdrawalPolicy,
+    strict_dispute_client: bool,
+    freeze_on_dispute: bool,
+    conflict_policy: ConflictPolicy,
+    as_of: Option<i64>,
+    run_id: Option<Arc<str>>,
+    round_digits: i64,
+    column_scales: ColumnScales,
+    round_mode: RoundMode,
+    fixed_scale: bool,
+}
+
+pub struct TransactionsBuilder {
+    db_url: String,
+    append: bool,
+    existing: bool,
+    cache: bool,
+    deterministic_ordinals: bool,
+    reject_client_zero: bool,
+    allowed_clients: Option<Arc<HashSet<u32>>>,
+    denied_clients: Option<Arc<HashSet<u32>>>,
+    max_input_scale: Option<u32>,
+    strict_input_scale: bool,
+    pending_disputes: bool,
+    lock_halts_replay: bool,
+    ignore_disputes: bool,
+    negative_available_policy: NegativeAvailablePolicy,
+    withdrawal_policy: WithdrawalPolicy,
+    strict_dispute_client: bool,
+    freeze_on_dispute: bool,
+    conflict_policy: ConflictPolicy,
+    as_of: Option<i64>,
+    run_id: Option<Arc<str>>,
+    round_digits: i64,
+    column_scales: ColumnScales,
+    round_mode: RoundMode,
+    fixed_scale: bool,
+    pool_size: Option<u32>,
+    on_transaction: Option<OnTransaction>,
+    validation_rules: Vec<ValidationRule>,
+}
+
+impl TransactionsBuilder {
+    // Keep any transactions from previous runs, instead of starting with an empty store.
+    pub fn append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    // Open a store that must already exist with its schema, e.g. to query it, instead of creating it.
+    pub fn existing(mut self, existing: bool) -> Self {
+        self.existing = existing;
+        self
+    }
+
+    // Keep computed accounts in memory until a new transaction is applied for the client.
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    // Order transactions by when they were added, rather than when their insert reaches the database,
+    // so that replay is deterministic when inputs are added concurrently.
+    pub fn deterministic_ordinals(mut self, deterministic_ordinals: bool) -> Self {
+        self.deterministic_ordinals = deterministic_ordinals;
+        self
+    }
+
+    // The number of decimal places the amounts of accounts are rounded to (default 4).
+    pub fn round_digits(mut self, round_digits: i64) -> Self {
+        self.round_digits = round_digits;
+        self
+    }
+
+    // The number of decimal places of the available, held or total amounts, overriding `round_digits` for each.
+    pub fn column_scales(mut self, column_scales: ColumnScales) -> Self {
+        self.column_scales = column_scales;
+        self
+    }
+
+    // How the amounts of accounts are rounded (default half-up).
+    pub fn round_mode(mut self, round_mode: RoundMode) -> Self {
+        self.round_mode = round_mode;
+        self
+    }
+
+    // Give the amounts of accounts exactly `round_digits` decimal places (or their column scale),
+    // padding them with zeros, rather than keeping the decimal places they have up to that.
+    pub fn fixed_scale(mut self, fixed_scale: bool) -> Self {
+        self.fixed_scale = fixed_scale;
+        self
+    }
+
+    // The maximum number of database connections.
+    pub fn pool_size(mut self, pool_size: Option<u32>) -> Self {
+        self.pool_size = pool_size;
+        self
+    }
+
+    // Skip transactions for client 0, for systems that use it as a sentinel.
+    pub fn reject_client_zero(mut self, reject_client_zero: bool) -> Self {
+        self.reject_client_zero = reject_client_zero;
+        self
+    }
+
+    // Skip transactions for clients that aren't in `allowed_clients`, if it's given.
+    pub fn allowed_clients(mut self, allowed_clients: Option<HashSet<u32>>) -> Self {
+        self.allowed_clients = allowed_clients.map(Arc::new);
+        self
+    }
+
+    // Skip transactions for clients that are in `denied_clients`, if it's given.
+    pub fn denied_clients(mut self, denied_clients: Option<HashSet<u32>>) -> Self {
+        self.denied_clients = denied_clients.map(Arc::new);
+        self
+    }
+
+    // Skip deposits and withdrawals with more than this many decimal places, instead of rounding them.
+    pub fn max_input_scale(mut self, max_input_scale: Option<u32>) -> Self {
+        self.max_input_scale = max_input_scale;
+        self
+    }
+
+    // Fail with an error, instead of skipping, a deposit or withdrawal with more decimal places than `max_input_scale`,
+    // or than the 4 decimal places amounts are stored with if it isn't given, so over-precise data is never ingested.
+    pub fn strict_input_scale(mut self, strict_input_scale: bool) -> Self {
+        self.strict_input_scale = strict_input_scale;
+        self
+    }
+
+    // Keep disputes for transactions that haven't been received yet,
+    // and apply them when the transaction is received.
+    pub fn pending_disputes(mut self, pending_disputes: bool) -> Self {
+        self.pending_disputes = pending_disputes;
+        self
+    }
+
+    // Stop replaying an account at the first charged back transaction (default true).
+    // When false, later transactions are still applied, but the account stays locked.
+    pub fn lock_halts_replay(mut self, lock_halts_replay: bool) -> Self {
+        self.lock_halts_replay = lock_halts_replay;
+        self
+    }
+
+    // Compute accounts as if no transaction had been disputed or charged back,
+    // without changing the disputes in the store.
+    pub fn ignore_disputes(mut self, ignore_disputes: bool) -> Self {
+        self.ignore_disputes = ignore_disputes;
+        self
+    }
+
+    // What to do with transactions that would bring the available amount below zero (default skip).
+    pub fn negative_available_policy(mut self, negative_available_policy: NegativeAvailablePolicy) -> Self {
+        self.negative_available_policy = negative_available_policy;
+        self
+    }
+
+    // What to do with a withdrawal of more than the available funds (default reject the whole withdrawal).
+    pub fn withdrawal_policy(mut self, withdrawal_policy: WithdrawalPolicy) -> Self {
+        self.withdrawal_policy = withdrawal_policy;
+        self
+    }
+
+    // Fail with an error, instead of skipping, a dispute, resolve or chargeback
+    // for a transaction that belongs to another client.
+    pub fn strict_dispute_client(mut self, strict_dispute_client: bool) -> Self {
+        self.strict_dispute_client = strict_dispute_client;
+        self
+    }
+
+    // Skip withdrawals for a client while any of their transactions is disputed,
+    // as if the account were locked until the dispute is resolved or charged back.
+    pub fn freeze_on_dispute(mut self, freeze_on_dispute: bool) -> Self {
+        self.freeze_on_dispute = freeze_on_dispute;
+        self
+    }
+
+    // What to do with a deposit or withdrawal whose transaction ID already exists (default ignore).
+    pub fn conflict_policy(mut self, conflict_policy: ConflictPolicy) -> Self {
+        self.conflict_policy = conflict_policy;
+        self
+    }
+
+    // Compute accounts as they were when the transaction at this ordinal was stored, ignoring later ones.
+    pub fn as_of(mut self, max_ordinal: Option<i64>) -> Self {
+        self.as_of = max_ordinal;
+        self
+    }
+
+    // Store this run's ID with each deposit and withdrawal it stores, to trace appended data back to its run.
+    // It isn't used for ordering, so replay is the same with or without it.
+    pub fn run_id(mut self, run_id: Option<String>) -> Self {
+        self.run_id = run_id.map(Arc::from);
+        self
+    }
+
+    // Register a callback that is called with the outcome of every input transaction.
+    pub fn on_transaction<F>(mut self, on_transaction: F) -> Self
+    where F: Fn(&InputTransaction, ApplyOutcome) + Send + Sync + 'static
+    {
+        self.on_transaction = Some(Arc::new(on_transaction));
+        self
+    }
+
+    // Register a rule that every input transaction is checked against before it is applied.
+    // Rejected transactions are skipped with the rule's reason.
+    pub fn validation_rule<F>(mut self, rule: F) -> Self
+    where F: Fn(&InputTransaction) -> ValidationResult + Send + Sync + 'static
+    {
+        self.validation_rules.push(Arc::new(rule));
+        self
+    }
+
+    pub async fn build(self) -> Result<Transactions, Box<dyn Error>> {
+        let schema_mode = if self.existing {
+            SchemaMode::Existing
+        } else if self.append {
+            SchemaMode::CreateIfAbsent
+        } else {
+            SchemaMode::Recreate
+        };
+        let transactions_store = MutableTransactionStore::open(&self.db_url, self.pool_size, schema_mode).await?;
+        let next_ordinal = if self.deterministic_ordinals {
+            let max_ordinal = transactions_store.get_max_ordinal().await?.unwrap_or(0);
+            Some(Arc::new(AtomicI64::new(max_ordinal + 1)))
+        } else {
+            None
+        };
+        Ok(Transactions {
+            transactions_store,
+            next_ordinal,
+            on_transaction: self.on_transaction,
+            validation_rules: self.validation_rules,
+            account_cache: if self.cache { Some(AccountCache::default()) } else { None },
+            reject_client_zero: self.reject_client_zero,
+            allowed_clients: self.allowed_clients,
+            denied_clients: self.denied_clients,
+            max_input_scale: self.max_input_scale,
+            strict_input_scale: self.strict_input_scale,
+            pending_disputes: self.pending_disputes,
+            lock_halts_replay: self.lock_halts_replay,
+            ignore_disputes: self.ignore_disputes,
+            negative_available_policy: self.negative_available_policy,
+            withdrawal_policy: self.withdrawal_policy,
+            strict_dispute_client: self.strict_dispute_client,
+            freeze_on_dispute: self.freeze_on_dispute,
+            conflict_policy: self.conflict_policy,
+            as_of: self.as_of,
+            run_id: self.run_id,
+            round_digits: self.round_digits,
+            column_scales: self.column_scales,
+            round_mode: self.round_mode,
+            fixed_scale: self.fixed_scale,
+        })
+    }
 }
 
 impl Transactions {
+    pub fn builder(db_url: &str) -> TransactionsBuilder {
+        TransactionsBuilder {
+            db_url: db_url.into(),
+            append: false,
+            existing: false,
+            cache: false,
+            deterministic_ordinals: false,
+            reject_client_zero: false,
+            allowed_clients: None,
+            denied_clients: None,
+            max_input_scale: None,
+            strict_input_scale: false,
+            pending_disputes: false,
+            lock_halts_replay: true,
+            ignore_disputes: false,
+            negative_available_policy: NegativeAvailablePolicy::Skip,
+            withdrawal_policy: WithdrawalPolicy::RejectWhole,
+            strict_dispute_client: false,
+            freeze_on_dispute: false,
+            conflict_policy: ConflictPolicy::Ignore,
+            as_of: None,
+            run_id: None,
+            round_digits: 4,
+            column_scales: ColumnScales::default(),
+            round_mode: RoundMode::HalfUp,
+            fixed_scale: false,
+            pool_size: None,
+            on_transaction: None,
+            validation_rules: Vec::new(),
+        }
+    }
+
     pub async fn new(db_url: &str) -> Result<Self, Box<dyn Error>> {
-        let transactions_store = MutableTransactionStore::new(db_url).await?;
-        transactions_store.clean_and_recreate().await?;
-        Ok(Self{ transactions_store })
-    }
-
-    pub async fn add_input(&self, input_transaction: InputTransaction) -> Result<(), Box<dyn Error>> {
-        match input_transaction.transaction_type {
-            TransactionType::Deposit => {
-                self.transactions_store.insert_transaction(
-                    input_transaction.client,
-                    input_transaction.tx,
-                    &input_transaction.amount.ok_or("Deposit must have an amount")?.to_string(),
-                ).await?
-            },
-            TransactionType::Withdrawal => {
-                self.transactions_store.insert_transaction(
-                    input_transaction.client,
-                    input_transaction.tx,
-                    &(-input_transaction.amount.ok_or("Deposit must have an amount")?).to_string(),
-                ).await?
+        Self::builder(db_url).build().await
+    }
+
+    // Open a store, keeping any transactions from previous runs.
+    pub async fn open(db_url: &str) -> Result<Self, Box<dyn Error>> {
+        Self::builder(db_url).append(true).build().await
+    }
+
+    pub async fn close(&self) {
+        self.transactions_store.close().await;
+    }
+
+    pub async fn add_input(&self, input_transaction: InputTransaction) -> Result<ApplyOutcome, Box<dyn Error>> {
+        let ordinal = self.reserve_ordinals(std::slice::from_ref(&input_transaction));
+        self.add_input_at(input_transaction, ordinal).await
+    }
+
+    // Apply a batch of input transactions in order.
+    // With deterministic ordinals, the deposits and withdrawals of the batch are stored in input order,
+    // next to each other, even if other inputs are added concurrently.
+    pub async fn add_inputs(&self, input_transactions: Vec<InputTransaction>) -> Result<Vec<ApplyOutcome>, Box<dyn Error>> {
+        let mut next_ordinal = self.reserve_ordinals(&input_transactions);
+        let mut outcomes = Vec::with_capacity(input_transactions.len());
+        for input_transaction in input_transactions {
+            let ordinal = if is_insert(&input_transaction) { next_ordinal } else { None };
+            next_ordinal = next_ordinal.map(|next| if ordinal.is_some() { next + 1 } else { next });
+            outcomes.push(self.add_input_at(input_transaction, ordinal).await?);
+        }
+        Ok(outcomes)
+    }
+
+    // Apply input transactions from an async source, such as a message queue, in the order they arrive,
+    // until the stream ends. Returns the number of inputs that were applied, rather than skipped,
+    // as the stream may be long running. The outcome of each can be observed with `on_transaction`.
+    pub async fn add_inputs_from_stream<S>(&self, input_transactions: S) -> Result<usize, Box<dyn Error>>
+    where S: Stream<Item = InputTransaction>
+    {
+        pin_mut!(input_transactions);
+        let mut applied = 0;
+        while let Some(input_transaction) = input_transactions.next().await {
+            if self.add_input(input_transaction).await? == ApplyOutcome::Applied {
+                applied += 1;
+            }
+        }
+        Ok(applied)
+    }
+
+    // Reserve an ordinal for each deposit and withdrawal in `input_transactions`, returning the first,
+    // if ordinals are deterministic.
+    // This happens before anything is awaited, so concurrent inputs are ordered by when they were added.
+    fn reserve_ordinals(&self, input_transactions: &[InputTransaction]) -> Option<i64> {
+        let next_ordinal = self.next_ordinal.as_ref()?;
+        let count = input_transactions.iter().filter(|input_transaction| is_insert(input_transaction)).count();
+        Some(next_ordinal.fetch_add(count as i64, Ordering::SeqCst))
+    }
+
+    async fn add_input_at(&self, input_transaction: InputTransaction, ordinal: Option<i64>) -> Result<ApplyOutcome, Box<dyn Error>> {
+        let mut conn = self.transactions_store.acquire().await?;
+        let outcome = self.apply_input(&input_transaction, ordinal, &mut conn).await?;
+        self.applied(&input_transaction, &outcome);
+        Ok(outcome)
+    }
+
+    // Apply input transactions in order within a single database transaction,
+    // so that either all of them are stored, or, if any of them fails, none of them are.
+    // The inputs are usually all for one client, so that a failure leaves the client's account as it was.
+    pub async fn add_inputs_atomically(&self, input_transactions: &[InputTransaction]) -> Result<Vec<ApplyOutcome>, Box<dyn Error>> {
+        let mut next_ordinal = self.reserve_ordinals(input_transactions);
+        let mut db_transaction = self.transactions_store.begin().await?;
+        let mut outcomes = Vec::with_capacity(input_transactions.len());
+        for input_transaction in input_transactions {
+            let ordinal = if is_insert(input_transaction) { next_ordinal } else { None };
+            next_ordinal = next_ordinal.map(|next| if ordinal.is_some() { next + 1 } else { next });
+            outcomes.push(self.apply_input(input_transaction, ordinal, &mut db_transaction).await?);
+        }
+        db_transaction.commit().await?;
+        for (input_transaction, outcome) in input_transactions.iter().zip(&outcomes) {
+            self.applied(input_transaction, outcome);
+        }
+        Ok(outcomes)
+    }
+
+    // Invalidate the cached account of an applied input, and report its outcome.
+    fn applied(&self, input_transaction: &InputTransaction, outcome: &ApplyOutcome) {
+        if let (Some(account_cache), ApplyOutcome::Applied) = (&self.account_cache, outcome) {
+            account_cache.lock().unwrap().retain(|(client_id, _), _| *client_id != input_transaction.client);
+        }
+        if let Some(on_transaction) = &self.on_transaction {
+            on_transaction(input_transaction, outcome.clone());
+        }
+    }
+
+    async fn apply_input(&self, input_transaction: &InputTransaction, ordinal: Option<i64>, conn: &mut SqliteConnection) -> Result<ApplyOutcome, Box<dyn Error>> {
+        let client = input_transaction.client;
+        let tx = input_transaction.tx;
+        if self.reject_client_zero && client == 0 {
+            return Ok(ApplyOutcome::Skipped(SkipReason::ClientZero));
+        }
+        if self.allowed_clients.as_ref().is_some_and(|allowed| !allowed.contains(&client)) {
+            return Ok(ApplyOutcome::Skipped(SkipReason::ClientNotAllowed));
+        }
+        if self.denied_clients.as_ref().is_some_and(|denied| denied.contains(&client)) {
+            return Ok(ApplyOutcome::Skipped(SkipReason::ClientDenied));
+        }
+        if input_transaction.amount.as_ref().is_some_and(|amount| amount.is_negative()) && is_insert(input_transaction) {
+            return Ok(ApplyOutcome::Skipped(SkipReason::NegativeAmount));
+        }
+        let max_scale = match self.max_input_scale {
+            None if self.strict_input_scale => Some(AMOUNT_SCALE as u32),
+            max_scale => max_scale,
+        };
+        if let (Some(max_scale), Some(amount)) = (max_scale, &input_transaction.amount) {
+            if is_insert(input_transaction) && decimal_places(amount) > max_scale.into() {
+                if self.strict_input_scale {
+                    return Err(Box::new(TransactionError::ExcessScale { tx: input_transaction.tx, max_scale }));
+                }
+                return Ok(ApplyOutcome::Skipped(SkipReason::ExcessScale { max_scale }));
+            }
+        }
+        for rule in &self.validation_rules {
+            if let ValidationResult::Reject(reason) = rule(input_transaction) {
+                return Ok(ApplyOutcome::Skipped(SkipReason::Rejected(reason)));
+            }
+        }
+        let currency = input_transaction.currency.as_deref().unwrap_or(DEFAULT_CURRENCY);
+        // The change and the stored balance it updates are made together, with the change first,
+        // so that concurrent changes to an account are applied to its balance one at a time.
+        let mut db_transaction = conn.begin().await?;
+        // The freeze is checked in the same database transaction as the withdrawal,
+        // so that a dispute stored concurrently can't slip in between the check and the withdrawal.
+        if self.freeze_on_dispute && input_transaction.transaction_type == TransactionType::Withdrawal {
+            let frozen = MutableTransactionStore::has_open_dispute_on(&mut db_transaction, client).await?;
+            if frozen {
+                return Ok(ApplyOutcome::Skipped(SkipReason::DisputeOpen));
+            }
+        }
+        let changed = match input_transaction.transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                let amount = input_transaction.amount.as_ref().ok_or(TransactionError::MissingAmount {
+                    tx,
+                    transaction_type: input_transaction.transaction_type,
+                })?;
+                let amount = normalize_amount(amount);
+                if amount.abs() > max_stored_amount() {
+                    return Err(Box::new(TransactionError::AmountTooLarge { tx }));
+                }
+                let amount = if input_transaction.transaction_type == TransactionType::Withdrawal { -amount } else { amount };
+                let transaction = NewTransaction {
+                    ordinal,
+                    client_id: client,
+                    transaction_id: tx,
+                    amount: &amount,
+                    currency,
+                    timestamp: input_transaction.timestamp,
+                    run_id: self.run_id.as_deref(),
+                };
+                MutableTransactionStore::insert_new_transaction_on(&mut db_transaction, transaction, self.conflict_policy).await?
             },
             TransactionType::Dispute => {
-                self.transactions_store.dispute_transaction(
-                    input_transaction.client,
-                    input_transaction.tx,
-                ).await?
+                MutableTransactionStore::dispute_transaction_on(&mut db_transaction, client, tx).await?
             },
             TransactionType::Resolve => {
-                self.transactions_store.resolve_dispute(
-                    input_transaction.client,
-                    input_transaction.tx,
-                ).await?
+                MutableTransactionStore::resolve_dispute_on(&mut db_transaction, client, tx).await?
             },
             TransactionType::Chargeback => {
-                self.transactions_store.chargeback_transaction(
-                    input_transaction.client,
-                    input_transaction.tx,
-                ).await?
+                MutableTransactionStore::chargeback_transaction_on(&mut db_transaction, client, tx).await?
+            },
+        };
+        if changed {
+            if is_insert(input_transaction) && self.pending_disputes {
+                MutableTransactionStore::apply_pending_dispute_on(&mut db_transaction, client, tx).await?;
+            }
+            self.update_account_balance_on(&mut db_transaction, client, tx).await?;
+            db_transaction.commit().await?;
+            return Ok(ApplyOutcome::Applied);
+        }
+        db_transaction.commit().await?;
+
+        let reason = match input_transaction.transaction_type {
+            // A disputed or charged back transaction isn't replaced, as that would change what is held or reversed.
+            TransactionType::Deposit | TransactionType::Withdrawal if self.conflict_policy == ConflictPolicy::Replace => {
+                let stored = MutableTransactionStore::get_transaction_on(conn, client, tx).await?;
+                match stored {
+                    Some(stored) if stored.charged_back => SkipReason::AlreadyChargedBack,
+                    Some(stored) if stored.disputed => SkipReason::AlreadyDisputed,
+                    _ => SkipReason::DuplicateTransaction,
+                }
+            },
+            TransactionType::Deposit | TransactionType::Withdrawal => SkipReason::DuplicateTransaction,
+            // The results are bound before matching on them, as the errors aren't `Send`,
+            // and so mustn't be held across an await for the future to be `Send`.
+            _ => {
+                let stored = MutableTransactionStore::get_transaction_on(conn, client, tx).await?;
+                match stored {
+                    None => {
+                        let owner = MutableTransactionStore::get_transaction_owner_on(conn, tx).await?;
+                        match owner {
+                            Some(owner) if self.strict_dispute_client => {
+                                return Err(format!("Transaction {} belongs to client {}, not client {}", tx, owner, client).into());
+                            },
+                            Some(_) => SkipReason::ClientMismatch,
+                            None if self.pending_disputes && input_transaction.transaction_type == TransactionType::Dispute => {
+                                MutableTransactionStore::add_pending_dispute_on(conn, client, tx).await?;
+                                return Ok(ApplyOutcome::Pending);
+                            },
+                            None => {
+                                let known_client = MutableTransactionStore::client_exists_on(conn, client).await?;
+                                if known_client { SkipReason::UnknownTransaction } else { SkipReason::UnknownClient }
+                            },
+                        }
+                    },
+                    Some(stored) if stored.charged_back => SkipReason::AlreadyChargedBack,
+                    Some(_) if input_transaction.transaction_type == TransactionType::Dispute => SkipReason::AlreadyDisputed,
+                    Some(_) => SkipReason::NotDisputed,
+                }
             },
+        };
+        Ok(ApplyOutcome::Skipped(reason))
+    }
+
+    // The clients with an account locked by a chargeback, without computing their accounts.
+    // A client is included if any of its accounts is locked, even if those in other currencies aren't.
+    // Accounts locked by `WithdrawalPolicy::RejectAndFlag` are only locked when computed, so they are not included.
+    pub async fn get_locked_clients(&self) -> impl Stream<Item = Result<ClientID, sqlx::Error>> + '_ {
+        self.transactions_store.get_locked_clients().await
+    }
+
+    // The number of deposits and withdrawals of each client, without reading the transactions themselves.
+    // Disputes, resolves and chargebacks update the transaction they refer to, so they aren't counted.
+    pub async fn get_transaction_counts(&self) -> impl Stream<Item = Result<TransactionCount, sqlx::Error>> + '_ {
+        self.transactions_store.get_transaction_counts().await
+    }
+
+    // The ordinal of the last deposit or withdrawal stored, if any.
+    pub async fn max_ordinal(&self) -> Result<Option<i64>, Box<dyn Error>> {
+        self.transactions_store.get_max_ordinal().await
+    }
+
+    // Whether any account is in a currency other than the default, e.g. to decide the columns of the output.
+    pub async fn has_currencies(&self) -> Result<bool, Box<dyn Error>> {
+        self.transactions_store.has_currencies().await
+    }
+
+    // The account for the client in the default currency.
+    pub async fn get_account_for_client(&self, client_id: u32) -> Result<OutputAccount, Box<dyn Error>> {
+        self.get_account(client_id, DEFAULT_CURRENCY).await
+    }
+
+    pub async fn get_account(&self, client_id: u32, currency: &str) -> Result<OutputAccount, Box<dyn Error>> {
+        let key = (client_id, currency.to_string());
+        if let Some(account_cache) = &self.account_cache {
+            if let Some(account) = account_cache.lock().unwrap().get(&key) {
+                return Ok(account.clone());
+            }
+        }
+        let account = match self.read_account_balance(client_id, currency).await? {
+            Some(account) => account,
+            None => self.replay_account(client_id, currency).await?,
+        };
+        if let Some(account_cache) = &self.account_cache {
+            account_cache.lock().unwrap().insert(key, account.clone());
+        }
+        Ok(account)
+    }
+
+    // The stored running balance of the account, if it is valid for these replay options.
+    // Accounts as of an earlier ordinal are always replayed, as only the current balance is stored.
+    async fn read_account_balance(&self, client_id: u32, currency: &str) -> Result<Option<OutputAccount>, Box<dyn Error>> {
+        if self.as_of.is_some() {
+            return Ok(None);
+        }
+        let balance = self.transactions_store.get_account_balance(client_id, currency).await?;
+        match balance {
+            Some(balance) if balance.replay_policy == self.replay_policy() => {
+                let mut account = balance_to_account(&balance)?;
+                self.finish_account(&mut account);
+                Ok(Some(account))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    // The options that change how transactions are replayed, which a stored balance must have been computed with.
+    fn replay_policy(&self) -> String {
+        format!("{:?}", (self.lock_halts_replay, self.ignore_disputes, self.negative_available_policy, self.withdrawal_policy))
+    }
+
+    // Bring the stored running balance of a changed transaction's account up to date, on the connection it was changed on.
+    // A deposit or withdrawal ordered after every other transaction of the account is applied to the balance on its own.
+    // Any other change replays the account, as it can change how the transactions ordered after it are applied.
+    async fn update_account_balance_on(&self, conn: &mut SqliteConnection, client_id: u32, transaction_id: u32) -> Result<(), Box<dyn Error>> {
+        let transaction = MutableTransactionStore::get_transaction_on(conn, client_id, transaction_id).await?
+            .ok_or_else(|| format!("Transaction {} of client {} isn't stored", transaction_id, client_id))?;
+        let currency = transaction.currency.clone();
+        let balance = MutableTransactionStore::get_account_balance_on(conn, client_id, &currency).await?;
+        let replay_policy = self.replay_policy();
+        let order = (transaction.timestamp, transaction.ordinal);
+        let balance = match balance {
+            Some(mut balance) if balance.replay_policy == replay_policy && order > (balance.last_timestamp, balance.last_ordinal) => {
+                if !balance.halted {
+                    let mut account = balance_to_account(&balance)?;
+                    let step = self.replay_step(&mut account, transaction);
+                    balance.halted = step == ReplayStep::ChargedBack && self.lock_halts_replay;
+                    balance.available = account.available.to_string();
+                    balance.held = account.held.to_string();
+                    balance.total = account.total.to_string();
+                    balance.locked = account.locked;
+                }
+                (balance.last_timestamp, balance.last_ordinal) = order;
+                balance
+            },
+            _ => {
+                let transactions = MutableTransactionStore::get_transactions_for_account_on(conn, client_id, &currency).await?;
+                self.replay_account_balance(client_id, &currency, transactions)
+            },
+        };
+        MutableTransactionStore::put_account_balance_on(conn, &balance).await
+    }
+
+    // Compute the running balance of an account from all of its transactions, in replay order,
+    // as `replay_account` does without `as_of`, but without rounding.
+    fn replay_account_balance(&self, client_id: u32, currency: &str, transactions: Vec<MutableTransaction>) -> AccountBalance {
+        let last = transactions.last().map(|transaction| (transaction.timestamp, transaction.ordinal));
+        let (last_timestamp, last_ordinal) = last.unwrap_or_default();
+        let mut account = empty_account(client_id, currency);
+        let mut halted = false;
+        for transaction in transactions {
+            if self.replay_step(&mut account, transaction) == ReplayStep::ChargedBack && self.lock_halts_replay {
+                halted = true;
+                break;
+            }
+        }
+        AccountBalance {
+            client_id,
+            currency: currency.into(),
+            available: account.available.to_string(),
+            held: account.held.to_string(),
+            total: account.total.to_string(),
+            locked: account.locked,
+            halted,
+            last_timestamp,
+            last_ordinal,
+            replay_policy: self.replay_policy(),
+        }
+    }
+
+    // Round the amounts of a computed account as configured.
+    fn finish_account(&self, account: &mut OutputAccount) {
+        account.round_columns(&self.column_scales, self.round_digits, self.round_mode);
+        if self.fixed_scale {
+            account.pad_columns(&self.column_scales, self.round_digits);
+        }
+    }
+
+    // The account of a client as it was when the transaction at `max_ordinal` was stored,
+    // replaying only the deposits and withdrawals stored up to then.
+    // Disputes, resolves and chargebacks are applied as they are now, as only the current state of a transaction is stored.
+    pub async fn get_account_for_client_as_of(&self, client_id: u32, max_ordinal: i64) -> Result<OutputAccount, Box<dyn Error>> {
+        self.replay_account_as_of(client_id, DEFAULT_CURRENCY, Some(max_ordinal)).await
+    }
+
+    // Compute the account by replaying all of the client's transactions in the currency,
+    // or only those up to the builder's `as_of` ordinal if it was given.
+    // When a chargeback halts the replay, the transactions after it aren't read at all.
+    pub async fn replay_account(&self, client_id: u32, currency: &str) -> Result<OutputAccount, Box<dyn Error>> {
+        self.replay_account_as_of(client_id, currency, self.as_of).await
+    }
+
+    async fn replay_account_as_of(&self, client_id: u32, currency: &str, max_ordinal: Option<i64>) -> Result<OutputAccount, Box<dyn Error>> {
+        let mut transactions = if let Some(max_ordinal) = max_ordinal {
+            self.transactions_store.get_transactions_for_account_as_of(client_id, currency, max_ordinal).await
+        } else if self.lock_halts_replay && !self.ignore_disputes {
+            self.transactions_store.get_transactions_for_account_until_lock(client_id, currency).await
+        } else {
+            self.transactions_store.get_transactions_for_account(client_id, currency).await
+        };
+        let mut account = empty_account(client_id, currency);
+        while let Some(transaction) = transactions.try_next().await? {
+            if self.replay_step(&mut account, transaction) == ReplayStep::ChargedBack && self.lock_halts_replay {
+                break;
+            }
+        }
+        self.finish_account(&mut account);
+        Ok(account)
+    }
+
+    // Apply a single stored transaction to the account being replayed.
+    fn replay_step(&self, account: &mut OutputAccount, mut transaction: MutableTransaction) -> ReplayStep {
+        if self.ignore_disputes {
+            transaction.disputed = false;
+            transaction.charged_back = false;
+        }
+        // A chargeback reverses the transaction: a charged back deposit is taken back,
+        // and a charged back withdrawal is refunded to the available funds,
+        // so the transaction doesn't change the balance either way.
+        if transaction.charged_back {
+            account.locked = true;
+            return ReplayStep::ChargedBack;
+        }
+        let transaction_amount = from_stored_amount(transaction.amount);
+
+        // A resolve clears the disputed flag, so a resolved transaction is applied like an undisputed one,
+        // which releases exactly the amount the dispute held.
+        // A disputed deposit is held instead of being available. A disputed withdrawal stays withdrawn from
+        // the available funds, as the money has left the account, and its amount is held as well, as a chargeback
+        // would refund it. So it is counted once, in held, and the total is what the account would have if refunded,
+        // e.g. deposit 100, withdraw 30 and dispute the withdrawal is available 70, held 30, total 100.
+        let mut new_held = account.held.clone();
+        let mut new_available = account.available.clone();
+        if transaction.disputed {
+            new_held += transaction_amount.abs();
+            if transaction_amount.is_negative() {
+                new_available += transaction_amount
+            }
+        } else {
+            new_available += transaction_amount
+        }
+        let new_total = new_available.clone() + new_held.clone();
+        if new_available < BigDecimal::zero() {
+            // Funds held by a dispute that were already spent are owed back by the client,
+            // which is a different matter from a withdrawal the account can't cover at all.
+            let overdraw = if new_total >= BigDecimal::zero() { Overdraw::HeldByDispute } else { Overdraw::Insufficient };
+            let allowed = overdraw == Overdraw::HeldByDispute
+                && self.negative_available_policy == NegativeAvailablePolicy::AllowWhenHeld;
+            if !allowed {
+                return self.overdraw_step(account, &transaction);
+            }
+        }
+
+        account.available = new_available;
+        account.held = new_held;
+        account.total = new_total;
+        ReplayStep::Applied
+    }
+
+    // Apply the withdrawal policy to a transaction that would overdraw the account.
+    fn overdraw_step(&self, account: &mut OutputAccount, transaction: &MutableTransaction) -> ReplayStep {
+        match self.withdrawal_policy {
+            WithdrawalPolicy::RejectWhole => ReplayStep::Overdrawn,
+            WithdrawalPolicy::AllowPartial => {
+                // Only an undisputed withdrawal can be partially applied, and only if there is something to withdraw.
+                if transaction.disputed || transaction.amount >= 0 || !account.available.is_positive() {
+                    return ReplayStep::Overdrawn;
+                }
+                account.total -= &account.available;
+                account.available = BigDecimal::zero();
+                ReplayStep::PartiallyApplied
+            },
+            WithdrawalPolicy::RejectAndFlag => {
+                account.locked = true;
+                ReplayStep::Flagged
+            },
+        }
+    }
+
+    // Replay the account of a transaction up to and including the transaction,
+    // to show what it did to the balance. Returns `None` if the client has no such transaction.
+    pub async fn explain(&self, client_id: u32, transaction_id: u32) -> Result<Option<Explanation>, Box<dyn Error>> {
+        let target = match self.transactions_store.get_transaction(client_id, transaction_id).await? {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        let mut transactions = self.transactions_store.get_transactions_for_account(client_id, &target.currency).await;
+        let mut account = empty_account(client_id, &target.currency);
+        let mut halted = false;
+        while let Some(transaction) = transactions.try_next().await? {
+            let before = account.clone();
+            let is_target = transaction.transaction_id == transaction_id;
+            let step = if halted {
+                ReplayStep::AfterLock
+            } else {
+                self.replay_step(&mut account, transaction)
+            };
+            halted |= step == ReplayStep::ChargedBack && self.lock_halts_replay;
+            if is_target {
+                return Ok(Some(Explanation {
+                    client: client_id,
+                    tx: transaction_id,
+                    amount: from_stored_amount(target.amount),
+                    disputed: target.disputed,
+                    charged_back: target.charged_back,
+                    step,
+                    before,
+                    after: account,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    // Write every stored transaction to `output` as CSV, returning the number of transactions written.
+    pub async fn dump_transactions<W: Write>(&self, output: W) -> Result<usize, Box<dyn Error>> {
+        let mut writer = TransactionDumpWriter::new(output);
+        let mut transactions = self.transactions_store.get_all_transactions().await;
+        let mut written = 0;
+        while let Some(transaction) = transactions.try_next().await? {
+            writer.write_transaction(&transaction)?;
+            written += 1;
+        }
+        writer.finish()?;
+        Ok(written)
+    }
+
+    // The clients with funds held by disputes, and the total held across all accounts.
+    pub async fn held_report(&self) -> Result<HeldReport, Box<dyn Error>> {
+        let mut report = HeldReport::default();
+        let accounts = self.clone().get_accounts().await;
+        pin_mut!(accounts);
+        while let Some(account) = accounts.try_next().await? {
+            if account.held.is_positive() {
+                report.total += &account.held;
+                report.entries.push((account.client, account.held));
+            }
+        }
+        Ok(report)
+    }
+
+    // Re-derive every account from the stored transactions, including those stored by previous runs,
+    // ignoring any accounts computed before, and the stored running balances.
+    // The accounts are limited to `max_clients` clients and ordered as with `get_accounts_for_clients`,
+    // or as with `get_accounts_grouped_by_locked` if `group_by_locked` is set.
+    pub async fn recompute_all(self, max_clients: Option<u32>, group_by_locked: bool) -> impl Stream<Item = Result<OutputAccount, Box<dyn Error>>> {
+        if let Some(account_cache) = &self.account_cache {
+            account_cache.lock().unwrap().clear();
+        }
+        try_stream! {
+            let mut account_keys = match (max_clients, group_by_locked) {
+                (_, true) => self.transactions_store.get_account_keys_by_locked(max_clients).await,
+                (Some(max_clients), false) => self.transactions_store.get_account_keys_for_clients(max_clients).await,
+                (None, false) => self.transactions_store.get_account_keys().await,
+            };
+            while let Some(key) = account_keys.try_next().await? {
+                let account = self.replay_account(key.client_id, &key.currency).await?;
+                yield account;
+            }
+        }
+    }
+
+    pub async fn get_accounts(self) -> impl Stream<Item = Result<OutputAccount, Box<dyn Error>>> {
+        self.get_accounts_for_clients(None).await
+    }
+
+    // The accounts of at most `max_clients` clients, or of all clients if not given.
+    // Which clients are included depends on the order the database returns them in.
+    pub async fn get_accounts_for_clients(self, max_clients: Option<u32>) -> impl Stream<Item = Result<OutputAccount, Box<dyn Error>>> {
+        try_stream! {
+            let mut account_keys = match max_clients {
+                Some(max_clients) => self.transactions_store.get_account_keys_for_clients(max_clients).await,
+                None => self.transactions_store.get_account_keys().await,
+            };
+            while let Some(key) = account_keys.try_next().await? {
+                let account = self.get_account(key.client_id, &key.currency).await?;
+                yield account;
+            }
+        }
+    }
+
+    // The accounts that aren't locked by a chargeback, followed by those that are, each in client and currency order.
+    // Each account goes by the chargebacks in its own currency, so a client's accounts can be in both groups.
+    // As with `get_locked_clients`, accounts locked by `WithdrawalPolicy::RejectAndFlag` are ordered with the unlocked ones.
+    pub async fn get_accounts_grouped_by_locked(self, max_clients: Option<u32>) -> impl Stream<Item = Result<OutputAccount, Box<dyn Error>>> {
+        try_stream! {
+            let mut account_keys = self.transactions_store.get_account_keys_by_locked(max_clients).await;
+            while let Some(key) = account_keys.try_next().await? {
+                let account = self.get_account(key.client_id, &key.currency).await?;
+                yield account;
+            }
+        }
+    }
+}
+
+ #[cfg(test)]
+ mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    use crate::transactions_store::AccountKey;
+
+    async fn run_test_scenario(transactions: Vec<InputTransaction>, expected_accounts: HashSet<OutputAccount>) {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        for transaction in transactions {
+            engine.add_input(transaction).await.unwrap();
+        }
+        let actual_accounts: HashSet<OutputAccount> = engine.get_accounts().await.try_collect().await.unwrap();
+        assert_eq!(actual_accounts, expected_accounts)
+    }
+
+    #[tokio::test]
+    async fn test_amounts_are_normalized() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Deposit,     1,  1, Some("10")).unwrap()).await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Deposit,     1,  2, Some("10.0")).unwrap()).await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Deposit,     1,  3, Some("10.0000")).unwrap()).await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Deposit,     1,  4, Some("9.99999")).unwrap()).await.unwrap();
+
+        let amounts: Vec<i64> = engine.transactions_store.get_transactions_for_client(1).await
+            .map_ok(|t| t.amount)
+            .try_collect().await.unwrap();
+        assert_eq!(amounts, vec![100000, 100000, 100000, 100000]);
+    }
+
+    #[tokio::test]
+    async fn test_on_transaction_outcomes() {
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = outcomes.clone();
+        let engine = Transactions::builder("sqlite::memory:")
+            .on_transaction(move |transaction, outcome| {
+                recorded.lock().unwrap().push((transaction.tx, outcome));
+            })
+            .build().await.unwrap();
+        let transactions = vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Resolve,     1,  1, None).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  1, None).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  1, None).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  2, None).unwrap(),
+            InputTransaction::new(TransactionType::Chargeback,  1,  1, None).unwrap(),
+        ];
+        for transaction in transactions {
+            engine.add_input(transaction).await.unwrap();
+        }
+
+        let expected = vec![
+            (1, ApplyOutcome::Applied),
+            (1, ApplyOutcome::Skipped(SkipReason::DuplicateTransaction)),
+            (1, ApplyOutcome::Skipped(SkipReason::NotDisputed)),
+            (1, ApplyOutcome::Applied),
+            (1, ApplyOutcome::Skipped(SkipReason::AlreadyDisputed)),
+            (2, ApplyOutcome::Skipped(SkipReason::UnknownTransaction)),
+            (1, ApplyOutcome::Applied),
+        ];
+        let actual = outcomes.lock().unwrap().clone();
+        assert_eq!(actual, expected)
+    }
+
+    #[tokio::test]
+    async fn test_validation_rule() {
+        let limit = BigDecimal::from(10000);
+        let engine = Transactions::builder("sqlite::memory:")
+            .validation_rule(move |transaction| match (&transaction.transaction_type, &transaction.amount) {
+                (TransactionType::Withdrawal, Some(amount)) if amount > &limit => {
+                    ValidationResult::Reject("withdrawal over 10000".into())
+                },
+                _ => ValidationResult::Accept,
+            })
+            .build().await.unwrap();
+        let inputs = vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("20000")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  1,  2, Some("15000")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  1,  3, Some("5000")).unwrap(),
+        ];
+        let mut outcomes = Vec::new();
+        for input in inputs {
+            outcomes.push(engine.add_input(input).await.unwrap());
+        }
+
+        assert_eq!(outcomes, vec![
+            ApplyOutcome::Applied,
+            ApplyOutcome::Skipped(SkipReason::Rejected("withdrawal over 10000".into())),
+            ApplyOutcome::Applied,
+        ]);
+        assert_eq!(SkipReason::Rejected("withdrawal over 10000".into()).to_string(), "rejected: withdrawal over 10000");
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "15000", "0", "15000", false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_dispute_after_chargeback() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        let inputs = vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     1,  2, Some("20")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  2, None).unwrap(),
+            InputTransaction::new(TransactionType::Chargeback,  1,  2, None).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  2, None).unwrap(),
+            InputTransaction::new(TransactionType::Resolve,     1,  2, None).unwrap(),
+        ];
+        let outcomes = engine.add_inputs(inputs).await.unwrap();
+        assert_eq!(&outcomes[4..], [
+            ApplyOutcome::Skipped(SkipReason::AlreadyChargedBack),
+            ApplyOutcome::Skipped(SkipReason::AlreadyChargedBack),
+        ]);
+        assert_eq!(SkipReason::AlreadyChargedBack.to_string(), "already charged back");
+
+        // The second dispute holds nothing, and the account stays locked.
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "100", "0", "100", true).unwrap());
+        assert!(!engine.transactions_store.get_transaction(1, 2).await.unwrap().unwrap().disputed);
+    }
+
+    #[tokio::test]
+    async fn test_add_inputs_from_stream() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        let inputs = futures_util::stream::iter(vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     2,  2, Some("50")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  1,  3, Some("30")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     2,  2, None).unwrap(),
+        ]);
+        assert_eq!(engine.add_inputs_from_stream(inputs).await.unwrap(), 4);
+
+        let accounts: HashSet<OutputAccount> = engine.clone().get_accounts().await.try_collect().await.unwrap();
+        assert_eq!(accounts, HashSet::from([
+            OutputAccount::new(1, "70", "0", "70", false).unwrap(),
+            OutputAccount::new(2, "0", "50", "50", false).unwrap(),
+        ]));
+
+        // An async producer, rather than one that is ready up front.
+        let inputs = futures_util::stream::unfold(4, |tx| async move {
+            tokio::task::yield_now().await;
+            let input = InputTransaction::new(TransactionType::Deposit, 3, tx, Some("1")).unwrap();
+            (tx < 7).then_some((input, tx + 1))
+        });
+        assert_eq!(engine.add_inputs_from_stream(inputs).await.unwrap(), 3);
+        assert_eq!(engine.get_account_for_client(3).await.unwrap(), OutputAccount::new(3, "3", "0", "3", false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_add_inputs_atomically() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        let first_client = vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  1,  2, Some("30")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  9, None).unwrap(),
+        ];
+        let outcomes = engine.add_inputs_atomically(&first_client).await.unwrap();
+        assert_eq!(outcomes, vec![ApplyOutcome::Applied, ApplyOutcome::Applied, ApplyOutcome::Skipped(SkipReason::UnknownTransaction)]);
+
+        // The deposit without an amount fails the batch after the first two inputs were stored.
+        let mut failing = InputTransaction::new(TransactionType::Deposit, 2, 5, Some("1")).unwrap();
+        failing.amount = None;
+        let second_client = vec![
+            InputTransaction::new(TransactionType::Deposit,     2,  3, Some("50")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     2,  3, None).unwrap(),
+            failing,
+            InputTransaction::new(TransactionType::Deposit,     2,  4, Some("50")).unwrap(),
+        ];
+        assert!(engine.add_inputs_atomically(&second_client).await.is_err());
+
+        let accounts: HashSet<OutputAccount> = engine.clone().get_accounts().await.try_collect().await.unwrap();
+        assert_eq!(accounts, HashSet::from([OutputAccount::new(1, "70", "0", "70", false).unwrap()]));
+        let stored: Vec<u32> = engine.transactions_store.get_transactions_for_client(2).await
+            .map_ok(|transaction| transaction.transaction_id)
+            .try_collect().await.unwrap();
+        assert!(stored.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_account_as_of() {
+        let inputs = || vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     2,  2, Some("10")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  1,  3, Some("30")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     1,  4, Some("5")).unwrap(),
+        ];
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        for input in inputs() {
+            engine.add_input(input).await.unwrap();
+        }
+        assert_eq!(engine.get_account_for_client_as_of(1, 0).await.unwrap(), OutputAccount::new(1, "0", "0", "0", false).unwrap());
+        assert_eq!(engine.get_account_for_client_as_of(1, 2).await.unwrap(), OutputAccount::new(1, "100", "0", "100", false).unwrap());
+        assert_eq!(engine.get_account_for_client_as_of(1, 3).await.unwrap(), OutputAccount::new(1, "70", "0", "70", false).unwrap());
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "75", "0", "75", false).unwrap());
+
+        let engine = Transactions::builder("sqlite::memory:").as_of(Some(1)).build().await.unwrap();
+        for input in inputs() {
+            engine.add_input(input).await.unwrap();
+        }
+        let accounts: HashSet<OutputAccount> = engine.get_accounts().await.try_collect().await.unwrap();
+        assert_eq!(accounts, HashSet::from([
+            OutputAccount::new(1, "100", "0", "100", false).unwrap(),
+            OutputAccount::new(2, "0", "0", "0", false).unwrap(),
+        ]));
+    }
+
+    // Producers adding the transactions of different clients at the same time, on several threads.
+    async fn run_concurrent_producers(engine: Transactions) {
+        let producers = 8u32;
+        let deposits = 25u32;
+        let tasks: Vec<_> = (1..=producers).map(|client| {
+            let engine = engine.clone();
+            tokio::spawn(async move {
+                for i in 0..deposits {
+                    let tx = client * 1000 + i;
+                    let deposit = InputTransaction::new(TransactionType::Deposit, client, tx, Some("10")).unwrap();
+                    assert_eq!(engine.add_input(deposit).await.unwrap(), ApplyOutcome::Applied);
+                    if i % 5 == 0 {
+                        let withdrawal = InputTransaction::new(TransactionType::Withdrawal, client, tx + 500, Some("2.5")).unwrap();
+                        assert_eq!(engine.add_input(withdrawal).await.unwrap(), ApplyOutcome::Applied);
+                    }
+                }
+                let dispute = InputTransaction::new(TransactionType::Dispute, client, client * 1000 + deposits - 1, None).unwrap();
+                assert_eq!(engine.add_input(dispute).await.unwrap(), ApplyOutcome::Applied);
+            })
+        }).collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let accounts: HashSet<OutputAccount> = engine.get_accounts().await.try_collect().await.unwrap();
+        let expected: HashSet<OutputAccount> = (1..=producers)
+            .map(|client| OutputAccount::new(client, "227.5", "10", "237.5", false).unwrap())
+            .collect();
+        assert_eq!(accounts, expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_producers() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite://{}", dir.path().join("transactions.db").display());
+        run_concurrent_producers(Transactions::new(&url).await.unwrap()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_producers_in_memory() {
+        run_concurrent_producers(Transactions::new("sqlite::memory:").await.unwrap()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_duplicates() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        let tasks: Vec<_> = (0..8).map(|_| {
+            let engine = engine.clone();
+            tokio::spawn(async move {
+                let deposit = InputTransaction::new(TransactionType::Deposit, 1, 1, Some("10")).unwrap();
+                engine.add_input(deposit).await.unwrap()
+            })
+        }).collect();
+        let mut outcomes = Vec::new();
+        for task in tasks {
+            outcomes.push(task.await.unwrap());
+        }
+        assert_eq!(outcomes.iter().filter(|outcome| **outcome == ApplyOutcome::Applied).count(), 1);
+        assert_eq!(
+            engine.get_account_for_client(1).await.unwrap(),
+            OutputAccount::new(1, "10", "0", "10", false).unwrap(),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_ordinals() {
+        let engine = Transactions::builder("sqlite::memory:").deterministic_ordinals(true).pool_size(Some(4)).build().await.unwrap();
+        let batch = |client, first_tx| vec![
+            InputTransaction::new(TransactionType::Deposit,     client, first_tx,     Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     client, first_tx,     None).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  client, first_tx + 1, Some("30")).unwrap(),
+            InputTransaction::new(TransactionType::Resolve,     client, first_tx,     None).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     client, first_tx + 2, Some("5")).unwrap(),
+        ];
+        let (first, second) = futures_util::join!(engine.add_inputs(batch(1, 1)), engine.add_inputs(batch(2, 11)));
+        assert!(first.unwrap().iter().chain(&second.unwrap()).all(|outcome| *outcome == ApplyOutcome::Applied));
+
+        let singles = (21..26).map(|tx| engine.add_input(InputTransaction::new(TransactionType::Deposit, 3, tx, Some("1")).unwrap()));
+        for outcome in futures_util::future::join_all(singles).await {
+            assert_eq!(outcome.unwrap(), ApplyOutcome::Applied);
+        }
+
+        let transactions: Vec<(i64, u32)> = engine.transactions_store.get_all_transactions().await
+            .map_ok(|transaction| (transaction.ordinal, transaction.transaction_id))
+            .try_collect().await.unwrap();
+        assert_eq!(transactions, vec![
+            (1, 1), (2, 2), (3, 3), (4, 11), (5, 12), (6, 13), (7, 21), (8, 22), (9, 23), (10, 24), (11, 25),
+        ]);
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "75", "0", "75", false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_pending_dispute_before_deposit() {
+        let engine = Transactions::builder("sqlite::memory:").pending_disputes(true).build().await.unwrap();
+        let dispute = InputTransaction::new(TransactionType::Dispute, 1, 1, None).unwrap();
+        assert_eq!(engine.add_input(dispute).await.unwrap(), ApplyOutcome::Pending);
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "0", "0", "0", false).unwrap());
+
+        engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, 1, Some("100")).unwrap()).await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, 2, Some("20")).unwrap()).await.unwrap();
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "20", "100", "120", false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_dispute_before_deposit_without_pending_disputes() {
+        run_test_scenario(
+            vec![
+                InputTransaction::new(TransactionType::Dispute,     1,  1, None).unwrap(),
+                InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            ], 
+            HashSet::from([
+                OutputAccount::new(1, "100", "0", "100", false).unwrap(),
+            ])
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_chargeback_refunds_debit() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        let inputs = vec![
+            (InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(), ("100", "0", "100", false)),
+            (InputTransaction::new(TransactionType::Withdrawal,  1,  2, Some("30")).unwrap(),  ("70", "0", "70", false)),
+            (InputTransaction::new(TransactionType::Dispute,     1,  2, None).unwrap(),        ("70", "30", "100", false)),
+            (InputTransaction::new(TransactionType::Chargeback,  1,  2, None).unwrap(),        ("100", "0", "100", true)),
+        ];
+        for (input, (available, held, total, locked)) in inputs {
+            engine.add_input(input).await.unwrap();
+            let expected = OutputAccount::new(1, available, held, total, locked).unwrap();
+            assert_eq!(engine.get_account_for_client(1).await.unwrap(), expected);
         }
-        Ok(())
     }
 
-    pub async fn get_account_for_client(&self, client_id: u16) -> Result<OutputAccount, Box<dyn Error>> {
-        let mut transactions = self.transactions_store.get_transactions_for_client(client_id).await;
-        let mut account = OutputAccount{
-            client: client_id,
-            available: BigDecimal::zero(),
-            held: BigDecimal::zero(),
-            total: BigDecimal::zero(),
-            locked: false,
-        };
-        while let Some(transaction) = transactions.try_next().await? {
-            if transaction.charged_back {
-                account.locked = true;
-                return Ok(account)
-            }
-            let transaction_amount = BigDecimal::from_str(&transaction.amount)?;
-
-            let mut new_held = account.held.clone();
-            let mut new_available = account.available.clone();
-            if transaction.disputed {
-                new_held += transaction_amount.abs();
-                if transaction_amount.is_negative() {
-                    new_available += transaction_amount
-                }
-            } else {
-                new_available += transaction_amount
-            }
-            let new_total = new_available.clone() + new_held.clone();
-            if new_available < BigDecimal::zero() {
-                continue;
+    #[tokio::test]
+    async fn test_disputed_withdrawal_accounting() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        // The same withdrawal dispute, followed by a resolve for client 1, and a chargeback for client 2.
+        let steps = |client, tx, follow_up| vec![
+            (InputTransaction::new(TransactionType::Deposit,     client, tx, Some("100")).unwrap(), ("100", "0", "100", false)),
+            (InputTransaction::new(TransactionType::Withdrawal,  client, tx + 1, Some("30")).unwrap(),  ("70", "0", "70", false)),
+            (InputTransaction::new(TransactionType::Dispute,     client, tx + 1, None).unwrap(),        ("70", "30", "100", false)),
+            match follow_up {
+                TransactionType::Resolve =>
+                    (InputTransaction::new(TransactionType::Resolve, client, tx + 1, None).unwrap(),    ("70", "0", "70", false)),
+                _ =>
+                    (InputTransaction::new(TransactionType::Chargeback, client, tx + 1, None).unwrap(), ("100", "0", "100", true)),
+            },
+        ];
+        for (client, tx, follow_up) in [(1, 1, TransactionType::Resolve), (2, 3, TransactionType::Chargeback)] {
+            for (input, (available, held, total, locked)) in steps(client, tx, follow_up) {
+                engine.add_input(input).await.unwrap();
+                let account = engine.get_account_for_client(client).await.unwrap();
+                assert_eq!(account, OutputAccount::new(client, available, held, total, locked).unwrap());
+                assert_eq!(account.check_invariants(), Ok(()));
             }
+        }
+    }
 
-            account.available = new_available;
-            account.held = new_held;
-            account.total = new_total;
+    #[tokio::test]
+    async fn test_withdrawal_chargeback_with_replay_after_lock() {
+        let engine = Transactions::builder("sqlite::memory:").lock_halts_replay(false).build().await.unwrap();
+        let inputs = vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  1,  2, Some("30")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  1,  3, Some("20")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  2, None).unwrap(),
+            InputTransaction::new(TransactionType::Chargeback,  1,  2, None).unwrap(),
+        ];
+        for input in inputs {
+            engine.add_input(input).await.unwrap();
         }
-        account.round_amounts(4);
-        Ok(account)
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "80", "0", "80", true).unwrap());
     }
 
-    pub async fn get_accounts(self) -> impl Stream<Item = Result<OutputAccount, Box<dyn Error>>> {
-        try_stream! {
-            let mut client_ids = self.transactions_store.get_clients().await;
-            while let Some(row) = client_ids.try_next().await? {
-                let client_id = row.client_id;
+    #[tokio::test]
+    async fn test_lock_halts_replay() {
+        let transactions = || vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     1,  2, Some("50")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  1, None).unwrap(),
+            InputTransaction::new(TransactionType::Chargeback,  1,  1, None).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     1,  3, Some("20")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  1,  4, Some("5")).unwrap(),
+        ];
 
-                let account = self.get_account_for_client(client_id).await?;
-                yield account;
+        let halting = Transactions::new("sqlite::memory:").await.unwrap();
+        let continuing = Transactions::builder("sqlite::memory:").lock_halts_replay(false).build().await.unwrap();
+        for (first, second) in transactions().into_iter().zip(transactions()) {
+            halting.add_input(first).await.unwrap();
+            continuing.add_input(second).await.unwrap();
+        }
+
+        assert_eq!(halting.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "0", "0", "0", true).unwrap());
+        assert_eq!(continuing.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "65", "0", "65", true).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_ignore_disputes() {
+        let transactions = || vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     1,  2, Some("50")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  2, None).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     2,  3, Some("10")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     2,  3, None).unwrap(),
+            InputTransaction::new(TransactionType::Chargeback,  2,  3, None).unwrap(),
+        ];
+
+        let adjusted = Transactions::new("sqlite::memory:").await.unwrap();
+        let gross = Transactions::builder("sqlite::memory:").ignore_disputes(true).build().await.unwrap();
+        for (first, second) in transactions().into_iter().zip(transactions()) {
+            adjusted.add_input(first).await.unwrap();
+            gross.add_input(second).await.unwrap();
+        }
+
+        assert_eq!(adjusted.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "100", "50", "150", false).unwrap());
+        assert_eq!(gross.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "150", "0", "150", false).unwrap());
+        assert_eq!(adjusted.get_account_for_client(2).await.unwrap(), OutputAccount::new(2, "0", "0", "0", true).unwrap());
+        assert_eq!(gross.get_account_for_client(2).await.unwrap(), OutputAccount::new(2, "10", "0", "10", false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_many_small_amounts() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        for tx in 0..100_000 {
+            engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, tx, Some("0.0001")).unwrap()).await.unwrap();
+        }
+        let account = engine.get_account_for_client(1).await.unwrap();
+        assert_eq!(account.total.to_string(), "10.0000");
+        assert_eq!(account, OutputAccount::new(1, "10", "0", "10", false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_large_amounts() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        for tx in 0..20 {
+            engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, tx, Some("900000000000000.0001")).unwrap()).await.unwrap();
+        }
+        engine.add_input(InputTransaction::new(TransactionType::Withdrawal, 1, 20, Some("0.0019")).unwrap()).await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Dispute, 1, 0, None).unwrap()).await.unwrap();
+        let expected = OutputAccount::new(1, "17100000000000000.0000", "900000000000000.0001", "18000000000000000.0001", false).unwrap();
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), expected);
+
+        // Amounts that can't be stored exactly are rejected rather than losing precision.
+        let too_large = InputTransaction::new(TransactionType::Deposit, 1, 21, Some("1000000000000000")).unwrap();
+        assert!(engine.add_input(too_large).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_policy() {
+        let inputs = || vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  1,  2, Some("150")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     1,  3, Some("20")).unwrap(),
+        ];
+        let expected = [
+            (WithdrawalPolicy::RejectWhole, ReplayStep::Overdrawn, OutputAccount::new(1, "120", "0", "120", false).unwrap()),
+            (WithdrawalPolicy::AllowPartial, ReplayStep::PartiallyApplied, OutputAccount::new(1, "20", "0", "20", false).unwrap()),
+            (WithdrawalPolicy::RejectAndFlag, ReplayStep::Flagged, OutputAccount::new(1, "120", "0", "120", true).unwrap()),
+        ];
+        for (withdrawal_policy, step, account) in expected {
+            let engine = Transactions::builder("sqlite::memory:").withdrawal_policy(withdrawal_policy).build().await.unwrap();
+            for input in inputs() {
+                engine.add_input(input).await.unwrap();
             }
+            assert_eq!(engine.explain(1, 2).await.unwrap().unwrap().step, step, "{:?}", withdrawal_policy);
+            assert_eq!(engine.get_account_for_client(1).await.unwrap(), account, "{:?}", withdrawal_policy);
         }
     }
-}
 
- #[cfg(test)]
- mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn test_partial_withdrawal_of_empty_account() {
+        let engine = Transactions::builder("sqlite::memory:").withdrawal_policy(WithdrawalPolicy::AllowPartial).build().await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Withdrawal, 1, 1, Some("10")).unwrap()).await.unwrap();
+        assert_eq!(engine.explain(1, 1).await.unwrap().unwrap().step, ReplayStep::Overdrawn);
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "0", "0", "0", false).unwrap());
+    }
 
-    use std::collections::HashSet;
+    #[tokio::test]
+    async fn test_dispute_after_withdrawal_is_not_an_overdraw() {
+        let engine = Transactions::builder("sqlite::memory:")
+            .negative_available_policy(NegativeAvailablePolicy::AllowWhenHeld)
+            .build().await.unwrap();
+        let inputs = vec![
+            // The deposit is disputed after most of it was withdrawn, so the client owes the difference.
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  1,  2, Some("80")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  1, None).unwrap(),
+            // A plain over-withdrawal is still rejected.
+            InputTransaction::new(TransactionType::Deposit,     2,  3, Some("10")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  2,  4, Some("30")).unwrap(),
+            // As is a withdrawal of more than the client has, even counting the held funds.
+            InputTransaction::new(TransactionType::Withdrawal,  1,  5, Some("50")).unwrap(),
+        ];
+        for input in inputs {
+            engine.add_input(input).await.unwrap();
+        }
 
-    async fn run_test_scenario(transactions: Vec<InputTransaction>, expected_accounts: HashSet<OutputAccount>) {
+        assert_eq!(engine.explain(1, 2).await.unwrap().unwrap().step, ReplayStep::Applied);
+        assert_eq!(engine.explain(1, 5).await.unwrap().unwrap().step, ReplayStep::Overdrawn);
+        assert_eq!(engine.explain(2, 4).await.unwrap().unwrap().step, ReplayStep::Overdrawn);
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "-80", "100", "20", false).unwrap());
+        assert_eq!(engine.get_account_for_client(2).await.unwrap(), OutputAccount::new(2, "10", "0", "10", false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_negative_available_policy() {
+        let transactions = || vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  1,  2, Some("80")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  1, None).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  1,  3, Some("30")).unwrap(),
+        ];
+
+        let skipping = Transactions::new("sqlite::memory:").await.unwrap();
+        let allowing = Transactions::builder("sqlite::memory:")
+            .negative_available_policy(NegativeAvailablePolicy::AllowWhenHeld)
+            .build().await.unwrap();
+        for (first, second) in transactions().into_iter().zip(transactions()) {
+            skipping.add_input(first).await.unwrap();
+            allowing.add_input(second).await.unwrap();
+        }
+
+        // The dispute holds funds that were already withdrawn, so both withdrawals overdraw the available amount.
+        assert_eq!(skipping.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "0", "100", "100", false).unwrap());
+        // Only the first withdrawal is covered by the held funds.
+        assert_eq!(allowing.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "-80", "100", "20", false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_conflict_policy_replace() {
+        let engine = Transactions::builder("sqlite::memory:")
+            .cache(true)
+            .conflict_policy(ConflictPolicy::Replace)
+            .build().await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, 1, Some("100")).unwrap()).await.unwrap();
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "100", "0", "100", false).unwrap());
+
+        // The re-sent deposit replaces the first, and the cached account is recomputed.
+        let outcome = engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, 1, Some("40")).unwrap()).await.unwrap();
+        assert_eq!(outcome, ApplyOutcome::Applied);
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "40", "0", "40", false).unwrap());
+
+        // A disputed deposit isn't replaced, as the dispute holds its amount, and neither is a charged back one.
+        engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, 2, Some("10")).unwrap()).await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Dispute, 1, 2, None).unwrap()).await.unwrap();
+        let outcome = engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, 2, Some("30")).unwrap()).await.unwrap();
+        assert_eq!(outcome, ApplyOutcome::Skipped(SkipReason::AlreadyDisputed));
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "40", "10", "50", false).unwrap());
+        engine.add_input(InputTransaction::new(TransactionType::Chargeback, 1, 2, None).unwrap()).await.unwrap();
+        let outcome = engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, 2, Some("30")).unwrap()).await.unwrap();
+        assert_eq!(outcome, ApplyOutcome::Skipped(SkipReason::AlreadyChargedBack));
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "40", "0", "40", true).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_dump_transactions() {
         let engine = Transactions::new("sqlite::memory:").await.unwrap();
-        for transaction in transactions {
-            engine.add_input(transaction).await.unwrap();
+        let inputs = vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     1,  2, Some("50")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  2, None).unwrap(),
+            InputTransaction::new(TransactionType::Chargeback,  1,  2, None).unwrap(),
+        ];
+        for input in inputs {
+            engine.add_input(input).await.unwrap();
         }
-        let actual_accounts: HashSet<OutputAccount> = engine.get_accounts().await.try_collect().await.unwrap();
-        assert_eq!(actual_accounts, expected_accounts)
+
+        let mut output = Vec::new();
+        assert_eq!(engine.dump_transactions(&mut output).await.unwrap(), 2);
+        let dump = String::from_utf8(output).unwrap();
+        let rows: Vec<&str> = dump.lines().collect();
+        assert_eq!(rows[0], "ordinal,client,tx,amount,currency,disputed,charged_back,reversal_reason,reversed_at,run_id");
+        assert_eq!(rows[1], "1,1,1,100.0000,,false,false,,,");
+        assert!(rows[2].starts_with("2,1,2,50.0000,,false,true,chargeback,"), "{}", rows[2]);
+        assert!(rows[2].ends_with("Z,"), "{}", rows[2]);
+    }
+
+    #[tokio::test]
+    async fn test_held_report() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        let inputs = vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     1,  2, Some("20")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  1, None).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     2,  3, Some("50")).unwrap(),
+        ];
+        for input in inputs {
+            engine.add_input(input).await.unwrap();
+        }
+
+        let report = engine.held_report().await.unwrap();
+        assert_eq!(report.entries, vec![(1, BigDecimal::from(100))]);
+        assert_eq!(report.total, BigDecimal::from(100));
+    }
+
+    #[tokio::test]
+    async fn test_max_input_scale() {
+        let input = "
+            type,       client, tx, amount
+            deposit,    1,      1,  1.23456
+            deposit,    1,      2,  1.2345
+            withdrawal, 1,      3,  0.100000";
+        let inputs = || crate::input::parse_input_transaction(input.as_bytes()).map(|result| result.unwrap());
+
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        for transaction in inputs() {
+            assert_eq!(engine.add_input(transaction).await.unwrap(), ApplyOutcome::Applied);
+        }
+        let account = engine.get_account_for_client(1).await.unwrap();
+        assert_eq!(account.total, BigDecimal::from_str("2.3691").unwrap());
+
+        let engine = Transactions::builder("sqlite::memory:").max_input_scale(Some(4)).build().await.unwrap();
+        let mut outcomes = Vec::new();
+        for transaction in inputs() {
+            outcomes.push(engine.add_input(transaction).await.unwrap());
+        }
+        let excess_scale = ApplyOutcome::Skipped(SkipReason::ExcessScale { max_scale: 4 });
+        assert_eq!(outcomes, vec![excess_scale.clone(), ApplyOutcome::Applied, ApplyOutcome::Applied]);
+        let account = engine.get_account_for_client(1).await.unwrap();
+        assert_eq!(account.total, BigDecimal::from_str("1.1345").unwrap());
+        assert_eq!(SkipReason::ExcessScale { max_scale: 4 }.to_string(), "amount has more than 4 decimal places");
+    }
+
+    #[tokio::test]
+    async fn test_strict_input_scale() {
+        let deposit = |tx, amount| InputTransaction::new(TransactionType::Deposit, 1, tx, Some(amount)).unwrap();
+        // At the default of 4 decimal places.
+        let engine = Transactions::builder("sqlite::memory:").strict_input_scale(true).build().await.unwrap();
+        assert_eq!(engine.add_input(deposit(1, "1.2345")).await.unwrap(), ApplyOutcome::Applied);
+        let error = engine.add_input(deposit(2, "1.23456")).await.unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&TransactionError::ExcessScale { tx: 2, max_scale: 4 }));
+        assert_eq!(error.to_string(), "Amount of transaction 2 has more than 4 decimal places");
+        // Integers, and trailing zeros, are always accepted.
+        assert_eq!(engine.add_input(deposit(3, "100")).await.unwrap(), ApplyOutcome::Applied);
+        assert_eq!(engine.add_input(deposit(4, "2.50000000")).await.unwrap(), ApplyOutcome::Applied);
+        let account = engine.get_account_for_client(1).await.unwrap();
+        assert_eq!(account.total, BigDecimal::from_str("103.7345").unwrap());
+
+        let engine = Transactions::builder("sqlite::memory:").max_input_scale(Some(0)).strict_input_scale(true).build().await.unwrap();
+        assert_eq!(engine.add_input(deposit(1, "100")).await.unwrap(), ApplyOutcome::Applied);
+        let error = engine.add_input(deposit(2, "0.5")).await.unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&TransactionError::ExcessScale { tx: 2, max_scale: 0 }));
+    }
+
+    #[tokio::test]
+    async fn test_negative_amounts_skipped() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        let deposit = InputTransaction::new(TransactionType::Deposit, 1, 1, Some("-50")).unwrap();
+        let withdrawal = InputTransaction::new(TransactionType::Withdrawal, 1, 2, Some("-50")).unwrap();
+        assert_eq!(engine.add_input(deposit).await.unwrap(), ApplyOutcome::Skipped(SkipReason::NegativeAmount));
+        assert_eq!(engine.add_input(withdrawal).await.unwrap(), ApplyOutcome::Skipped(SkipReason::NegativeAmount));
+        let accounts: Vec<OutputAccount> = engine.get_accounts().await.try_collect().await.unwrap();
+        assert!(accounts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_explain() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        let inputs = vec![
+            InputTransaction::new(TransactionType::Deposit,     7,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     7,  2, Some("50")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  7,  3, Some("500")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     7,  2, None).unwrap(),
+        ];
+        for input in inputs {
+            engine.add_input(input).await.unwrap();
+        }
+
+        let explanation = engine.explain(7, 2).await.unwrap().unwrap();
+        assert_eq!(explanation.step, ReplayStep::Applied);
+        assert!(explanation.disputed);
+        assert_eq!(explanation.before, OutputAccount::new(7, "100", "0", "100", false).unwrap());
+        assert_eq!(explanation.after, OutputAccount::new(7, "100", "50", "150", false).unwrap());
+        assert_eq!(explanation.to_string(), "\
+Transaction 2 for client 7: amount 50.0000, disputed: true, charged back: false
+Outcome: applied
+Available: 100.0000 -> 100.0000
+Held: 0 -> 50.0000
+");
+
+        assert_eq!(engine.explain(7, 3).await.unwrap().unwrap().step, ReplayStep::Overdrawn);
+        assert_eq!(engine.explain(7, 4).await.unwrap(), None);
+        assert_eq!(engine.explain(8, 1).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_missing_amount() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        let dispute = InputTransaction::new(TransactionType::Dispute, 1, 1, None).unwrap();
+        assert_eq!(engine.add_input(dispute).await.unwrap(), ApplyOutcome::Skipped(SkipReason::UnknownClient));
+        let deposit = InputTransaction::new(TransactionType::Deposit, 1, 1, None).unwrap();
+        let error = engine.add_input(deposit).await.unwrap_err();
+        assert_eq!(error.to_string(), "Deposit must have an amount (transaction 1)");
+        assert_eq!(
+            error.downcast_ref::<TransactionError>(),
+            Some(&TransactionError::MissingAmount { tx: 1, transaction_type: TransactionType::Deposit }),
+        );
+        let withdrawal = InputTransaction::new(TransactionType::Withdrawal, 1, 2, None).unwrap();
+        let error = engine.add_input(withdrawal).await.unwrap_err();
+        assert_eq!(error.to_string(), "Withdrawal must have an amount (transaction 2)");
+        assert_eq!(
+            error.downcast_ref::<TransactionError>(),
+            Some(&TransactionError::MissingAmount { tx: 2, transaction_type: TransactionType::Withdrawal }),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_amount_too_large() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        let largest = InputTransaction::new(TransactionType::Deposit, 1, 1, Some("922337203685477.5807")).unwrap();
+        assert_eq!(engine.add_input(largest).await.unwrap(), ApplyOutcome::Applied);
+        let too_large = InputTransaction::new(TransactionType::Withdrawal, 1, 2, Some("922337203685477.5808")).unwrap();
+        let error = engine.add_input(too_large).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<TransactionError>(), Some(&TransactionError::AmountTooLarge { tx: 2 }));
+        assert_eq!(error.to_string(), "Amount of transaction 2 is larger than the maximum of 922337203685477.5807");
+        assert!(engine.transactions_store.get_transaction(1, 2).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_recompute_all_after_appends() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_url = format!("sqlite://{}", dir.path().join("transactions.db").display());
+
+        let first = Transactions::builder(&db_url).pending_disputes(true).build().await.unwrap();
+        first.add_input(InputTransaction::new(TransactionType::Deposit, 1, 1, Some("100")).unwrap()).await.unwrap();
+        first.add_input(InputTransaction::new(TransactionType::Dispute, 2, 2, None).unwrap()).await.unwrap();
+        first.close().await;
+
+        let second = Transactions::builder(&db_url).append(true).pending_disputes(true).build().await.unwrap();
+        second.add_input(InputTransaction::new(TransactionType::Deposit, 2, 2, Some("30")).unwrap()).await.unwrap();
+        second.add_input(InputTransaction::new(TransactionType::Withdrawal, 1, 3, Some("40")).unwrap()).await.unwrap();
+        second.close().await;
+
+        let recomputed = Transactions::builder(&db_url).append(true).cache(true).build().await.unwrap();
+        let accounts: HashSet<OutputAccount> = recomputed.clone().recompute_all(None, false).await.try_collect().await.unwrap();
+        recomputed.close().await;
+        assert_eq!(accounts, HashSet::from([
+            OutputAccount::new(1, "60", "0", "60", false).unwrap(),
+            OutputAccount::new(2, "0", "30", "30", false).unwrap(),
+        ]));
+    }
+
+    // Every account as read from its stored running balance, checked against replaying its transactions from scratch,
+    // including the scale of the amounts, which their `Debug` shows.
+    async fn assert_balances_match_replay(engine: &Transactions) {
+        let keys: Vec<AccountKey> = engine.transactions_store.get_account_keys().await.try_collect().await.unwrap();
+        for key in keys {
+            let stored = engine.read_account_balance(key.client_id, &key.currency).await.unwrap();
+            let replayed = engine.replay_account(key.client_id, &key.currency).await.unwrap();
+            assert_eq!(format!("{:?}", stored), format!("{:?}", Some(replayed)), "client {}", key.client_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_account_balances_match_replay() {
+        let at = |mut input: InputTransaction, timestamp| {
+            input.timestamp = Some(timestamp);
+            input
+        };
+        let inputs = vec![
+            InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     2,  2, Some("50")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  1,  3, Some("30")).unwrap(),
+            // Holding the deposit makes the earlier withdrawal overdraw the account when it is replayed.
+            InputTransaction::new(TransactionType::Dispute,     1,  1, None).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     1,  4, Some("20.25")).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  2,  5, Some("80")).unwrap(),
+            InputTransaction::new(TransactionType::Resolve,     1,  1, None).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,     2,  6, Some("10")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     2,  6, None).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal,  1,  7, Some("50")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,     1,  7, None).unwrap(),
+            InputTransaction::new(TransactionType::Chargeback,  2,  6, None).unwrap(),
+            // After the chargeback, so it doesn't change the locked account.
+            InputTransaction::new(TransactionType::Deposit,     2,  8, Some("5")).unwrap(),
+            at(InputTransaction::new(TransactionType::Deposit,     3,  9, Some("10")).unwrap(), 10),
+            at(InputTransaction::new(TransactionType::Withdrawal,  3, 10, Some("10")).unwrap(), 20),
+            // Ordered before the transactions stored before it.
+            at(InputTransaction::new(TransactionType::Deposit,     3, 11, Some("2.5")).unwrap(), 5),
+            at(InputTransaction::new(TransactionType::Withdrawal,  3, 12, Some("1")).unwrap(), 30),
+        ];
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        for input in inputs {
+            engine.add_input(input).await.unwrap();
+            assert_balances_match_replay(&engine).await;
+        }
+
+        let accounts: HashSet<OutputAccount> = engine.clone().get_accounts().await.try_collect().await.unwrap();
+        assert_eq!(accounts, HashSet::from([
+            OutputAccount::new(1, "40.25", "50", "90.25", false).unwrap(),
+            OutputAccount::new(2, "50", "0", "50", true).unwrap(),
+            OutputAccount::new(3, "1.5", "0", "1.5", false).unwrap(),
+        ]));
+        let recomputed: HashSet<OutputAccount> = engine.recompute_all(None, false).await.try_collect().await.unwrap();
+        assert_eq!(recomputed, accounts);
+    }
+
+    #[tokio::test]
+    async fn test_account_balances_with_other_replay_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_url = format!("sqlite://{}", dir.path().join("transactions.db").display());
+
+        let first = Transactions::new(&db_url).await.unwrap();
+        first.add_input(InputTransaction::new(TransactionType::Deposit,     1,  1, Some("10")).unwrap()).await.unwrap();
+        first.add_input(InputTransaction::new(TransactionType::Withdrawal,  1,  2, Some("15")).unwrap()).await.unwrap();
+        assert_eq!(first.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "10", "0", "10", false).unwrap());
+        first.close().await;
+
+        // The stored balance was computed without partial withdrawals, so the account is replayed instead.
+        let second = Transactions::builder(&db_url).append(true).withdrawal_policy(WithdrawalPolicy::AllowPartial).build().await.unwrap();
+        assert_eq!(second.read_account_balance(1, DEFAULT_CURRENCY).await.unwrap(), None);
+        assert_eq!(second.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "0", "0", "0", false).unwrap());
+
+        // A change replays the account with the new options, and stores its balance again.
+        second.add_input(InputTransaction::new(TransactionType::Deposit,    1,  3, Some("4")).unwrap()).await.unwrap();
+        assert_eq!(second.read_account_balance(1, DEFAULT_CURRENCY).await.unwrap(), Some(OutputAccount::new(1, "4", "0", "4", false).unwrap()));
+        assert_balances_match_replay(&second).await;
+        second.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_run_id_across_appends() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_url = format!("sqlite://{}", dir.path().join("transactions.db").display());
+
+        let first = Transactions::builder(&db_url).run_id(Some("monday".into())).build().await.unwrap();
+        first.add_input(InputTransaction::new(TransactionType::Deposit, 1, 1, Some("100")).unwrap()).await.unwrap();
+        first.close().await;
+
+        let second = Transactions::builder(&db_url).append(true).run_id(Some("tuesday".into())).build().await.unwrap();
+        second.add_input(InputTransaction::new(TransactionType::Deposit, 1, 2, Some("40")).unwrap()).await.unwrap();
+        // A dispute changes a transaction stored by the first run, which keeps its run ID.
+        second.add_input(InputTransaction::new(TransactionType::Dispute, 1, 1, None).unwrap()).await.unwrap();
+
+        let run_ids: Vec<(u32, Option<String>)> = second.transactions_store.get_transactions_for_client(1).await
+            .map_ok(|transaction| (transaction.transaction_id, transaction.run_id))
+            .try_collect().await.unwrap();
+        assert_eq!(run_ids, vec![(1, Some("monday".into())), (2, Some("tuesday".into()))]);
+        assert_eq!(second.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "40", "100", "140", false).unwrap());
+        second.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_timestamp_order() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        let inputs = vec![
+            InputTransaction::new(TransactionType::Withdrawal,  1,  1, Some("30")).unwrap().with_timestamp(100),
+            InputTransaction::new(TransactionType::Deposit,     1,  2, Some("50")).unwrap().with_timestamp(100),
+            InputTransaction::new(TransactionType::Withdrawal,  2,  3, Some("30")).unwrap().with_timestamp(100),
+            InputTransaction::new(TransactionType::Deposit,     2,  4, Some("50")).unwrap().with_timestamp(50),
+        ];
+        for input in inputs {
+            engine.add_input(input).await.unwrap();
+        }
+
+        // With equal timestamps, the withdrawal was received first, so it overdraws the account.
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "50", "0", "50", false).unwrap());
+        // The deposit was made before the withdrawal, even though it was received after it.
+        assert_eq!(engine.get_account_for_client(2).await.unwrap(), OutputAccount::new(2, "20", "0", "20", false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_client_and_transaction() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, 1, Some("100")).unwrap()).await.unwrap();
+
+        for transaction_type in [TransactionType::Dispute, TransactionType::Resolve, TransactionType::Chargeback] {
+            let unknown_transaction = InputTransaction::new(transaction_type, 1, 2, None).unwrap();
+            assert_eq!(engine.add_input(unknown_transaction).await.unwrap(), ApplyOutcome::Skipped(SkipReason::UnknownTransaction));
+            let unknown_client = InputTransaction::new(transaction_type, 2, 2, None).unwrap();
+            assert_eq!(engine.add_input(unknown_client).await.unwrap(), ApplyOutcome::Skipped(SkipReason::UnknownClient));
+        }
+        assert_eq!(SkipReason::UnknownClient.to_string(), "unknown client");
+
+        // A dispute for a client without transactions is still held with pending disputes, as the transaction may come later.
+        let engine = Transactions::builder("sqlite::memory:").pending_disputes(true).build().await.unwrap();
+        let dispute = InputTransaction::new(TransactionType::Dispute, 2, 2, None).unwrap();
+        assert_eq!(engine.add_input(dispute).await.unwrap(), ApplyOutcome::Pending);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_client_mismatch() {
+        let deposit = || InputTransaction::new(TransactionType::Deposit, 1, 1, Some("100")).unwrap();
+        let dispute = || InputTransaction::new(TransactionType::Dispute, 2, 1, None).unwrap();
+
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        engine.add_input(deposit()).await.unwrap();
+        assert_eq!(engine.add_input(dispute()).await.unwrap(), ApplyOutcome::Skipped(SkipReason::ClientMismatch));
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "100", "0", "100", false).unwrap());
+
+        let engine = Transactions::builder("sqlite::memory:").strict_dispute_client(true).build().await.unwrap();
+        engine.add_input(deposit()).await.unwrap();
+        let error = engine.add_input(dispute()).await.unwrap_err();
+        assert_eq!(error.to_string(), "Transaction 1 belongs to client 1, not client 2");
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "100", "0", "100", false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_freeze_on_dispute() {
+        let inputs = || vec![
+            InputTransaction::new(TransactionType::Deposit,    1, 1, Some("100")).unwrap(),
+            InputTransaction::new(TransactionType::Deposit,    1, 2, Some("50")).unwrap(),
+            InputTransaction::new(TransactionType::Dispute,    1, 2, None).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal, 1, 3, Some("30")).unwrap(),
+            InputTransaction::new(TransactionType::Resolve,    1, 2, None).unwrap(),
+            InputTransaction::new(TransactionType::Withdrawal, 1, 4, Some("20")).unwrap(),
+        ];
+
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        for input in inputs() {
+            assert_eq!(engine.add_input(input).await.unwrap(), ApplyOutcome::Applied);
+        }
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "100", "0", "100", false).unwrap());
+
+        let engine = Transactions::builder("sqlite::memory:").freeze_on_dispute(true).build().await.unwrap();
+        let mut outcomes = Vec::new();
+        for input in inputs() {
+            outcomes.push(engine.add_input(input).await.unwrap());
+        }
+        assert_eq!(outcomes[3], ApplyOutcome::Skipped(SkipReason::DisputeOpen));
+        assert_eq!(outcomes.iter().filter(|outcome| **outcome == ApplyOutcome::Applied).count(), 5);
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "130", "0", "130", false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_round_digits() {
+        let engine = Transactions::builder("sqlite::memory:").round_digits(2).pool_size(Some(1)).build().await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, 1, Some("10.125")).unwrap()).await.unwrap();
+        let account = engine.get_account_for_client(1).await.unwrap();
+        assert_eq!(account.total.to_string(), "10.13");
+    }
+
+    #[tokio::test]
+    async fn test_column_scales() {
+        let engine = Transactions::builder("sqlite::memory:")
+            .round_digits(3)
+            .column_scales(ColumnScales { available: Some(1), held: Some(2), total: None })
+            .build().await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, 1, Some("10.1234")).unwrap()).await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, 2, Some("2.4567")).unwrap()).await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Dispute, 1, 2, None).unwrap()).await.unwrap();
+        let account = engine.get_account_for_client(1).await.unwrap();
+        assert_eq!(
+            (account.available.to_string(), account.held.to_string(), account.total.to_string()),
+            ("10.1".into(), "2.46".into(), "12.580".into()),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fixed_scale() {
+        for (round_digits, available, total) in [(2, "5.00", "7.46"), (8, "5.00000000", "7.45670000")] {
+            let engine = Transactions::builder("sqlite::memory:").round_digits(round_digits).fixed_scale(true).build().await.unwrap();
+            engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, 1, Some("5")).unwrap()).await.unwrap();
+            engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, 2, Some("2.4567")).unwrap()).await.unwrap();
+            engine.add_input(InputTransaction::new(TransactionType::Dispute, 1, 2, None).unwrap()).await.unwrap();
+            let account = engine.get_account_for_client(1).await.unwrap();
+            assert_eq!((account.available.to_string(), account.total.to_string()), (available.into(), total.into()));
+            engine.close().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_mode() {
+        let engine = Transactions::builder("sqlite::memory:").round_digits(2).round_mode(RoundMode::Truncate).build().await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Deposit, 1, 1, Some("10.129")).unwrap()).await.unwrap();
+        let account = engine.get_account_for_client(1).await.unwrap();
+        assert_eq!(account.total.to_string(), "10.12");
+    }
+
+    #[tokio::test]
+    async fn test_client_zero() {
+        let deposit = || InputTransaction::new(TransactionType::Deposit, 0, 1, Some("10")).unwrap();
+
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        assert_eq!(engine.add_input(deposit()).await.unwrap(), ApplyOutcome::Applied);
+        assert_eq!(engine.get_account_for_client(0).await.unwrap(), OutputAccount::new(0, "10", "0", "10", false).unwrap());
+
+        let engine = Transactions::builder("sqlite::memory:").reject_client_zero(true).build().await.unwrap();
+        assert_eq!(engine.add_input(deposit()).await.unwrap(), ApplyOutcome::Skipped(SkipReason::ClientZero));
+        let accounts: Vec<OutputAccount> = engine.get_accounts().await.try_collect().await.unwrap();
+        assert!(accounts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_allowed_and_denied_clients() {
+        let deposit = |client, tx| InputTransaction::new(TransactionType::Deposit, client, tx, Some("10")).unwrap();
+        let engine = Transactions::builder("sqlite::memory:")
+            .allowed_clients(Some(HashSet::from([1, 2])))
+            .denied_clients(Some(HashSet::from([2, 3])))
+            .build().await.unwrap();
+        assert_eq!(engine.add_input(deposit(1, 1)).await.unwrap(), ApplyOutcome::Applied);
+        assert_eq!(engine.add_input(deposit(2, 2)).await.unwrap(), ApplyOutcome::Skipped(SkipReason::ClientDenied));
+        assert_eq!(engine.add_input(deposit(3, 3)).await.unwrap(), ApplyOutcome::Skipped(SkipReason::ClientNotAllowed));
+        assert_eq!(engine.add_input(deposit(4, 4)).await.unwrap(), ApplyOutcome::Skipped(SkipReason::ClientNotAllowed));
+
+        let accounts: Vec<OutputAccount> = engine.get_accounts().await.try_collect().await.unwrap();
+        assert_eq!(accounts, vec![OutputAccount::new(1, "10", "0", "10", false).unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_account_cache_hit() {
+        let engine = Transactions::builder("sqlite::memory:").cache(true).build().await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Deposit,     1,  1, Some("10")).unwrap()).await.unwrap();
+        let account = engine.get_account_for_client(1).await.unwrap();
+
+        // With the store closed, only a cached account can be returned.
+        engine.close().await;
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), account);
+        assert!(engine.get_account_for_client(2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_account_cache_invalidated() {
+        let engine = Transactions::builder("sqlite::memory:").cache(true).build().await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Deposit,     1,  1, Some("10")).unwrap()).await.unwrap();
+        engine.add_input(InputTransaction::new(TransactionType::Deposit,     2,  2, Some("20")).unwrap()).await.unwrap();
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "10", "0", "10", false).unwrap());
+        assert_eq!(engine.get_account_for_client(2).await.unwrap(), OutputAccount::new(2, "20", "0", "20", false).unwrap());
+
+        engine.add_input(InputTransaction::new(TransactionType::Dispute,     1,  1, None).unwrap()).await.unwrap();
+        assert_eq!(engine.get_account_for_client(1).await.unwrap(), OutputAccount::new(1, "0", "10", "10", false).unwrap());
+        assert!(engine.account_cache.as_ref().unwrap().lock().unwrap().contains_key(&(2, DEFAULT_CURRENCY.into())));
+    }
+
+    #[tokio::test]
+    async fn test_no_transactions() {
+        run_test_scenario(vec![], HashSet::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_multiple_currencies() {
+        run_test_scenario(
+            vec![
+                InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap().with_currency("USD"),
+                InputTransaction::new(TransactionType::Deposit,     1,  2, Some("50")).unwrap().with_currency("EUR"),
+                InputTransaction::new(TransactionType::Withdrawal,  1,  3, Some("70")).unwrap().with_currency("EUR"),
+                InputTransaction::new(TransactionType::Withdrawal,  1,  4, Some("30")).unwrap().with_currency("USD"),
+                InputTransaction::new(TransactionType::Dispute,     1,  2, None).unwrap(),
+                InputTransaction::new(TransactionType::Deposit,     2,  5, Some("10")).unwrap(),
+            ], 
+            HashSet::from([
+                OutputAccount::new(1, "70", "0", "70", false).unwrap().with_currency("USD"),
+                OutputAccount::new(1, "0", "50", "50", false).unwrap().with_currency("EUR"),
+                OutputAccount::new(2, "10", "0", "10", false).unwrap(),
+            ])
+        ).await;
     }
 
     #[tokio::test]
@@ -237,6 +2319,62 @@ impl Transactions {
         ).await;
     }
 
+    // A resolve on a deposit moves exactly its amount from held back to available, leaving the total unchanged,
+    // and a resolve on a withdrawal releases the amount held for it.
+    #[tokio::test]
+    async fn test_resolve_moves_held_to_available() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        let steps = vec![
+            (InputTransaction::new(TransactionType::Deposit,     1,  1, Some("0.1")).unwrap(),    ("0.1", "0", "0.1")),
+            (InputTransaction::new(TransactionType::Deposit,     1,  2, Some("0.2")).unwrap(),    ("0.3", "0", "0.3")),
+            (InputTransaction::new(TransactionType::Dispute,     1,  2, None).unwrap(),           ("0.1", "0.2", "0.3")),
+            (InputTransaction::new(TransactionType::Resolve,     1,  2, None).unwrap(),           ("0.3", "0", "0.3")),
+            (InputTransaction::new(TransactionType::Withdrawal,  1,  3, Some("0.0001")).unwrap(), ("0.2999", "0", "0.2999")),
+            (InputTransaction::new(TransactionType::Dispute,     1,  3, None).unwrap(),           ("0.2999", "0.0001", "0.3")),
+            (InputTransaction::new(TransactionType::Resolve,     1,  3, None).unwrap(),           ("0.2999", "0", "0.2999")),
+        ];
+        for (input, (available, held, total)) in steps {
+            let tx = input.tx;
+            assert_eq!(engine.add_input(input).await.unwrap(), ApplyOutcome::Applied);
+            let account = engine.get_account_for_client(1).await.unwrap();
+            assert_eq!(account, OutputAccount::new(1, available, held, total, false).unwrap(), "after transaction {}", tx);
+        }
+    }
+
+    // Resolving a dispute restores the withdrawals that the held funds had blocked.
+    #[tokio::test]
+    async fn test_resolve_restores_blocked_withdrawal() {
+        run_test_scenario(
+            vec![
+                InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(),
+                InputTransaction::new(TransactionType::Withdrawal,  1,  2, Some("60")).unwrap(),
+                InputTransaction::new(TransactionType::Dispute,     1,  1, None).unwrap(),
+                InputTransaction::new(TransactionType::Resolve,     1,  1, None).unwrap(),
+            ], 
+            HashSet::from([
+                OutputAccount::new(1, "40", "0", "40", false).unwrap(),
+            ])
+        ).await;
+    }
+
+    // A resolved transaction can be disputed again, and a second resolve is skipped.
+    #[tokio::test]
+    async fn test_resolve_then_dispute_again() {
+        let engine = Transactions::new("sqlite::memory:").await.unwrap();
+        let inputs = vec![
+            (InputTransaction::new(TransactionType::Deposit,     1,  1, Some("100")).unwrap(), ApplyOutcome::Applied),
+            (InputTransaction::new(TransactionType::Dispute,     1,  1, None).unwrap(),        ApplyOutcome::Applied),
+            (InputTransaction::new(TransactionType::Resolve,     1,  1, None).unwrap(),        ApplyOutcome::Applied),
+            (InputTransaction::new(TransactionType::Resolve,     1,  1, None).unwrap(),        ApplyOutcome::Skipped(SkipReason::NotDisputed)),
+            (InputTransaction::new(TransactionType::Dispute,     1,  1, None).unwrap(),        ApplyOutcome::Applied),
+        ];
+        for (input, outcome) in inputs {
+            assert_eq!(engine.add_input(input).await.unwrap(), outcome);
+        }
+        let account = engine.get_account_for_client(1).await.unwrap();
+        assert_eq!(account, OutputAccount::new(1, "0", "100", "100", false).unwrap());
+    }
+
     #[tokio::test]
     async fn test_chargeback_on_deposit() {
         run_test_scenario(
@@ -299,4 +2437,100 @@ impl Transactions {
             ])
         ).await;
     }
+
+    // A step of a generated history for a single client.
+    // Disputes, resolves and chargebacks refer to one of the deposits and withdrawals before them.
+    #[derive(Debug, Clone)]
+    enum Step {
+        Deposit(u32),
+        Withdrawal(u32),
+        Dispute(usize),
+        Resolve(usize),
+        Chargeback(usize),
+    }
+
+    fn step() -> impl proptest::strategy::Strategy<Value = Step> {
+        use proptest::prelude::*;
+        // Amounts in ten-thousandths, so that rounding never changes them.
+        prop_oneof![
+            3 => (1..1_000_000u32).prop_map(Step::Deposit),
+            2 => (1..1_000_000u32).prop_map(Step::Withdrawal),
+            2 => any::<usize>().prop_map(Step::Dispute),
+            1 => any::<usize>().prop_map(Step::Resolve),
+            1 => any::<usize>().prop_map(Step::Chargeback),
+        ]
+    }
+
+    // Turn a step into an input for client 1, where `transaction_ids` are the deposits and withdrawals so far.
+    fn step_input(step: &Step, transaction_ids: &mut Vec<u32>) -> InputTransaction {
+        let amount = |units: u32| Some(BigDecimal::new(units.into(), 4).to_string());
+        let referenced = |index: usize| if transaction_ids.is_empty() { 1 } else { transaction_ids[index % transaction_ids.len()] };
+        match *step {
+            Step::Deposit(units) | Step::Withdrawal(units) => {
+                let tx = transaction_ids.len() as u32 + 1;
+                transaction_ids.push(tx);
+                let transaction_type = if let Step::Deposit(_) = step { TransactionType::Deposit } else { TransactionType::Withdrawal };
+                InputTransaction::new(transaction_type, 1, tx, amount(units).as_deref()).unwrap()
+            },
+            Step::Dispute(index) => InputTransaction::new(TransactionType::Dispute, 1, referenced(index), None).unwrap(),
+            Step::Resolve(index) => InputTransaction::new(TransactionType::Resolve, 1, referenced(index), None).unwrap(),
+            Step::Chargeback(index) => InputTransaction::new(TransactionType::Chargeback, 1, referenced(index), None).unwrap(),
+        }
+    }
+
+    fn run_steps(steps: &[Step]) -> Result<(), proptest::test_runner::TestCaseError> {
+        use proptest::prelude::*;
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let engine = Transactions::new("sqlite::memory:").await.unwrap();
+            let mut transaction_ids = Vec::new();
+            let mut before = engine.get_account_for_client(1).await.unwrap();
+            for step in steps {
+                engine.add_input(step_input(step, &mut transaction_ids)).await.unwrap();
+                let after = engine.get_account_for_client(1).await.unwrap();
+                prop_assert_eq!(after.check_invariants(), Ok(()), "after {:?}", step);
+                if before.locked && matches!(step, Step::Deposit(_) | Step::Withdrawal(_)) {
+                    prop_assert_eq!(&after, &before, "a {:?} changed a locked account", step);
+                }
+                before = after;
+            }
+            Ok(())
+        })
+    }
+
+    // Dispute and then resolve a transaction at the end of a history, which must restore the account.
+    fn run_dispute_and_resolve(steps: &[Step], index: usize) -> Result<(), proptest::test_runner::TestCaseError> {
+        use proptest::prelude::*;
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let engine = Transactions::new("sqlite::memory:").await.unwrap();
+            let mut transaction_ids = Vec::new();
+            for step in steps {
+                engine.add_input(step_input(step, &mut transaction_ids)).await.unwrap();
+            }
+            let before = engine.get_account_for_client(1).await.unwrap();
+            let dispute = engine.add_input(step_input(&Step::Dispute(index), &mut transaction_ids)).await.unwrap();
+            if dispute != ApplyOutcome::Applied {
+                return Ok(());
+            }
+            let resolve = engine.add_input(step_input(&Step::Resolve(index), &mut transaction_ids)).await.unwrap();
+            prop_assert_eq!(resolve, ApplyOutcome::Applied);
+            let after = engine.get_account_for_client(1).await.unwrap();
+            prop_assert_eq!(&after.available + &after.held, &before.available + &before.held);
+            prop_assert_eq!(after, before);
+            Ok(())
+        })
+    }
+
+    proptest::proptest! {
+        #![proptest_config(proptest::test_runner::Config::with_cases(64))]
+
+        #[test]
+        fn test_balance_invariants(steps in proptest::collection::vec(step(), 0..30)) {
+            run_steps(&steps)?;
+        }
+
+        #[test]
+        fn test_resolve_restores_account(steps in proptest::collection::vec(step(), 1..30), index in proptest::prelude::any::<usize>()) {
+            run_dispute_and_resolve(&steps, index)?;
+        }
+    }
  }
\ No newline at end of file
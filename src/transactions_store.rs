@@ -1,25 +1,181 @@
 use std::error::Error;
+use std::fmt;
 use std::pin::Pin;
 use std::str::FromStr;
 
+use bigdecimal::{BigDecimal, ToPrimitive};
 use futures_core::stream::Stream;
 use sqlx::sqlite::SqliteJournalMode;
+use sqlx::pool::PoolConnection;
 use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::sqlite::SqliteConnection;
 use sqlx::sqlite::SqlitePool;
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::{Connection, Sqlite};
+
+// The schema, as the migrations in `migrations/`, applied in order of their version.
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+// The accounts with a charged back transaction, as a macro so that it can be part of other queries with `concat!`.
+// An account is locked by a chargeback in its own currency, so a client's accounts in other currencies aren't.
+macro_rules! locked_accounts_query {
+    () => { "SELECT DISTINCT client_id, currency FROM transactions WHERE charged_back = true" };
+}
+
+// Amounts are stored as integers in units of 10^-AMOUNT_SCALE,
+// so that they can be summed exactly, including by the database.
+pub const AMOUNT_SCALE: i64 = 4;
+
+// Convert an amount to the stored integer.
+// The amount must already be normalized to at most `AMOUNT_SCALE` decimal places.
+pub fn to_stored_amount(amount: &BigDecimal) -> Result<i64, Box<dyn Error>> {
+    let scaled = amount.with_scale(AMOUNT_SCALE);
+    if &scaled != amount {
+        return Err(format!("Amount {} has more than {} decimal places", amount, AMOUNT_SCALE).into());
+    }
+    let (units, _) = scaled.into_bigint_and_exponent();
+    Ok(units.to_i64().ok_or_else(|| format!("Amount {} is too large to store", amount))?)
+}
+
+pub fn from_stored_amount(units: i64) -> BigDecimal {
+    BigDecimal::new(units.into(), AMOUNT_SCALE)
+}
+
+// The largest amount that can be stored, either way, as withdrawals are stored as negative amounts.
+pub fn max_stored_amount() -> BigDecimal {
+    from_stored_amount(i64::MAX)
+}
+
+// The reversal reason recorded for charged back transactions.
+pub const CHARGEBACK_REASON: &str = "chargeback";
+
+// The currency of transactions that don't specify one.
+pub const DEFAULT_CURRENCY: &str = "";
+
+// What inserting a transaction with an existing transaction ID does.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    // Keep the existing transaction.
+    #[default]
+    Ignore,
+    // Update the amount of the existing transaction, if it belongs to the same client and isn't disputed or charged back.
+    // Its currency and timestamp are kept, so it stays in the same account, in the same place.
+    Replace,
+    // Fail with a `DuplicateTransactionError`.
+    Error,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(ConflictPolicy::Ignore),
+            "replace" => Ok(ConflictPolicy::Replace),
+            "error" => Ok(ConflictPolicy::Error),
+            _ => Err(format!("Unknown conflict policy: {}", s).into()),
+        }
+    }
+}
+
+// How a store prepares its schema when it is opened.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SchemaMode {
+    // Drop any existing tables and create them empty.
+    #[default]
+    Recreate,
+    // Create the tables only if they don't exist, otherwise use them as they are.
+    CreateIfAbsent,
+    // Use the tables of a database that must already exist, failing if it or its tables don't.
+    Existing,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateTransactionError {
+    pub transaction_id: u32,
+}
+
+impl fmt::Display for DuplicateTransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Transaction {} already exists", self.transaction_id)
+    }
+}
+
+impl Error for DuplicateTransactionError {}
 
 #[derive(sqlx::FromRow, Debug, Eq, Hash, PartialEq)]
 pub struct ClientID {
-    pub client_id: u16,
+    pub client_id: u32,
+}
+
+// A client has a separate account for each currency they have transactions in.
+#[derive(sqlx::FromRow, Debug, Eq, Hash, PartialEq)]
+pub struct AccountKey {
+    pub client_id: u32,
+    pub currency: String,
+}
+
+// The number of deposits and withdrawals stored for a client.
+#[derive(sqlx::FromRow, Debug, Eq, Hash, PartialEq)]
+pub struct TransactionCount {
+    pub client_id: u32,
+    pub count: i64,
+}
+
+// A deposit or withdrawal to insert.
+pub struct NewTransaction<'a> {
+    // Where the transaction is ordered, or after every stored transaction if not given.
+    pub ordinal: Option<i64>,
+    pub client_id: u32,
+    pub transaction_id: u32,
+    pub amount: &'a BigDecimal,
+    pub currency: &'a str,
+    pub timestamp: Option<i64>,
+    // The run that stored the transaction, for tracing appended data back to it.
+    pub run_id: Option<&'a str>,
 }
 
 #[derive(sqlx::FromRow, Debug, Eq, Hash, PartialEq)]
 pub struct MutableTransaction {
     pub ordinal: i64,
-    pub client_id: u16,
+    pub client_id: u32,
     pub transaction_id: u32,
-    pub amount: String,
+    pub amount: i64,
+    pub currency: String,
+    // When the transaction was made, if the input has timestamps.
+    pub timestamp: Option<i64>,
     pub disputed: bool,
     pub charged_back: bool,
+    // Why and when the transaction was reversed, if it has been charged back.
+    pub reversal_reason: Option<String>,
+    pub reversed_at: Option<String>,
+    // The run that stored the transaction, if it was given one.
+    pub run_id: Option<String>,
+}
+
+// The running balance of an account, kept up to date as its transactions change,
+// so that it can be read without replaying its transactions.
+// The amounts are stored as they were computed, including their scale, so that they read back exactly as replay gives them.
+#[derive(sqlx::FromRow, Debug, Clone, PartialEq)]
+pub struct AccountBalance {
+    pub client_id: u32,
+    pub currency: String,
+    pub available: String,
+    pub held: String,
+    pub total: String,
+    pub locked: bool,
+    // Whether replay stopped at a chargeback, so that the transactions after it don't change the account.
+    pub halted: bool,
+    // Where the last of the account's transactions is ordered, as of when the balance was computed.
+    pub last_timestamp: Option<i64>,
+    pub last_ordinal: i64,
+    // The replay options the balance was computed with, as it is only valid for the same options.
+    pub replay_policy: String,
+}
+
+fn is_in_memory(url: &str) -> bool {
+    url.contains(":memory:") || url.contains("mode=memory")
 }
 
 #[derive(Clone)]
@@ -29,89 +185,484 @@ pub struct MutableTransactionStore {
 
 impl MutableTransactionStore {
     pub async fn new(url: &str) -> Result<Self, Box<dyn Error>> {
-        let db_pool = SqlitePool::connect_with(
-            SqliteConnectOptions::from_str(url)?
+        Self::with_pool_size(url, None).await
+    }
+
+    // Connect with at most `pool_size` connections, or the sqlx default if not given.
+    // The connections to an in-memory database share its cache, so they all see the same data,
+    // and one connection is always kept open, as the database only lives as long as its connections.
+    pub async fn with_pool_size(url: &str, pool_size: Option<u32>) -> Result<Self, Box<dyn Error>> {
+        Self::connect(url, pool_size, true).await
+    }
+
+    async fn connect(url: &str, pool_size: Option<u32>, create_if_missing: bool) -> Result<Self, Box<dyn Error>> {
+        let mut pool_options = SqlitePoolOptions::new();
+        if let Some(pool_size) = pool_size {
+            pool_options = pool_options.max_connections(pool_size);
+        }
+        let mut connect_options = SqliteConnectOptions::from_str(url)?
             .journal_mode(SqliteJournalMode::Wal)
-            .create_if_missing(true)
-        ).await?;
+            .create_if_missing(create_if_missing);
+        if is_in_memory(url) {
+            pool_options = pool_options
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None);
+            connect_options = connect_options.shared_cache(true);
+        }
+        let db_pool = pool_options.connect_with(connect_options).await?;
 
         Ok(Self{ db_pool })
     }
 
+    // Connect as with `with_pool_size`, then prepare the schema according to `schema_mode`.
+    pub async fn open(url: &str, pool_size: Option<u32>, schema_mode: SchemaMode) -> Result<Self, Box<dyn Error>> {
+        let store = match schema_mode {
+            SchemaMode::Existing => Self::connect(url, pool_size, false).await
+                .map_err(|error| format!("Couldn't open the existing database {}: {}", url, error))?,
+            _ => Self::with_pool_size(url, pool_size).await?,
+        };
+        match schema_mode {
+            SchemaMode::Recreate => store.clean_and_recreate().await?,
+            SchemaMode::CreateIfAbsent => store.create_if_absent().await?,
+            SchemaMode::Existing => {
+                let exists = Self::table_exists_on(&mut *store.db_pool.acquire().await?, "transactions").await?;
+                if !exists {
+                    store.close().await;
+                    return Err(format!("The database {} has no transactions table", url).into());
+                }
+                store.create_if_absent().await?
+            },
+        }
+        Ok(store)
+    }
+
+    // A connection to run several queries on, e.g. with the `_on` methods.
+    pub async fn acquire(&self) -> Result<PoolConnection<Sqlite>, sqlx::Error> {
+        self.db_pool.acquire().await
+    }
+
+    // Start a database transaction, which the `_on` methods can run in, and which is rolled back unless committed.
+    pub async fn begin(&self) -> Result<sqlx::Transaction<'static, Sqlite>, sqlx::Error> {
+        self.db_pool.begin().await
+    }
+
+    // Close all connections, checkpointing the write-ahead log.
+    pub async fn close(&self) {
+        self.db_pool.close().await;
+    }
+
     pub async fn clean_and_recreate(&self) -> Result<(), Box<dyn Error>> {
         sqlx::query("
             DROP TABLE IF EXISTS transactions;
+            DROP TABLE IF EXISTS pending_disputes;
+            DROP TABLE IF EXISTS account_balances;
+            DROP TABLE IF EXISTS _sqlx_migrations;
+        ").execute(&self.db_pool).await?;
+
+        self.create_if_absent().await
+    }
+
+    // Create the schema unless it already exists, keeping any stored transactions,
+    // and migrate an existing schema to the latest version.
+    pub async fn create_if_absent(&self) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.db_pool.acquire().await?;
+        if !Self::table_exists_on(&mut conn, "_sqlx_migrations").await? {
+            if let Some(version) = Self::legacy_schema_version_on(&mut conn).await? {
+                Self::convert_text_amounts_on(&mut conn).await?;
+                Self::baseline_on(&mut conn, version).await?;
+            }
+        }
+        MIGRATOR.run_direct(&mut *conn).await?;
+
+        Ok(())
+    }
+
+    async fn table_exists_on(conn: &mut SqliteConnection, table: &str) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar("
+            SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = $1;
+        ")
+        .bind(table)
+        .fetch_one(conn).await
+    }
+
+    // The migration that the schema of a database from before migrations were tracked is at,
+    // going by the tables and columns it has, or `None` if it has no schema yet.
+    async fn legacy_schema_version_on(conn: &mut SqliteConnection) -> Result<Option<i64>, sqlx::Error> {
+        let columns: Vec<String> = sqlx::query_scalar("
+            SELECT name FROM pragma_table_info('transactions');
+        ")
+        .fetch_all(&mut *conn).await?;
+        if columns.is_empty() {
+            return Ok(None);
+        }
+        let has_column = |column: &str| columns.iter().any(|name| name == column);
+        let version = if has_column("run_id") {
+            6
+        } else if has_column("timestamp") {
+            5
+        } else if has_column("reversal_reason") {
+            4
+        } else if Self::table_exists_on(conn, "pending_disputes").await? {
+            3
+        } else if has_column("currency") {
+            2
+        } else {
+            1
+        };
+        Ok(Some(version))
+    }
 
+    // The first schema stored amounts as decimal text, e.g. `2.5` or `-1.25`, rather than as integers.
+    // Rebuild the table of such a database with its amounts converted, which leaves it as the first migration creates it.
+    async fn convert_text_amounts_on(conn: &mut SqliteConnection) -> Result<(), Box<dyn Error>> {
+        let amount_type: Option<String> = sqlx::query_scalar("
+            SELECT type FROM pragma_table_info('transactions') WHERE name = 'amount';
+        ")
+        .fetch_optional(&mut *conn).await?;
+        if !amount_type.is_some_and(|amount_type| amount_type.eq_ignore_ascii_case("TEXT")) {
+            return Ok(());
+        }
+        let amounts: Vec<(i64, String)> = sqlx::query_as("
+            SELECT ordinal, amount FROM transactions;
+        ")
+        .fetch_all(&mut *conn).await?;
+
+        let mut db_transaction = conn.begin().await?;
+        sqlx::query("
+            ALTER TABLE transactions RENAME TO text_amount_transactions;
+            DROP INDEX IF EXISTS idx_client_transaction;
             CREATE TABLE transactions
             (
                 ordinal         INTEGER PRIMARY KEY,
                 client_id       INTEGER,
                 transaction_id  INTEGER UNIQUE,
-                amount          TEXT,
+                amount          INTEGER,
                 disputed        BOOLEAN,
                 charged_back    BOOLEAN
             );
-
             CREATE INDEX idx_client_transaction ON transactions (client_id, transaction_id);
-        ").execute(&self.db_pool).await?;
+            INSERT INTO transactions (ordinal, client_id, transaction_id, disputed, charged_back)
+                SELECT ordinal, client_id, transaction_id, disputed, charged_back FROM text_amount_transactions;
+            DROP TABLE text_amount_transactions;
+        ").execute(&mut *db_transaction).await?;
+        for (ordinal, amount) in amounts {
+            let amount = BigDecimal::from_str(&amount)
+                .map_err(|error| format!("Invalid stored amount {} of transaction {}: {}", amount, ordinal, error))?;
+            let amount = to_stored_amount(&amount.round(AMOUNT_SCALE).with_scale(AMOUNT_SCALE))?;
+            sqlx::query("
+                UPDATE transactions SET amount = $1 WHERE ordinal = $2;
+            ")
+            .bind(amount)
+            .bind(ordinal)
+            .execute(&mut *db_transaction).await?;
+        }
+        db_transaction.commit().await?;
+        Ok(())
+    }
 
+    // Record the migrations up to `version` as applied, without running them.
+    async fn baseline_on(conn: &mut SqliteConnection, version: i64) -> Result<(), Box<dyn Error>> {
+        conn.ensure_migrations_table().await?;
+        for migration in MIGRATOR.iter().filter(|migration| migration.version <= version) {
+            sqlx::query("
+                INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+                VALUES ($1, $2, TRUE, $3, 0);
+            ")
+            .bind(migration.version)
+            .bind(&*migration.description)
+            .bind(&*migration.checksum)
+            .execute(&mut *conn).await?;
+        }
         Ok(())
     }
 
-    pub async fn insert_transaction(&self, client_id: u16, transaction_id: u32, amount: &str) -> Result<(), Box<dyn Error>> {
-        sqlx::query("
+    // Each of the following returns whether a transaction was changed.
+    // The `_on` variants run on the given connection, so that they can be part of a database transaction.
+
+    // Insert a transaction, keeping any existing transaction with the same ID.
+    pub async fn insert_transaction(&self, client_id: u32, transaction_id: u32, amount: &BigDecimal, currency: &str) -> Result<bool, Box<dyn Error>> {
+        self.insert_transaction_at(client_id, transaction_id, amount, currency, None, ConflictPolicy::Ignore).await
+    }
+
+    // Insert a transaction with the time it was made, which takes precedence over the order transactions are inserted in.
+    // `conflict_policy` decides what happens when a transaction with the same ID already exists.
+    pub async fn insert_transaction_at(
+        &self,
+        client_id: u32,
+        transaction_id: u32,
+        amount: &BigDecimal,
+        currency: &str,
+        timestamp: Option<i64>,
+        conflict_policy: ConflictPolicy,
+    ) -> Result<bool, Box<dyn Error>> {
+        let transaction = NewTransaction { ordinal: None, client_id, transaction_id, amount, currency, timestamp, run_id: None };
+        self.insert_new_transaction(transaction, conflict_policy).await
+    }
+
+    // Insert a transaction, at its given ordinal if it has one.
+    pub async fn insert_new_transaction(&self, transaction: NewTransaction<'_>, conflict_policy: ConflictPolicy) -> Result<bool, Box<dyn Error>> {
+        Self::insert_new_transaction_on(&mut *self.db_pool.acquire().await?, transaction, conflict_policy).await
+    }
+
+    pub async fn insert_new_transaction_on(conn: &mut SqliteConnection, transaction: NewTransaction<'_>, conflict_policy: ConflictPolicy) -> Result<bool, Box<dyn Error>> {
+        let NewTransaction { ordinal, client_id, transaction_id, amount, currency, timestamp, run_id } = transaction;
+        let amount = to_stored_amount(amount)?;
+        let on_conflict = match conflict_policy {
+            ConflictPolicy::Replace => "
+                ON CONFLICT (transaction_id) DO UPDATE SET amount = excluded.amount
+                WHERE client_id = excluded.client_id AND NOT disputed AND NOT charged_back",
+            ConflictPolicy::Ignore | ConflictPolicy::Error => "ON CONFLICT (transaction_id) DO NOTHING",
+        };
+        let result = sqlx::query(&format!("
             INSERT INTO transactions (
-                client_id, transaction_id, amount, disputed, charged_back
-            ) VALUES ($1, $2, $3, false, false)
-            ON CONFLICT (transaction_id) DO NOTHING;
-        ")
+                ordinal, client_id, transaction_id, amount, currency, timestamp, run_id, disputed, charged_back
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, false, false)
+            {};
+        ", on_conflict))
+        .bind(ordinal)
         .bind(client_id)
         .bind(transaction_id)
         .bind(amount)
-        .execute(&self.db_pool).await?;
-    
-        Ok(())
+        .bind(currency)
+        .bind(timestamp)
+        .bind(run_id)
+        .execute(&mut *conn).await?;
+
+        let changed = result.rows_affected() > 0;
+        if !changed && conflict_policy == ConflictPolicy::Error {
+            return Err(Box::new(DuplicateTransactionError { transaction_id }));
+        }
+        Ok(changed)
     }
 
-    pub async fn dispute_transaction(&self, client_id: u16, transaction_id: u32) -> Result<(), Box<dyn Error>> {
-        sqlx::query("
+    pub async fn dispute_transaction(&self, client_id: u32, transaction_id: u32) -> Result<bool, Box<dyn Error>> {
+        Self::dispute_transaction_on(&mut *self.db_pool.acquire().await?, client_id, transaction_id).await
+    }
+
+    pub async fn dispute_transaction_on(conn: &mut SqliteConnection, client_id: u32, transaction_id: u32) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query("
                 UPDATE transactions 
                 SET disputed = true
-                WHERE client_id = $1 AND transaction_id = $2;
+                WHERE client_id = $1 AND transaction_id = $2 AND disputed = false AND charged_back = false;
         ")
         .bind(client_id)
         .bind(transaction_id)
-        .execute(&self.db_pool).await?;
+        .execute(&mut *conn).await?;
 
-        Ok(())
+        Ok(result.rows_affected() > 0)
     }
 
-    pub async fn resolve_dispute(&self, client_id: u16, transaction_id: u32) -> Result<(), Box<dyn Error>> {
-        sqlx::query("
+    pub async fn resolve_dispute(&self, client_id: u32, transaction_id: u32) -> Result<bool, Box<dyn Error>> {
+        Self::resolve_dispute_on(&mut *self.db_pool.acquire().await?, client_id, transaction_id).await
+    }
+
+    pub async fn resolve_dispute_on(conn: &mut SqliteConnection, client_id: u32, transaction_id: u32) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query("
                 UPDATE transactions 
                 SET disputed = false
-                WHERE client_id = $1 AND transaction_id = $2;
+                WHERE client_id = $1 AND transaction_id = $2 AND disputed = true;
         ")
         .bind(client_id)
         .bind(transaction_id)
-        .execute(&self.db_pool).await?;
+        .execute(&mut *conn).await?;
 
-        Ok(())
+        Ok(result.rows_affected() > 0)
     }
 
-    pub async fn chargeback_transaction(&self, client_id: u16, transaction_id: u32) -> Result<(), Box<dyn Error>> {
-        sqlx::query("
+    pub async fn chargeback_transaction(&self, client_id: u32, transaction_id: u32) -> Result<bool, Box<dyn Error>> {
+        Self::chargeback_transaction_on(&mut *self.db_pool.acquire().await?, client_id, transaction_id).await
+    }
+
+    pub async fn chargeback_transaction_on(conn: &mut SqliteConnection, client_id: u32, transaction_id: u32) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query("
                 UPDATE transactions 
-                SET disputed = false, charged_back = true
+                SET disputed = false, charged_back = true,
+                    reversal_reason = $3, reversed_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now')
                 WHERE client_id = $1 AND transaction_id = $2 AND disputed = true;
         ")
         .bind(client_id)
         .bind(transaction_id)
-        .execute(&self.db_pool).await?;
+        .bind(CHARGEBACK_REASON)
+        .execute(&mut *conn).await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Record a dispute for a transaction that hasn't been received yet.
+    pub async fn add_pending_dispute(&self, client_id: u32, transaction_id: u32) -> Result<bool, Box<dyn Error>> {
+        Self::add_pending_dispute_on(&mut *self.db_pool.acquire().await?, client_id, transaction_id).await
+    }
+
+    pub async fn add_pending_dispute_on(conn: &mut SqliteConnection, client_id: u32, transaction_id: u32) -> Result<bool, Box<dyn Error>> {
+        let result = sqlx::query("
+            INSERT INTO pending_disputes (client_id, transaction_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING;
+        ")
+        .bind(client_id)
+        .bind(transaction_id)
+        .execute(&mut *conn).await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Dispute the transaction if a dispute for it was received before it, removing the pending dispute.
+    pub async fn apply_pending_dispute(&self, client_id: u32, transaction_id: u32) -> Result<bool, Box<dyn Error>> {
+        Self::apply_pending_dispute_on(&mut *self.db_pool.acquire().await?, client_id, transaction_id).await
+    }
+
+    pub async fn apply_pending_dispute_on(conn: &mut SqliteConnection, client_id: u32, transaction_id: u32) -> Result<bool, Box<dyn Error>> {
+        let mut db_transaction = conn.begin().await?;
+        let pending = sqlx::query("
+            DELETE FROM pending_disputes
+            WHERE client_id = $1 AND transaction_id = $2;
+        ")
+        .bind(client_id)
+        .bind(transaction_id)
+        .execute(&mut *db_transaction).await?;
+        if pending.rows_affected() == 0 {
+            return Ok(false);
+        }
+        let result = sqlx::query("
+                UPDATE transactions 
+                SET disputed = true
+                WHERE client_id = $1 AND transaction_id = $2 AND disputed = false;
+        ")
+        .bind(client_id)
+        .bind(transaction_id)
+        .execute(&mut *db_transaction).await?;
+        db_transaction.commit().await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn get_transaction(&self, client_id: u32, transaction_id: u32) -> Result<Option<MutableTransaction>, Box<dyn Error>> {
+        Self::get_transaction_on(&mut *self.db_pool.acquire().await?, client_id, transaction_id).await
+    }
+
+    pub async fn get_transaction_on(conn: &mut SqliteConnection, client_id: u32, transaction_id: u32) -> Result<Option<MutableTransaction>, Box<dyn Error>> {
+        let transaction = sqlx::query_as::<_, MutableTransaction>("
+            SELECT * from transactions
+            WHERE client_id = $1 AND transaction_id = $2;
+        ")
+        .bind(client_id)
+        .bind(transaction_id)
+        .fetch_optional(&mut *conn).await?;
+
+        Ok(transaction)
+    }
+
+    // The running balance of an account, if one has been stored.
+    pub async fn get_account_balance(&self, client_id: u32, currency: &str) -> Result<Option<AccountBalance>, Box<dyn Error>> {
+        Self::get_account_balance_on(&mut *self.db_pool.acquire().await?, client_id, currency).await
+    }
+
+    pub async fn get_account_balance_on(conn: &mut SqliteConnection, client_id: u32, currency: &str) -> Result<Option<AccountBalance>, Box<dyn Error>> {
+        let account = sqlx::query_as::<_, AccountBalance>("
+            SELECT * from account_balances
+            WHERE client_id = $1 AND currency = $2;
+        ")
+        .bind(client_id)
+        .bind(currency)
+        .fetch_optional(&mut *conn).await?;
+
+        Ok(account)
+    }
+
+    // Store the running balance of an account, replacing any stored before.
+    pub async fn put_account_balance_on(conn: &mut SqliteConnection, account: &AccountBalance) -> Result<(), Box<dyn Error>> {
+        sqlx::query("
+            INSERT INTO account_balances (
+                client_id, currency, available, held, total, locked, halted, last_timestamp, last_ordinal, replay_policy
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            ON CONFLICT (client_id, currency) DO UPDATE SET
+                available = excluded.available, held = excluded.held, total = excluded.total,
+                locked = excluded.locked, halted = excluded.halted,
+                last_timestamp = excluded.last_timestamp, last_ordinal = excluded.last_ordinal,
+                replay_policy = excluded.replay_policy;
+        ")
+        .bind(account.client_id)
+        .bind(&account.currency)
+        .bind(&account.available)
+        .bind(&account.held)
+        .bind(&account.total)
+        .bind(account.locked)
+        .bind(account.halted)
+        .bind(account.last_timestamp)
+        .bind(account.last_ordinal)
+        .bind(&account.replay_policy)
+        .execute(&mut *conn).await?;
 
         Ok(())
     }
 
+    // The client a transaction belongs to, if it exists.
+    pub async fn get_transaction_owner(&self, transaction_id: u32) -> Result<Option<u32>, Box<dyn Error>> {
+        Self::get_transaction_owner_on(&mut *self.db_pool.acquire().await?, transaction_id).await
+    }
+
+    pub async fn get_transaction_owner_on(conn: &mut SqliteConnection, transaction_id: u32) -> Result<Option<u32>, Box<dyn Error>> {
+        let owner: Option<u32> = sqlx::query_scalar("
+            SELECT client_id from transactions
+            WHERE transaction_id = $1;
+        ")
+        .bind(transaction_id)
+        .fetch_optional(&mut *conn).await?;
+
+        Ok(owner)
+    }
+
+    // Whether a client has any stored transactions.
+    pub async fn client_exists(&self, client_id: u32) -> Result<bool, Box<dyn Error>> {
+        Self::client_exists_on(&mut *self.db_pool.acquire().await?, client_id).await
+    }
+
+    pub async fn client_exists_on(conn: &mut SqliteConnection, client_id: u32) -> Result<bool, Box<dyn Error>> {
+        let exists: bool = sqlx::query_scalar("
+            SELECT EXISTS (SELECT 1 FROM transactions WHERE client_id = $1);
+        ")
+        .bind(client_id)
+        .fetch_one(&mut *conn).await?;
+
+        Ok(exists)
+    }
+
+    // Whether any stored transaction is in a currency other than the default.
+    pub async fn has_currencies(&self) -> Result<bool, Box<dyn Error>> {
+        let has_currencies: bool = sqlx::query_scalar("
+            SELECT EXISTS (SELECT 1 FROM transactions WHERE currency != $1);
+        ")
+        .bind(DEFAULT_CURRENCY)
+        .fetch_one(&self.db_pool).await?;
+
+        Ok(has_currencies)
+    }
+
+    // Whether any of a client's transactions is disputed, and not yet resolved or charged back.
+    pub async fn has_open_dispute(&self, client_id: u32) -> Result<bool, Box<dyn Error>> {
+        Self::has_open_dispute_on(&mut *self.db_pool.acquire().await?, client_id).await
+    }
+
+    pub async fn has_open_dispute_on(conn: &mut SqliteConnection, client_id: u32) -> Result<bool, Box<dyn Error>> {
+        let open: bool = sqlx::query_scalar("
+            SELECT EXISTS (SELECT 1 FROM transactions WHERE client_id = $1 AND disputed = true);
+        ")
+        .bind(client_id)
+        .fetch_one(&mut *conn).await?;
+
+        Ok(open)
+    }
+
+    pub async fn get_max_ordinal(&self) -> Result<Option<i64>, Box<dyn Error>> {
+        let max_ordinal: Option<i64> = sqlx::query_scalar("
+            SELECT MAX(ordinal) from transactions;
+        ")
+        .fetch_one(&self.db_pool).await?;
+
+        Ok(max_ordinal)
+    }
+
     pub async fn get_clients(&self) -> Pin<Box<dyn Stream<Item = Result<ClientID, sqlx::Error>> + Send + '_>> {
         sqlx::query_as::<_, ClientID>("
             SELECT DISTINCT client_id from transactions;
@@ -119,12 +670,132 @@ impl MutableTransactionStore {
         .fetch(&self.db_pool)
     }
 
-    pub async fn get_transactions_for_client(&self, client_id: u16) -> Pin<Box<dyn Stream<Item = Result<MutableTransaction, sqlx::Error>> + Send + '_>> {
+    // The clients with a charged back transaction, and so with at least one locked account,
+    // though their accounts in other currencies may not be locked.
+    pub async fn get_locked_clients(&self) -> Pin<Box<dyn Stream<Item = Result<ClientID, sqlx::Error>> + Send + '_>> {
+        sqlx::query_as::<_, ClientID>("
+            SELECT DISTINCT client_id FROM transactions WHERE charged_back = true ORDER BY client_id;
+        ")
+        .fetch(&self.db_pool)
+    }
+
+    // The number of stored transactions of each client, in client order, counted by the database.
+    pub async fn get_transaction_counts(&self) -> Pin<Box<dyn Stream<Item = Result<TransactionCount, sqlx::Error>> + Send + '_>> {
+        sqlx::query_as::<_, TransactionCount>("
+            SELECT client_id, COUNT(*) AS count FROM transactions GROUP BY client_id ORDER BY client_id;
+        ")
+        .fetch(&self.db_pool)
+    }
+
+    // The account keys of the accounts that aren't locked, followed by those that are, each in client and currency order.
+    // With `max_clients`, only the first that many clients the database returns are included, as with `get_account_keys_for_clients`.
+    pub async fn get_account_keys_by_locked(&self, max_clients: Option<u32>) -> Pin<Box<dyn Stream<Item = Result<AccountKey, sqlx::Error>> + Send + '_>> {
+        sqlx::query_as::<_, AccountKey>(concat!("
+            SELECT DISTINCT client_id, currency from transactions
+            WHERE client_id IN (SELECT DISTINCT client_id from transactions LIMIT $1)
+            ORDER BY (client_id, currency) IN (", locked_accounts_query!(), "), client_id, currency;
+        "))
+        // A negative limit is no limit.
+        .bind(max_clients.map_or(-1, i64::from))
+        .fetch(&self.db_pool)
+    }
+
+    pub async fn get_account_keys(&self) -> Pin<Box<dyn Stream<Item = Result<AccountKey, sqlx::Error>> + Send + '_>> {
+        sqlx::query_as::<_, AccountKey>("
+            SELECT DISTINCT client_id, currency from transactions;
+        ")
+        .fetch(&self.db_pool)
+    }
+
+    // The account keys of at most `max_clients` clients, in the order the database returns the clients.
+    pub async fn get_account_keys_for_clients(&self, max_clients: u32) -> Pin<Box<dyn Stream<Item = Result<AccountKey, sqlx::Error>> + Send + '_>> {
+        sqlx::query_as::<_, AccountKey>("
+            SELECT DISTINCT client_id, currency from transactions
+            WHERE client_id IN (SELECT DISTINCT client_id from transactions LIMIT $1);
+        ")
+        .bind(max_clients)
+        .fetch(&self.db_pool)
+    }
+
+    pub async fn get_transactions_for_account<'a>(&'a self, client_id: u32, currency: &'a str) -> Pin<Box<dyn Stream<Item = Result<MutableTransaction, sqlx::Error>> + Send + 'a>> {
+        sqlx::query_as::<_, MutableTransaction>("
+            SELECT * from transactions
+            WHERE client_id = $1 AND currency = $2
+            ORDER BY timestamp, ordinal;
+        ")
+        .bind(client_id)
+        .bind(currency)
+        .fetch(&self.db_pool)
+    }
+
+    // The transactions of an account in replay order, read on the given connection,
+    // so that they include the changes of a database transaction that hasn't been committed yet.
+    pub async fn get_transactions_for_account_on(conn: &mut SqliteConnection, client_id: u32, currency: &str) -> Result<Vec<MutableTransaction>, Box<dyn Error>> {
+        let transactions = sqlx::query_as::<_, MutableTransaction>("
+            SELECT * from transactions
+            WHERE client_id = $1 AND currency = $2
+            ORDER BY timestamp, ordinal;
+        ")
+        .bind(client_id)
+        .bind(currency)
+        .fetch_all(&mut *conn).await?;
+
+        Ok(transactions)
+    }
+
+    // The transactions of an account that were stored at or before `max_ordinal`.
+    pub async fn get_transactions_for_account_as_of<'a>(&'a self, client_id: u32, currency: &'a str, max_ordinal: i64) -> Pin<Box<dyn Stream<Item = Result<MutableTransaction, sqlx::Error>> + Send + 'a>> {
+        sqlx::query_as::<_, MutableTransaction>("
+            SELECT * from transactions
+            WHERE client_id = $1 AND currency = $2 AND ordinal <= $3
+            ORDER BY timestamp, ordinal;
+        ")
+        .bind(client_id)
+        .bind(currency)
+        .bind(max_ordinal)
+        .fetch(&self.db_pool)
+    }
+
+    // The transactions of an account up to and including its first charged back transaction,
+    // or all of them if none has been charged back, so that a locked account's later history isn't read.
+    // Missing timestamps are compared as the lowest timestamp, as they are ordered first.
+    pub async fn get_transactions_for_account_until_lock<'a>(&'a self, client_id: u32, currency: &'a str) -> Pin<Box<dyn Stream<Item = Result<MutableTransaction, sqlx::Error>> + Send + 'a>> {
+        sqlx::query_as::<_, MutableTransaction>("
+            SELECT * from transactions
+            WHERE client_id = $1 AND currency = $2
+            AND (
+                NOT EXISTS (
+                    SELECT 1 FROM transactions WHERE client_id = $1 AND currency = $2 AND charged_back = true
+                )
+                OR (IFNULL(timestamp, -9223372036854775808), ordinal) <= (
+                    SELECT IFNULL(timestamp, -9223372036854775808), ordinal FROM transactions
+                    WHERE client_id = $1 AND currency = $2 AND charged_back = true
+                    ORDER BY timestamp, ordinal
+                    LIMIT 1
+                )
+            )
+            ORDER BY timestamp, ordinal;
+        ")
+        .bind(client_id)
+        .bind(currency)
+        .fetch(&self.db_pool)
+    }
+
+    // Every transaction, in the order they were received.
+    pub async fn get_all_transactions(&self) -> Pin<Box<dyn Stream<Item = Result<MutableTransaction, sqlx::Error>> + Send + '_>> {
         sqlx::query_as::<_, MutableTransaction>("
             SELECT * from transactions
-            WHERE client_id = $1
             ORDER BY ordinal;
         ")
+        .fetch(&self.db_pool)
+    }
+
+    pub async fn get_transactions_for_client(&self, client_id: u32) -> Pin<Box<dyn Stream<Item = Result<MutableTransaction, sqlx::Error>> + Send + '_>> {
+        sqlx::query_as::<_, MutableTransaction>("
+            SELECT * from transactions
+            WHERE client_id = $1
+            ORDER BY timestamp, ordinal;
+        ")
         .bind(client_id)
         .fetch(&self.db_pool)
     }
@@ -139,13 +810,71 @@ mod tests {
     use std::iter::FromIterator;
     use futures_util::TryStreamExt;
 
+    fn amount(value: &str) -> BigDecimal {
+        BigDecimal::from_str(value).unwrap()
+    }
+
+    #[test]
+    fn test_stored_amount_round_trip() {
+        for value in ["0.0001", "-0.0001", "0", "2.5", "-250.75", "922337203685477.5807"] {
+            let stored = to_stored_amount(&amount(value)).unwrap();
+            assert_eq!(from_stored_amount(stored), amount(value));
+        }
+        assert_eq!(to_stored_amount(&amount("0.0001")).unwrap(), 1);
+        assert_eq!(to_stored_amount(&amount("1.99990")).unwrap(), 19999);
+        assert!(to_stored_amount(&amount("1.99999")).is_err());
+        assert!(to_stored_amount(&amount("922337203685477.5808")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_insert_large_and_small_amounts() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        store.insert_transaction(7, 15, &amount("0.0001"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 16, &amount("900000000000000.9999"), DEFAULT_CURRENCY).await.unwrap();
+
+        let amounts: Vec<BigDecimal> = store.get_transactions_for_client(7).await
+            .map_ok(|t| from_stored_amount(t.amount))
+            .try_collect().await.unwrap();
+        assert_eq!(amounts, vec![amount("0.0001"), amount("900000000000000.9999")]);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store_is_shared_by_connections() {
+        let store = MutableTransactionStore::with_pool_size("sqlite::memory:", Some(4)).await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+
+        // Hold every connection but one, so the reads use connections other than the one that inserted.
+        let mut held = Vec::new();
+        for _ in 0..3 {
+            held.push(store.db_pool.acquire().await.unwrap());
+        }
+        assert_eq!(store.get_transaction(7, 15).await.unwrap().unwrap().amount, 25000);
+        drop(held);
+
+        // Each concurrent read checks out its own connection.
+        let reads = (0..8).map(|_| store.get_transaction(7, 15));
+        for transaction in futures_util::future::join_all(reads).await {
+            assert_eq!(transaction.unwrap().unwrap().amount, 25000);
+        }
+    }
+
+    #[test]
+    fn test_is_in_memory() {
+        assert!(is_in_memory("sqlite::memory:"));
+        assert!(is_in_memory("sqlite://file:ledger?mode=memory&cache=shared"));
+        assert!(!is_in_memory("sqlite://transactions.db"));
+    }
+
     #[tokio::test]
     async fn test_insert_transactions() {
         let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
         store.clean_and_recreate().await.unwrap();
 
-        store.insert_transaction(7, 15, "2.50").await.unwrap();
-        store.insert_transaction(7, 19, "3.50").await.unwrap();
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 19, &amount("3.50"), DEFAULT_CURRENCY).await.unwrap();
 
         let transactions: Vec<MutableTransaction> = store.get_transactions_for_client(7).await.try_collect().await.unwrap();
         let expected: Vec<MutableTransaction> = vec![
@@ -153,17 +882,27 @@ mod tests {
                 ordinal: 1,
                 client_id: 7,
                 transaction_id: 15,
-                amount: "2.50".into(),
+                amount: 25000,
+                currency: DEFAULT_CURRENCY.into(),
+                timestamp: None,
                 disputed: false,
                 charged_back: false,
+                reversal_reason: None,
+                reversed_at: None,
+                run_id: None,
             }, 
             MutableTransaction {
                 ordinal: 2,
                 client_id: 7,
                 transaction_id: 19,
-                amount: "3.50".into(),
+                amount: 35000,
+                currency: DEFAULT_CURRENCY.into(),
+                timestamp: None,
                 disputed: false,
                 charged_back: false,
+                reversal_reason: None,
+                reversed_at: None,
+                run_id: None,
             },
         ];
 
@@ -175,7 +914,7 @@ mod tests {
         let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
         store.clean_and_recreate().await.unwrap();
 
-        store.insert_transaction(7, 15, "2.50").await.unwrap();
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
         store.dispute_transaction(7, 15).await.unwrap();
 
         let transactions: Vec<MutableTransaction> = store.get_transactions_for_client(7).await.try_collect().await.unwrap();
@@ -184,21 +923,163 @@ mod tests {
                 ordinal: 1,
                 client_id: 7,
                 transaction_id: 15,
-                amount: "2.50".into(),
+                amount: 25000,
+                currency: DEFAULT_CURRENCY.into(),
+                timestamp: None,
                 disputed: true,
                 charged_back: false,
+                reversal_reason: None,
+                reversed_at: None,
+                run_id: None,
             },
         ];
 
         assert_eq!(transactions, expected);
     }
 
+    #[tokio::test]
+    async fn test_updates_report_changes() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        assert!(store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap());
+        assert!(!store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap());
+        assert!(!store.resolve_dispute(7, 15).await.unwrap());
+        assert!(store.dispute_transaction(7, 15).await.unwrap());
+        assert!(!store.dispute_transaction(7, 15).await.unwrap());
+        assert!(!store.dispute_transaction(8, 15).await.unwrap());
+        assert!(store.chargeback_transaction(7, 15).await.unwrap());
+        assert!(!store.chargeback_transaction(7, 15).await.unwrap());
+        // A charged back transaction is settled, so it can't be disputed again.
+        assert!(!store.dispute_transaction(7, 15).await.unwrap());
+        assert!(!store.get_transaction(7, 15).await.unwrap().unwrap().disputed);
+    }
+
+    #[tokio::test]
+    async fn test_insert_at_ordinal() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        let new_transaction = |ordinal, transaction_id, amount| NewTransaction {
+            ordinal,
+            client_id: 7,
+            transaction_id,
+            amount,
+            currency: DEFAULT_CURRENCY,
+            timestamp: None,
+            run_id: None,
+        };
+        let amount = amount("1");
+        store.insert_new_transaction(new_transaction(Some(3), 15, &amount), ConflictPolicy::Ignore).await.unwrap();
+        store.insert_new_transaction(new_transaction(Some(1), 16, &amount), ConflictPolicy::Ignore).await.unwrap();
+        store.insert_new_transaction(new_transaction(None, 17, &amount), ConflictPolicy::Ignore).await.unwrap();
+
+        let transactions: Vec<(i64, u32)> = store.get_all_transactions().await
+            .map_ok(|transaction| (transaction.ordinal, transaction.transaction_id))
+            .try_collect().await.unwrap();
+        assert_eq!(transactions, vec![(1, 16), (3, 15), (4, 17)]);
+    }
+
+    #[tokio::test]
+    async fn test_conflict_policy_ignore() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        assert!(store.insert_transaction_at(7, 15, &amount("2.50"), DEFAULT_CURRENCY, None, ConflictPolicy::Ignore).await.unwrap());
+        assert!(!store.insert_transaction_at(7, 15, &amount("3.50"), DEFAULT_CURRENCY, None, ConflictPolicy::Ignore).await.unwrap());
+        assert_eq!(store.get_transaction(7, 15).await.unwrap().unwrap().amount, 25000);
+    }
+
+    #[tokio::test]
+    async fn test_conflict_policy_replace() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        assert!(store.insert_transaction_at(7, 15, &amount("2.50"), DEFAULT_CURRENCY, None, ConflictPolicy::Replace).await.unwrap());
+        assert!(store.insert_transaction_at(7, 15, &amount("3.50"), DEFAULT_CURRENCY, None, ConflictPolicy::Replace).await.unwrap());
+        assert!(!store.insert_transaction_at(8, 15, &amount("4.50"), DEFAULT_CURRENCY, None, ConflictPolicy::Replace).await.unwrap());
+        let transaction = store.get_transaction(7, 15).await.unwrap().unwrap();
+        assert_eq!((transaction.ordinal, transaction.amount), (1, 35000));
+    }
+
+    #[tokio::test]
+    async fn test_conflict_policy_error() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        assert!(store.insert_transaction_at(7, 15, &amount("2.50"), DEFAULT_CURRENCY, None, ConflictPolicy::Error).await.unwrap());
+        let error = store.insert_transaction_at(7, 15, &amount("3.50"), DEFAULT_CURRENCY, None, ConflictPolicy::Error).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<DuplicateTransactionError>(), Some(&DuplicateTransactionError { transaction_id: 15 }));
+        assert_eq!(store.get_transaction(7, 15).await.unwrap().unwrap().amount, 25000);
+    }
+
+    #[tokio::test]
+    async fn test_pending_dispute() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        assert!(store.add_pending_dispute(7, 15).await.unwrap());
+        assert!(!store.add_pending_dispute(7, 15).await.unwrap());
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 16, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        assert!(store.apply_pending_dispute(7, 15).await.unwrap());
+        assert!(!store.apply_pending_dispute(7, 15).await.unwrap());
+        assert!(!store.apply_pending_dispute(7, 16).await.unwrap());
+
+        assert!(store.get_transaction(7, 15).await.unwrap().unwrap().disputed);
+        assert!(!store.get_transaction(7, 16).await.unwrap().unwrap().disputed);
+    }
+
+    #[tokio::test]
+    async fn test_timestamp_order() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        store.insert_transaction_at(7, 15, &amount("1"), DEFAULT_CURRENCY, Some(200), ConflictPolicy::Ignore).await.unwrap();
+        store.insert_transaction_at(7, 16, &amount("2"), DEFAULT_CURRENCY, Some(100), ConflictPolicy::Ignore).await.unwrap();
+        store.insert_transaction_at(7, 17, &amount("3"), DEFAULT_CURRENCY, Some(200), ConflictPolicy::Ignore).await.unwrap();
+        store.insert_transaction_at(7, 18, &amount("4"), DEFAULT_CURRENCY, Some(100), ConflictPolicy::Ignore).await.unwrap();
+
+        // Transactions with the same timestamp are in the order they were inserted.
+        let ids: Vec<u32> = store.get_transactions_for_client(7).await
+            .map_ok(|t| t.transaction_id)
+            .try_collect().await.unwrap();
+        assert_eq!(ids, vec![16, 18, 15, 17]);
+        let ids: Vec<u32> = store.get_transactions_for_account(7, DEFAULT_CURRENCY).await
+            .map_ok(|t| t.transaction_id)
+            .try_collect().await.unwrap();
+        assert_eq!(ids, vec![16, 18, 15, 17]);
+    }
+
+    #[tokio::test]
+    async fn test_get_transaction_owner() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        assert_eq!(store.get_transaction_owner(15).await.unwrap(), Some(7));
+        assert_eq!(store.get_transaction_owner(16).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_transaction() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+
+        let transaction = store.get_transaction(7, 15).await.unwrap().unwrap();
+        assert_eq!(transaction.amount, 25000);
+        assert!(store.get_transaction(8, 15).await.unwrap().is_none());
+        assert!(store.get_transaction(7, 16).await.unwrap().is_none());
+    }
+
     #[tokio::test]
     async fn test_resolve_dispute() {
         let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
         store.clean_and_recreate().await.unwrap();
 
-        store.insert_transaction(7, 15, "2.50").await.unwrap();
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
         store.dispute_transaction(7, 15).await.unwrap();
         store.resolve_dispute(7, 15).await.unwrap();
 
@@ -208,9 +1089,14 @@ mod tests {
                 ordinal: 1,
                 client_id: 7,
                 transaction_id: 15,
-                amount: "2.50".into(),
+                amount: 25000,
+                currency: DEFAULT_CURRENCY.into(),
+                timestamp: None,
                 disputed: false,
                 charged_back: false,
+                reversal_reason: None,
+                reversed_at: None,
+                run_id: None,
             },
         ];
 
@@ -222,33 +1108,216 @@ mod tests {
         let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
         store.clean_and_recreate().await.unwrap();
 
-        store.insert_transaction(7, 15, "2.50").await.unwrap();
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
         store.dispute_transaction(7, 15).await.unwrap();
         store.chargeback_transaction(7, 15).await.unwrap();
 
-        let transactions: Vec<MutableTransaction> = store.get_transactions_for_client(7).await.try_collect().await.unwrap();
+        let mut transactions: Vec<MutableTransaction> = store.get_transactions_for_client(7).await.try_collect().await.unwrap();
+        let reversed_at = transactions[0].reversed_at.take().unwrap();
+        assert!(reversed_at.ends_with('Z'), "{}", reversed_at);
         let expected: Vec<MutableTransaction> = vec![
             MutableTransaction {
                 ordinal: 1,
                 client_id: 7,
                 transaction_id: 15,
-                amount: "2.50".into(),
+                amount: 25000,
+                currency: DEFAULT_CURRENCY.into(),
+                timestamp: None,
                 disputed: false,
                 charged_back: true,
+                reversal_reason: Some(CHARGEBACK_REASON.into()),
+                reversed_at: None,
+                run_id: None,
             },
         ];
 
         assert_eq!(transactions, expected);
     }
 
+    #[tokio::test]
+    async fn test_create_if_absent_keeps_transactions() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.create_if_absent().await.unwrap();
+        assert_eq!(store.get_max_ordinal().await.unwrap(), None);
+
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 19, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.create_if_absent().await.unwrap();
+
+        assert_eq!(store.get_max_ordinal().await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_open_schema_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite://{}", dir.path().join("transactions.db").display());
+
+        // An empty database gets the schema either way.
+        let store = MutableTransactionStore::open(&url, None, SchemaMode::CreateIfAbsent).await.unwrap();
+        assert_eq!(store.get_max_ordinal().await.unwrap(), None);
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 19, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.close().await;
+
+        let store = MutableTransactionStore::open(&url, None, SchemaMode::CreateIfAbsent).await.unwrap();
+        assert_eq!(store.get_max_ordinal().await.unwrap(), Some(2));
+        assert!(store.get_transaction(7, 19).await.unwrap().is_some());
+        store.close().await;
+
+        let store = MutableTransactionStore::open(&url, None, SchemaMode::Existing).await.unwrap();
+        assert_eq!(store.get_max_ordinal().await.unwrap(), Some(2));
+        store.close().await;
+
+        let store = MutableTransactionStore::open(&url, None, SchemaMode::Recreate).await.unwrap();
+        assert_eq!(store.get_max_ordinal().await.unwrap(), None);
+        store.close().await;
+
+        // An existing store isn't created if it's missing or has no schema.
+        let missing_url = format!("sqlite://{}", dir.path().join("missing.db").display());
+        assert!(MutableTransactionStore::open(&missing_url, None, SchemaMode::Existing).await.is_err());
+        assert!(!dir.path().join("missing.db").exists());
+        let empty_url = format!("sqlite://{}", dir.path().join("empty.db").display());
+        MutableTransactionStore::new(&empty_url).await.unwrap().close().await;
+        let error = MutableTransactionStore::open(&empty_url, None, SchemaMode::Existing).await.err().unwrap();
+        assert!(error.to_string().ends_with("has no transactions table"));
+    }
+
+    #[tokio::test]
+    async fn test_migrate_old_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("sqlite://{}", dir.path().join("transactions.db").display());
+
+        // The first schema, from before currencies, pending disputes, reversals and timestamps,
+        // with the amounts stored as decimal text.
+        let store = MutableTransactionStore::new(&url).await.unwrap();
+        sqlx::query("
+            CREATE TABLE transactions
+            (
+                ordinal         INTEGER PRIMARY KEY,
+                client_id       INTEGER,
+                transaction_id  INTEGER UNIQUE,
+                amount          TEXT,
+                disputed        BOOLEAN,
+                charged_back    BOOLEAN
+            );
+            CREATE INDEX idx_client_transaction ON transactions (client_id, transaction_id);
+            INSERT INTO transactions (client_id, transaction_id, amount, disputed, charged_back) VALUES (7, 15, '2.5', true, false);
+            INSERT INTO transactions (client_id, transaction_id, amount, disputed, charged_back) VALUES (7, 14, '-1.25', false, false);
+        ").execute(&store.db_pool).await.unwrap();
+        store.close().await;
+
+        let store = MutableTransactionStore::open(&url, None, SchemaMode::CreateIfAbsent).await.unwrap();
+        let transaction = store.get_transaction(7, 15).await.unwrap().unwrap();
+        assert_eq!((transaction.amount, transaction.disputed, transaction.currency.as_str()), (25000, true, DEFAULT_CURRENCY));
+        assert_eq!(transaction.timestamp, None);
+        assert_eq!(store.get_transaction(7, 14).await.unwrap().unwrap().amount, -12500);
+        assert!(store.insert_transaction_at(7, 16, &amount("1.5"), "EUR", Some(10), ConflictPolicy::Error).await.unwrap());
+        assert!(store.add_pending_dispute(7, 17).await.unwrap());
+
+        let versions: Vec<i64> = sqlx::query_scalar("SELECT version FROM _sqlx_migrations ORDER BY version;")
+            .fetch_all(&store.db_pool).await.unwrap();
+        assert_eq!(versions, vec![1, 2, 3, 4, 5, 6, 7]);
+        store.close().await;
+
+        // Opening it again doesn't reapply anything.
+        let store = MutableTransactionStore::open(&url, None, SchemaMode::CreateIfAbsent).await.unwrap();
+        assert_eq!(store.get_max_ordinal().await.unwrap(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_migrate_untracked_schema() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.create_if_absent().await.unwrap();
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+
+        // A database with the latest schema, created before migrations were tracked.
+        sqlx::query("DROP TABLE _sqlx_migrations;").execute(&store.db_pool).await.unwrap();
+        store.create_if_absent().await.unwrap();
+        assert_eq!(store.get_max_ordinal().await.unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_account_balance() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+        assert_eq!(store.get_account_balance(7, "USD").await.unwrap(), None);
+
+        let mut balance = AccountBalance {
+            client_id: 7,
+            currency: "USD".into(),
+            available: "2.5000".into(),
+            held: "0".into(),
+            total: "2.5000".into(),
+            locked: false,
+            halted: false,
+            last_timestamp: None,
+            last_ordinal: 1,
+            replay_policy: "default".into(),
+        };
+        let mut conn = store.acquire().await.unwrap();
+        MutableTransactionStore::put_account_balance_on(&mut conn, &balance).await.unwrap();
+        balance.held = "1.0000".into();
+        balance.last_timestamp = Some(10);
+        MutableTransactionStore::put_account_balance_on(&mut conn, &balance).await.unwrap();
+        drop(conn);
+        assert_eq!(store.get_account_balance(7, "USD").await.unwrap(), Some(balance));
+        assert_eq!(store.get_account_balance(7, DEFAULT_CURRENCY).await.unwrap(), None);
+
+        store.clean_and_recreate().await.unwrap();
+        assert_eq!(store.get_account_balance(7, "USD").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_account_keys() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        store.insert_transaction(7, 15, &amount("2.50"), "USD").await.unwrap();
+        store.insert_transaction(7, 16, &amount("2.50"), "EUR").await.unwrap();
+        store.insert_transaction(8, 13, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 19, &amount("2.50"), "USD").await.unwrap();
+
+        let keys: HashSet<AccountKey> = store.get_account_keys().await.try_collect().await.unwrap();
+        let expected: HashSet<AccountKey> = HashSet::from_iter(vec![
+            AccountKey { client_id: 7, currency: "USD".into() },
+            AccountKey { client_id: 7, currency: "EUR".into() },
+            AccountKey { client_id: 8, currency: DEFAULT_CURRENCY.into() },
+        ]);
+        assert_eq!(keys, expected);
+
+        let ids: Vec<u32> = store.get_transactions_for_account(7, "USD").await
+            .map_ok(|t| t.transaction_id)
+            .try_collect().await.unwrap();
+        assert_eq!(ids, vec![15, 19]);
+    }
+
+    #[tokio::test]
+    async fn test_get_account_keys_for_clients() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        store.insert_transaction(7, 15, &amount("2.50"), "USD").await.unwrap();
+        store.insert_transaction(7, 16, &amount("2.50"), "EUR").await.unwrap();
+        store.insert_transaction(8, 13, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(9, 19, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+
+        let keys: Vec<AccountKey> = store.get_account_keys_for_clients(2).await.try_collect().await.unwrap();
+        let clients: HashSet<u32> = keys.iter().map(|key| key.client_id).collect();
+        assert_eq!(clients.len(), 2);
+        assert_eq!(keys.len(), if clients.contains(&7) { 3 } else { 2 });
+
+        let keys: Vec<AccountKey> = store.get_account_keys_for_clients(0).await.try_collect().await.unwrap();
+        assert!(keys.is_empty());
+    }
+
     #[tokio::test]
     async fn test_get_clients() {
         let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
         store.clean_and_recreate().await.unwrap();
 
-        store.insert_transaction(7, 15, "2.50").await.unwrap();
-        store.insert_transaction(8, 13, "2.50").await.unwrap();
-        store.insert_transaction(7, 19, "2.50").await.unwrap();
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(8, 13, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 19, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
 
         let clients: HashSet<ClientID> = store.get_clients().await.try_collect().await.unwrap();
         let expected: HashSet<ClientID> = HashSet::from_iter(vec![ClientID { client_id: 7}, ClientID { client_id: 8}]);
@@ -256,14 +1325,163 @@ mod tests {
         assert_eq!(clients, expected);
     }
 
+    #[tokio::test]
+    async fn test_get_transactions_for_account_as_of() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        store.insert_transaction(7, 15, &amount("1"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(8, 16, &amount("2"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 17, &amount("3"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 18, &amount("4"), DEFAULT_CURRENCY).await.unwrap();
+
+        let ids = |max_ordinal| {
+            let store = &store;
+            async move {
+                store.get_transactions_for_account_as_of(7, DEFAULT_CURRENCY, max_ordinal).await
+                    .map_ok(|t| t.transaction_id)
+                    .try_collect::<Vec<u32>>().await.unwrap()
+            }
+        };
+        assert_eq!(ids(0).await, Vec::<u32>::new());
+        assert_eq!(ids(3).await, vec![15, 17]);
+        assert_eq!(ids(4).await, vec![15, 17, 18]);
+    }
+
+    #[tokio::test]
+    async fn test_get_transactions_for_account_until_lock() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        store.insert_transaction_at(7, 15, &amount("1"), DEFAULT_CURRENCY, Some(200), ConflictPolicy::Ignore).await.unwrap();
+        store.insert_transaction_at(7, 16, &amount("2"), DEFAULT_CURRENCY, None, ConflictPolicy::Ignore).await.unwrap();
+        store.insert_transaction_at(7, 17, &amount("3"), DEFAULT_CURRENCY, Some(100), ConflictPolicy::Ignore).await.unwrap();
+        store.insert_transaction_at(7, 18, &amount("4"), DEFAULT_CURRENCY, Some(300), ConflictPolicy::Ignore).await.unwrap();
+        store.insert_transaction_at(7, 19, &amount("5"), DEFAULT_CURRENCY, Some(100), ConflictPolicy::Ignore).await.unwrap();
+
+        let ids = || async {
+            store.get_transactions_for_account_until_lock(7, DEFAULT_CURRENCY).await
+                .map_ok(|t| t.transaction_id)
+                .try_collect::<Vec<u32>>().await.unwrap()
+        };
+        assert_eq!(ids().await, vec![16, 17, 19, 15, 18]);
+
+        store.dispute_transaction(7, 15).await.unwrap();
+        store.chargeback_transaction(7, 15).await.unwrap();
+        store.dispute_transaction(7, 18).await.unwrap();
+        store.chargeback_transaction(7, 18).await.unwrap();
+        assert_eq!(ids().await, vec![16, 17, 19, 15]);
+
+        store.dispute_transaction(7, 16).await.unwrap();
+        store.chargeback_transaction(7, 16).await.unwrap();
+        assert_eq!(ids().await, vec![16]);
+    }
+
+    #[tokio::test]
+    async fn test_client_exists() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        assert!(!store.client_exists(7).await.unwrap());
+        store.insert_transaction(7, 15, &amount("2.50"), "EUR").await.unwrap();
+        assert!(store.client_exists(7).await.unwrap());
+        assert!(!store.client_exists(8).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_has_open_dispute() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 16, &amount("2.50"), "EUR").await.unwrap();
+        assert!(!store.has_open_dispute(7).await.unwrap());
+        store.dispute_transaction(7, 16).await.unwrap();
+        assert!(store.has_open_dispute(7).await.unwrap());
+        assert!(!store.has_open_dispute(8).await.unwrap());
+        store.resolve_dispute(7, 16).await.unwrap();
+        assert!(!store.has_open_dispute(7).await.unwrap());
+        store.dispute_transaction(7, 15).await.unwrap();
+        store.chargeback_transaction(7, 15).await.unwrap();
+        assert!(!store.has_open_dispute(7).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_get_transaction_counts() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        for transaction_id in 1..=3 {
+            store.insert_transaction(9, transaction_id, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        }
+        store.insert_transaction(7, 4, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 5, &amount("-1.00"), "EUR").await.unwrap();
+        store.insert_transaction(8, 6, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.dispute_transaction(8, 6).await.unwrap();
+
+        let counts: Vec<TransactionCount> = store.get_transaction_counts().await.try_collect().await.unwrap();
+        assert_eq!(counts, vec![
+            TransactionCount { client_id: 7, count: 2 },
+            TransactionCount { client_id: 8, count: 1 },
+            TransactionCount { client_id: 9, count: 3 },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_get_locked_clients() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 16, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(8, 13, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.dispute_transaction(7, 15).await.unwrap();
+        store.chargeback_transaction(7, 15).await.unwrap();
+        store.dispute_transaction(8, 13).await.unwrap();
+        // A client is listed if any of its accounts is locked.
+        store.insert_transaction(9, 17, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(9, 18, &amount("2.50"), "EUR").await.unwrap();
+        store.dispute_transaction(9, 18).await.unwrap();
+        store.chargeback_transaction(9, 18).await.unwrap();
+
+        let clients: Vec<ClientID> = store.get_locked_clients().await.try_collect().await.unwrap();
+        assert_eq!(clients, vec![ClientID { client_id: 7 }, ClientID { client_id: 9 }]);
+    }
+
+    #[tokio::test]
+    async fn test_get_account_keys_by_locked() {
+        let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
+        store.clean_and_recreate().await.unwrap();
+
+        store.insert_transaction(9, 12, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 16, &amount("2.50"), "EUR").await.unwrap();
+        store.insert_transaction(8, 13, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(6, 14, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.dispute_transaction(7, 15).await.unwrap();
+        store.chargeback_transaction(7, 15).await.unwrap();
+        store.dispute_transaction(6, 14).await.unwrap();
+        store.chargeback_transaction(6, 14).await.unwrap();
+
+        let store = &store;
+        let keys = |max_clients| async move {
+            let keys: Vec<AccountKey> = store.get_account_keys_by_locked(max_clients).await.try_collect().await.unwrap();
+            keys.into_iter().map(|key| (key.client_id, key.currency)).collect::<Vec<_>>()
+        };
+        let key = |client_id: u32, currency: &str| (client_id, currency.to_string());
+        // Only the account in the charged back currency is locked, so client 7's other account is with the unlocked ones.
+        assert_eq!(keys(None).await, vec![key(7, "EUR"), key(8, ""), key(9, ""), key(6, ""), key(7, "")]);
+        assert_eq!(keys(Some(2)).await.len(), 3);
+    }
+
     #[tokio::test]
     async fn test_get_transactions_for_client() {
         let store = MutableTransactionStore::new("sqlite::memory:").await.unwrap();
         store.clean_and_recreate().await.unwrap();
 
-        store.insert_transaction(7, 15, "2.50").await.unwrap();
-        store.insert_transaction(8, 13, "2.50").await.unwrap();
-        store.insert_transaction(7, 19, "3.50").await.unwrap();
+        store.insert_transaction(7, 15, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(8, 13, &amount("2.50"), DEFAULT_CURRENCY).await.unwrap();
+        store.insert_transaction(7, 19, &amount("3.50"), DEFAULT_CURRENCY).await.unwrap();
 
         let transactions: Vec<MutableTransaction> = store.get_transactions_for_client(7).await.try_collect().await.unwrap();
         let expected: Vec<MutableTransaction> = vec![
@@ -271,17 +1489,27 @@ mod tests {
                 ordinal: 1,
                 client_id: 7,
                 transaction_id: 15,
-                amount: "2.50".into(),
+                amount: 25000,
+                currency: DEFAULT_CURRENCY.into(),
+                timestamp: None,
                 disputed: false,
                 charged_back: false,
+                reversal_reason: None,
+                reversed_at: None,
+                run_id: None,
             }, 
             MutableTransaction {
                 ordinal: 3,
                 client_id: 7,
                 transaction_id: 19,
-                amount: "3.50".into(),
+                amount: 35000,
+                currency: DEFAULT_CURRENCY.into(),
+                timestamp: None,
                 disputed: false,
                 charged_back: false,
+                reversal_reason: None,
+                reversed_at: None,
+                run_id: None,
             },
         ];
 
@@ -0,0 +1,137 @@
+use std::process::Command;
+
+use pledger::config::Config;
+use pledger::input::{open_inputs, parse_input_transaction};
+
+const INPUT: &str = "\
+type,client,tx,amount
+deposit,7,1,10.0
+withdrawal,7,2,2.5
+deposit,8,3,1.0
+";
+
+fn parse_args(args: &[&str]) -> Config {
+    Config::from_args_and_env(args.iter().map(|arg| arg.to_string()), |_| None).unwrap()
+}
+
+// The (client, tx) of each transaction read from the inputs opened for `args`.
+fn read_transactions(args: &[&str]) -> Vec<(u32, u32)> {
+    let inputs = open_inputs(&parse_args(args)).unwrap();
+    inputs.into_iter()
+        .flat_map(parse_input_transaction)
+        .map(|result| result.map(|transaction| (transaction.client, transaction.tx)))
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+#[test]
+fn test_open_input_file_from_args() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("transactions.csv");
+    std::fs::write(&path, INPUT).unwrap();
+
+    assert_eq!(read_transactions(&[path.to_str().unwrap()]), vec![(7, 1), (7, 2), (8, 3)]);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_open_zstd_input() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("transactions.csv.zst");
+    std::fs::write(&path, zstd::encode_all(INPUT.as_bytes(), 0).unwrap()).unwrap();
+
+    assert_eq!(read_transactions(&[path.to_str().unwrap()]), vec![(7, 1), (7, 2), (8, 3)]);
+}
+
+#[cfg(feature = "bzip2")]
+#[test]
+fn test_open_bzip2_input() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("transactions.csv.bz2");
+    let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+    encoder.write_all(INPUT.as_bytes()).unwrap();
+    std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+    assert_eq!(read_transactions(&[path.to_str().unwrap()]), vec![(7, 1), (7, 2), (8, 3)]);
+}
+
+#[cfg(all(feature = "zstd", feature = "bzip2"))]
+#[test]
+fn test_open_compressed_inputs_from_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("1.csv"), "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+    std::fs::write(dir.path().join("2.csv.zst"), zstd::encode_all(&b"type,client,tx,amount\ndeposit,2,2,1.0\n"[..], 0).unwrap()).unwrap();
+    let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+    std::io::Write::write_all(&mut encoder, b"type,client,tx,amount\ndeposit,3,3,1.0\n").unwrap();
+    std::fs::write(dir.path().join("3.csv.bz2"), encoder.finish().unwrap()).unwrap();
+    std::fs::write(dir.path().join("4.txt.zst"), "not an input").unwrap();
+
+    assert_eq!(read_transactions(&["--dir", dir.path().to_str().unwrap()]), vec![(1, 1), (2, 2), (3, 3)]);
+}
+
+#[cfg(not(feature = "zstd"))]
+#[test]
+fn test_open_zstd_input_without_feature() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("transactions.csv.zst");
+    std::fs::write(&path, "").unwrap();
+
+    let error = open_inputs(&parse_args(&[path.to_str().unwrap()])).err().unwrap();
+    assert!(error.to_string().ends_with("requires pledger to be built with the `zstd` feature."), "{}", error);
+}
+
+#[test]
+fn test_open_missing_input_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = parse_args(&[dir.path().join("missing.csv").to_str().unwrap()]);
+    assert!(open_inputs(&config).is_err());
+
+    let error = open_inputs(&parse_args(&[])).err().unwrap();
+    assert_eq!(error.to_string(), "A valid file name is required as an argument.");
+}
+
+#[test]
+fn test_run_with_input_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("transactions.csv");
+    std::fs::write(&path, INPUT).unwrap();
+    let db_url = format!("sqlite://{}", dir.path().join("transactions.db").display());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_pledger"))
+        .args([path.to_str().unwrap(), "--db-url", &db_url])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.remove(0), "client,available,held,total,locked");
+    lines.sort_unstable();
+    assert_eq!(lines, vec!["7,7.5000,0,7.5000,false", "8,1.0000,0,1.0000,false"]);
+}
+
+#[test]
+fn test_run_without_input_file() {
+    let output = Command::new(env!("CARGO_BIN_EXE_pledger")).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("A valid file name is required as an argument."));
+}
+
+#[test]
+fn test_version() {
+    let output = Command::new(env!("CARGO_BIN_EXE_pledger")).arg("--version").output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with(&format!("pledger {}", env!("CARGO_PKG_VERSION"))), "{}", stdout);
+}
+
+#[test]
+fn test_emit_schema() {
+    let output = Command::new(env!("CARGO_BIN_EXE_pledger")).arg("--emit-schema").output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let schema: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(schema, pledger::output::output_account_schema());
+    assert_eq!(schema["properties"]["client"]["type"], "integer");
+}